@@ -1,9 +1,10 @@
 use criterion::{criterion_group, criterion_main, Bencher, Criterion};
-use dynstack::{dyn_push, DynStack};
+use dynstack::{dyn_push, DynStack, SmallDynStack};
 use std::fmt::Display;
 
 trait ATrait {}
 
+#[allow(dead_code)]
 struct Large([u8; 950]);
 
 impl Large {
@@ -13,6 +14,7 @@ impl Large {
 }
 
 impl ATrait for Large {}
+impl ATrait for u8 {}
 
 fn new_speed_naive(b: &mut Bencher) {
     b.iter(Vec::<Box<dyn Display>>::new);
@@ -22,6 +24,7 @@ fn new_speed_dynstack(b: &mut Bencher) {
     b.iter(DynStack::<dyn Display>::new);
 }
 
+#[allow(clippy::vec_init_then_push)]
 fn push_large_speed_naive(b: &mut Bencher) {
     b.iter(|| {
         let mut vec = Vec::<Box<dyn ATrait>>::new();
@@ -58,6 +61,56 @@ fn push_speed_dynstack(b: &mut Bencher) {
     });
 }
 
+fn push_speed_short_lived_dynstack(b: &mut Bencher) {
+    b.iter(|| {
+        let mut stack = DynStack::<dyn Display>::new();
+        dyn_push!(stack, 0xF00BAAusize);
+        dyn_push!(stack, 0xABBAu16);
+        dyn_push!(stack, 0xBA7123AAu32);
+        dyn_push!(stack, 12u8);
+        stack
+    });
+}
+
+fn push_speed_short_lived_small_dynstack(b: &mut Bencher) {
+    b.iter(|| {
+        let mut stack = SmallDynStack::<dyn Display, 32>::new();
+        dyn_push!(stack, 0xF00BAAusize);
+        dyn_push!(stack, 0xABBAu16);
+        dyn_push!(stack, 0xBA7123AAu32);
+        dyn_push!(stack, 12u8);
+        stack
+    });
+}
+
+#[allow(dead_code)]
+#[repr(align(64))]
+struct Aligned64(u8);
+
+impl ATrait for Aligned64 {}
+
+fn push_aligned_speed_naive(b: &mut Bencher) {
+    b.iter(|| {
+        let mut vec = Vec::<Box<dyn ATrait>>::new();
+        for i in 0..100 {
+            vec.push(Box::new(Aligned64(i)));
+            vec.push(Box::new(i));
+        }
+        vec
+    });
+}
+
+fn push_aligned_speed_dynstack(b: &mut Bencher) {
+    b.iter(|| {
+        let mut stack = DynStack::<dyn ATrait>::new();
+        for i in 0..100 {
+            dyn_push!(stack, Aligned64(i));
+            dyn_push!(stack, i);
+        }
+        stack
+    });
+}
+
 fn push_and_run_naive(b: &mut Bencher) {
     b.iter(|| {
         let mut stack = Vec::<Box<dyn Fn() -> usize>>::new();
@@ -157,6 +210,99 @@ fn access_dynstack(b: &mut Bencher) {
     });
 }
 
+fn access_indexed_dynstack(b: &mut Bencher) {
+    let mut stack = DynStack::<dyn AsUsize>::new();
+    for _ in 0..1000 {
+        dyn_push!(stack, 0xF00BAAusize);
+    }
+    b.iter(|| {
+        for i in 0..stack.len() {
+            criterion::black_box(stack.get(i).unwrap().make());
+        }
+    });
+}
+
+// Build with `--features unchecked-index` to compare against the checked indexing.
+fn access_index_dynstack(b: &mut Bencher) {
+    let mut stack = DynStack::<dyn AsUsize>::new();
+    for _ in 0..1000 {
+        dyn_push!(stack, 0xF00BAAusize);
+    }
+    b.iter(|| {
+        for i in 0..stack.len() {
+            criterion::black_box(stack[i].make());
+        }
+    });
+}
+
+fn access_unchecked_dynstack(b: &mut Bencher) {
+    let mut stack = DynStack::<dyn AsUsize>::new();
+    for _ in 0..1000 {
+        dyn_push!(stack, 0xF00BAAusize);
+    }
+    b.iter(|| {
+        for i in 0..stack.len() {
+            criterion::black_box(unsafe { stack.get_unchecked(i) }.make());
+        }
+    });
+}
+
+fn sum_iter_dynstack(b: &mut Bencher) {
+    let mut stack = DynStack::<dyn AsUsize>::new();
+    for _ in 0..1000 {
+        dyn_push!(stack, 0xF00BAAusize);
+    }
+    b.iter(|| criterion::black_box(stack.iter().map(|i| i.make()).sum::<usize>()));
+}
+
+fn sum_by_dynstack(b: &mut Bencher) {
+    let mut stack = DynStack::<dyn AsUsize>::new();
+    for _ in 0..1000 {
+        dyn_push!(stack, 0xF00BAAusize);
+    }
+    b.iter(|| criterion::black_box(stack.sum_by(|i| i.make())));
+}
+
+fn drop_pod_naive(b: &mut Bencher) {
+    b.iter_with_large_setup(
+        || {
+            let mut stack = Vec::<Box<dyn AsUsize>>::new();
+            for _ in 0..10000 {
+                stack.push(Box::new(0xF00BAAusize));
+            }
+            stack
+        },
+        drop,
+    );
+}
+
+fn drop_pod_dynstack(b: &mut Bencher) {
+    b.iter_with_large_setup(
+        || {
+            let mut stack = DynStack::<dyn AsUsize>::new();
+            for _ in 0..10000 {
+                dyn_push!(stack, 0xF00BAAusize);
+            }
+            stack
+        },
+        drop,
+    );
+}
+
+// Items pushed from a box might have destructors, so each one is dropped.
+fn drop_pod_boxed_dynstack(b: &mut Bencher) {
+    b.iter_with_large_setup(
+        || {
+            let mut stack = DynStack::<dyn AsUsize>::new();
+            for _ in 0..10000 {
+                stack.push_box(Box::new(0xF00BAAusize));
+            }
+            stack
+        },
+        drop,
+    );
+}
+
 fn criterion_benchmark(c: &mut Criterion) {
     c.bench_function("new_speed_naive", new_speed_naive);
     c.bench_function("new_speed_dynstack", new_speed_dynstack);
@@ -164,12 +310,30 @@ fn criterion_benchmark(c: &mut Criterion) {
     c.bench_function("push_large_speed_dynstack", push_large_speed_dynstack);
     c.bench_function("push_speed_naive", push_speed_naive);
     c.bench_function("push_speed_dynstack", push_speed_dynstack);
+    c.bench_function(
+        "push_speed_short_lived_dynstack",
+        push_speed_short_lived_dynstack,
+    );
+    c.bench_function(
+        "push_speed_short_lived_small_dynstack",
+        push_speed_short_lived_small_dynstack,
+    );
+    c.bench_function("push_aligned_speed_naive", push_aligned_speed_naive);
+    c.bench_function("push_aligned_speed_dynstack", push_aligned_speed_dynstack);
     c.bench_function("push_and_run_naive", push_and_run_naive);
     c.bench_function("push_and_run_dynstack", push_and_run_dynstack);
     c.bench_function("pseudorecursive2_naive", pseudorecursive2_naive);
     c.bench_function("pseudorecursive2_dynstack", pseudorecursive2_dynstack);
     c.bench_function("access_naive", access_naive);
     c.bench_function("access_dynstack", access_dynstack);
+    c.bench_function("access_indexed_dynstack", access_indexed_dynstack);
+    c.bench_function("access_index_dynstack", access_index_dynstack);
+    c.bench_function("access_unchecked_dynstack", access_unchecked_dynstack);
+    c.bench_function("sum_iter_dynstack", sum_iter_dynstack);
+    c.bench_function("sum_by_dynstack", sum_by_dynstack);
+    c.bench_function("drop_pod_naive", drop_pod_naive);
+    c.bench_function("drop_pod_dynstack", drop_pod_dynstack);
+    c.bench_function("drop_pod_boxed_dynstack", drop_pod_boxed_dynstack);
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -1,3 +1,5 @@
+#![cfg_attr(dynstack_ptr_metadata, feature(ptr_metadata))]
+
 use std::mem;
 
 #[path = "src/fatptr.rs"]
@@ -25,8 +27,20 @@ fn main() {
         "First part of the fat pointer does not point to the data"
     );
 
-    let data1_vtable2: &dyn TestTrait = unsafe { &*fatptr::recomp([data1, vtable2]) };
-    let data2_vtable1: &dyn TestTrait = unsafe { &*fatptr::recomp([data2, vtable1]) };
+    let thin = &instance1 as *const Implementer1;
+    assert_eq!(
+        unsafe { fatptr::decomp(thin) },
+        [thin as usize, 0],
+        "Decomposing a thin pointer yielded unexpected result"
+    );
+    assert_eq!(
+        unsafe { fatptr::recomp::<Implementer1>(thin as *mut u8, 0) } as *const Implementer1,
+        thin,
+        "Recomposing a thin pointer yielded unexpected result"
+    );
+
+    let data1_vtable2: &dyn TestTrait = unsafe { &*fatptr::recomp(data1 as *mut u8, vtable2) };
+    let data2_vtable1: &dyn TestTrait = unsafe { &*fatptr::recomp(data2 as *mut u8, vtable1) };
     assert_eq!(
         data1_vtable2.calc(),
         1 + 20,
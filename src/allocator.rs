@@ -0,0 +1,118 @@
+//! A stable stand-in for the unstable `core::alloc::Allocator` trait.
+
+use alloc::alloc::{alloc, dealloc, realloc, Layout};
+use core::ptr::{self, NonNull};
+
+/// The error returned when an [`Allocator`] fails to provide memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AllocError;
+
+/// An allocator that a [`DynStack`](crate::DynStack) can store its trait objects in.
+///
+/// This mirrors the unstable `core::alloc::Allocator` trait, so custom allocators can be used on
+/// stable Rust.
+///
+/// # Safety
+///
+/// Memory blocks returned by an allocator must fit the requested layout, and must stay valid until
+/// they are passed to `deallocate`, `grow` or `shrink`.
+pub unsafe trait Allocator {
+    /// Allocate a block of memory fitting `layout`.
+    fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError>;
+
+    /// Deallocate a block of memory.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been allocated by this allocator, with `layout`.
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout);
+
+    /// Grow a block of memory, preserving its contents.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been allocated by this allocator, with `old_layout`. `new_layout` must be at
+    /// least as large as `old_layout`.
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<u8>, AllocError> {
+        realloc_by_copy(self, ptr, old_layout, new_layout)
+    }
+
+    /// Shrink a block of memory, preserving as much of its contents as fits.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must have been allocated by this allocator, with `old_layout`. `new_layout` must be no
+    /// larger than `old_layout`.
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<u8>, AllocError> {
+        realloc_by_copy(self, ptr, old_layout, new_layout)
+    }
+}
+
+/// Move a block of memory into a new allocation, then free the old one.
+unsafe fn realloc_by_copy<A: Allocator + ?Sized>(
+    allocator: &A,
+    ptr: NonNull<u8>,
+    old_layout: Layout,
+    new_layout: Layout,
+) -> Result<NonNull<u8>, AllocError> {
+    let new_ptr = allocator.allocate(new_layout)?;
+    let size = old_layout.size().min(new_layout.size());
+    ptr::copy_nonoverlapping(ptr.as_ptr(), new_ptr.as_ptr(), size);
+    allocator.deallocate(ptr, old_layout);
+    Ok(new_ptr)
+}
+
+/// The global memory allocator.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Global;
+
+unsafe impl Allocator for Global {
+    fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+        if layout.size() == 0 {
+            // The global allocator can't hand out zero-sized blocks, but any aligned address
+            // serves as one.
+            return Ok(unsafe { NonNull::new_unchecked(layout.align() as *mut u8) });
+        }
+        NonNull::new(unsafe { alloc(layout) }).ok_or(AllocError)
+    }
+
+    unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+        if layout.size() != 0 {
+            dealloc(ptr.as_ptr(), layout);
+        }
+    }
+
+    unsafe fn grow(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<u8>, AllocError> {
+        if old_layout.size() == 0 || old_layout.align() != new_layout.align() {
+            return realloc_by_copy(self, ptr, old_layout, new_layout);
+        }
+        NonNull::new(realloc(ptr.as_ptr(), old_layout, new_layout.size())).ok_or(AllocError)
+    }
+
+    unsafe fn shrink(
+        &self,
+        ptr: NonNull<u8>,
+        old_layout: Layout,
+        new_layout: Layout,
+    ) -> Result<NonNull<u8>, AllocError> {
+        if new_layout.size() == 0 || old_layout.align() != new_layout.align() {
+            return realloc_by_copy(self, ptr, old_layout, new_layout);
+        }
+        NonNull::new(realloc(ptr.as_ptr(), old_layout, new_layout.size())).ok_or(AllocError)
+    }
+}
@@ -0,0 +1,100 @@
+//! A builder for [`DynStack`]s with a non-default buffer layout.
+
+use crate::{Allocator, DynStack, Global};
+use core::{marker::PhantomData, mem};
+
+/// Configures the buffer of a [`DynStack`] before creating it. Returned by
+/// [`DynStack::builder`].
+///
+/// By default, buffers are at least 16 bytes large and aligned to 16 bytes. Raising the base
+/// alignment to that of the strictest item that will be pushed means the buffer never has to be
+/// moved to realign its contents when it grows.
+pub struct DynStackBuilder<T: ?Sized, A: Allocator = Global> {
+    min_capacity: usize,
+    base_align: usize,
+    growth_factor: (usize, usize),
+    allocator: A,
+    _spooky: PhantomData<fn() -> *const T>,
+}
+
+impl<T: ?Sized> DynStackBuilder<T> {
+    pub(crate) fn new() -> Self {
+        DynStackBuilder {
+            min_capacity: 16,
+            base_align: 16,
+            growth_factor: (2, 1),
+            allocator: Global,
+            _spooky: PhantomData,
+        }
+    }
+}
+
+impl<T: ?Sized, A: Allocator> DynStackBuilder<T, A> {
+    /// Sets the smallest buffer the stack allocates, in bytes. Buffer sizes are still rounded up
+    /// to a power of two. Defaults to 16.
+    ///
+    /// The buffer is allocated on the first push, like with [`DynStack::new`].
+    pub fn min_capacity(mut self, bytes: usize) -> Self {
+        self.min_capacity = bytes;
+        self
+    }
+
+    /// Sets the alignment every buffer the stack allocates has at least. Defaults to 16.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `align` is not a power of two, or is less than the alignment of `usize`.
+    pub fn base_align(mut self, align: usize) -> Self {
+        assert!(
+            align.is_power_of_two() && align >= mem::align_of::<usize>(),
+            "base alignment must be a power of two, and at least the alignment of usize"
+        );
+        self.base_align = align;
+        self
+    }
+
+    /// Sets the factor the buffer grows by when a push doesn't fit, as the fraction
+    /// `numerator / denominator`. Defaults to 2, which keeps the capacity a power of two.
+    ///
+    /// A smaller factor, like `growth_factor(3, 2)`, wastes less memory on a mostly full buffer,
+    /// but reallocates more often while the stack grows, and each reallocation may copy the whole
+    /// buffer. A push still grows the buffer enough to fit the pushed item.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the factor is not greater than 1.
+    pub fn growth_factor(mut self, numerator: usize, denominator: usize) -> Self {
+        assert!(
+            denominator > 0 && numerator > denominator,
+            "growth factor must be greater than 1"
+        );
+        self.growth_factor = (numerator, denominator);
+        self
+    }
+
+    /// Sets the allocator the stack allocates its memory with.
+    pub fn allocator<B: Allocator>(self, allocator: B) -> DynStackBuilder<T, B> {
+        DynStackBuilder {
+            min_capacity: self.min_capacity,
+            base_align: self.base_align,
+            growth_factor: self.growth_factor,
+            allocator,
+            _spooky: PhantomData,
+        }
+    }
+
+    /// Creates the configured, empty, [`DynStack`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if pointers to `T` are neither thin, nor a data pointer and one `usize` of metadata.
+    pub fn build(self) -> DynStack<T, A> {
+        let mut stack = DynStack::new_in(self.allocator);
+        stack.base_align = self.base_align;
+        stack.max_align = self.base_align;
+        stack.buf_align = self.base_align;
+        stack.min_cap = self.min_capacity;
+        stack.growth_factor = self.growth_factor;
+        stack
+    }
+}
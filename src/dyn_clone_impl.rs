@@ -0,0 +1,22 @@
+//! `Clone` support, enabled by the `dyn-clone` feature.
+
+use crate::{Allocator, DynStack};
+use dyn_clone::DynClone;
+
+/// Clones every item into a new stack, which uses a clone of the allocator.
+///
+/// Each item is cloned into a temporary box with `dyn_clone::clone_box`, and moved from there
+/// onto the new stack.
+impl<T: ?Sized + DynClone, A: Allocator + Clone> Clone for DynStack<T, A> {
+    fn clone(&self) -> Self {
+        let mut out = self.empty_clone();
+        out.reserve(self.byte_len());
+        out.offs_table.reserve_exact(self.len());
+        for item in self.iter() {
+            out.push_box(dyn_clone::clone_box(item));
+        }
+        out.type_ids = self.type_ids.clone();
+        out.needs_drop = self.needs_drop;
+        out
+    }
+}
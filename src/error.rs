@@ -0,0 +1,69 @@
+//! Errors returned by fallible [`DynStack`](crate::DynStack) operations.
+
+use alloc::alloc::Layout;
+use core::fmt;
+
+/// The error returned by [`DynStack::try_push`](crate::DynStack::try_push).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PushError {
+    /// The allocator failed to provide memory for the pushed item.
+    AllocFailed,
+}
+
+impl fmt::Display for PushError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PushError::AllocFailed => f.write_str("memory allocation failed"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for PushError {}
+
+/// The error returned by [`DynStack::try_reserve`](crate::DynStack::try_reserve).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TryReserveError {
+    /// The requested capacity would exceed `isize::MAX` bytes.
+    CapacityOverflow,
+    /// The allocator failed to provide memory for the new buffer.
+    AllocFailed {
+        /// The layout of the buffer that couldn't be allocated.
+        layout: Layout,
+    },
+}
+
+impl fmt::Display for TryReserveError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TryReserveError::CapacityOverflow => f.write_str("capacity overflow"),
+            TryReserveError::AllocFailed { .. } => f.write_str("memory allocation failed"),
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for TryReserveError {}
+
+/// The error returned by [`DynStack::replace`](crate::DynStack::replace).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReplaceError {
+    /// The index is past the end of the stack.
+    OutOfBounds,
+    /// The new item's size or alignment differs from the item it would replace.
+    LayoutMismatch,
+}
+
+impl fmt::Display for ReplaceError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplaceError::OutOfBounds => f.write_str("index out of bounds"),
+            ReplaceError::LayoutMismatch => {
+                f.write_str("replacement item's size or alignment differs")
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for ReplaceError {}
@@ -1,21 +1,74 @@
-/// Decompose a fat pointer into its constituent [pointer, extdata] pair
+//! Splitting pointers to `T` into their data pointer and metadata, and putting them back together.
+//!
+//! By default, this reinterprets the pointers' memory representation. When built with
+//! `--cfg dynstack_ptr_metadata` on a nightly compiler, the unstable `core::ptr::metadata` API is
+//! used instead, which lets tools like Miri follow the pointers' provenance.
+
+use core::{mem, ptr};
+
+/// Decompose a pointer into its constituent [pointer, extdata] pair. Thin pointers have no
+/// extdata, so 0 is returned in its place.
 ///
 /// # Safety
 ///
-/// Must only be called with the generic, `T`, being a trait object.
+/// Pointers to `T` must either be thin, or consist of a data pointer followed by one `usize` of
+/// extdata, like trait objects and slices.
+#[cfg(not(dynstack_ptr_metadata))]
 pub unsafe fn decomp<T: ?Sized>(ptr: *const T) -> [usize; 2] {
+    let mut components = [0usize; 2];
     let ptr_ref: *const *const T = &ptr;
-    let decomp_ref = ptr_ref as *const [usize; 2];
-    *decomp_ref
+    ptr::copy_nonoverlapping(
+        ptr_ref as *const u8,
+        components.as_mut_ptr() as *mut u8,
+        mem::size_of::<*const T>(),
+    );
+    components
+}
+
+/// Recompose a pointer from its data pointer and extdata. The extdata is ignored if pointers to
+/// `T` are thin.
+///
+/// # Safety
+///
+/// Pointers to `T` must either be thin, or consist of a data pointer followed by one `usize` of
+/// extdata, like trait objects and slices.
+#[cfg(not(dynstack_ptr_metadata))]
+pub unsafe fn recomp<T: ?Sized>(data: *mut u8, extdata: usize) -> *mut T {
+    // `data` stays a pointer throughout, so the result keeps its provenance.
+    #[repr(C)]
+    struct Parts {
+        data: *mut u8,
+        extdata: usize,
+    }
+    mem::transmute_copy(&Parts { data, extdata })
+}
+
+/// Decompose a pointer into its constituent [pointer, extdata] pair. Thin pointers have no
+/// extdata, so 0 is returned in its place.
+///
+/// # Safety
+///
+/// The metadata of pointers to `T` must fit in a `usize`.
+#[cfg(dynstack_ptr_metadata)]
+pub unsafe fn decomp<T: ?Sized>(ptr: *const T) -> [usize; 2] {
+    let metadata = ptr::metadata(ptr);
+    let mut extdata = 0usize;
+    ptr::copy_nonoverlapping(
+        &metadata as *const _ as *const u8,
+        &mut extdata as *mut usize as *mut u8,
+        mem::size_of_val(&metadata),
+    );
+    [ptr as *const u8 as usize, extdata]
 }
 
-/// Recompose a fat pointer from its constituent [pointer, extdata] pair
+/// Recompose a pointer from its data pointer and extdata. The extdata is ignored if pointers to
+/// `T` are thin.
 ///
 /// # Safety
 ///
-/// Must only be called with the generic, `T`, being a trait object.
-pub unsafe fn recomp<T: ?Sized>(components: [usize; 2]) -> *mut T {
-    let component_ref: *const [usize; 2] = &components;
-    let ptr_ref = component_ref as *const *mut T;
-    *ptr_ref
+/// `extdata` must have been returned by [`decomp`] for a pointer to `T`.
+#[cfg(dynstack_ptr_metadata)]
+pub unsafe fn recomp<T: ?Sized>(data: *mut u8, extdata: usize) -> *mut T {
+    let metadata: <T as ptr::Pointee>::Metadata = mem::transmute_copy(&extdata);
+    ptr::from_raw_parts_mut(data, metadata)
 }
@@ -0,0 +1,83 @@
+//! A read-only view of a finished [`DynStack`], for sharing between threads.
+
+use crate::{Allocator, DynStack, DynStackIter, Global};
+use core::{fmt, ops::Index};
+
+/// A [`DynStack`] that can no longer be modified. Returned by [`DynStack::freeze`].
+///
+/// It only gives shared access to its items, so it can be handed to several threads at once, for
+/// example through an `Arc`, without locking. It is `Sync` if the items are. Call
+/// [`thaw`](FrozenDynStack::thaw) to get the stack back.
+///
+/// ```
+/// # use dynstack::{DynStack, dyn_push};
+/// # use std::{fmt::Debug, sync::Arc, thread};
+/// let mut stack = DynStack::<dyn Debug + Send + Sync>::new();
+/// dyn_push!(stack, 1u8);
+/// dyn_push!(stack, "two");
+/// let frozen = Arc::new(stack.freeze());
+///
+/// let shared = Arc::clone(&frozen);
+/// let debug = thread::spawn(move || format!("{:?}", shared)).join().unwrap();
+/// assert_eq!(debug, r#"[1, "two"]"#);
+///
+/// let mut stack = Arc::try_unwrap(frozen).unwrap().thaw();
+/// dyn_push!(stack, 3u32);
+/// ```
+pub struct FrozenDynStack<T: ?Sized, A: Allocator = Global> {
+    stack: DynStack<T, A>,
+}
+
+impl<T: ?Sized, A: Allocator> FrozenDynStack<T, A> {
+    pub(crate) fn new(stack: DynStack<T, A>) -> Self {
+        FrozenDynStack { stack }
+    }
+
+    /// Retrieve a trait object reference at the provided index.
+    pub fn get(&self, index: usize) -> Option<&T> {
+        self.stack.get(index)
+    }
+
+    /// Returns an iterator over trait object references, in stack order.
+    pub fn iter(&self) -> DynStackIter<'_, T, A> {
+        self.stack.iter()
+    }
+
+    /// Returns the number of trait objects stored on the stack.
+    pub fn len(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// Returns `true` if the stack holds no trait objects.
+    pub fn is_empty(&self) -> bool {
+        self.stack.is_empty()
+    }
+
+    /// Make the stack mutable again.
+    pub fn thaw(self) -> DynStack<T, A> {
+        self.stack
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, A: Allocator> fmt::Debug for FrozenDynStack<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.stack.fmt(f)
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Index<usize> for FrozenDynStack<T, A> {
+    type Output = T;
+
+    fn index(&self, idx: usize) -> &T {
+        &self.stack[idx]
+    }
+}
+
+impl<'a, T: 'a + ?Sized, A: Allocator> IntoIterator for &'a FrozenDynStack<T, A> {
+    type Item = &'a T;
+    type IntoIter = DynStackIter<'a, T, A>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
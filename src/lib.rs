@@ -19,29 +19,99 @@
 //! //  0
 //! //  [1, 2, 3, 4, 5, 6]
 //! ```
+//!
+//! Besides trait objects, a stack can hold slices, like `DynStack<[u8]>`, and string slices,
+//! `DynStack<str>`. Their pointers carry a length rather than a vtable, and each item keeps its
+//! own length.
+//!
+//! ```
+//! # use dynstack::{DynStack, dyn_push};
+//! let mut stack = DynStack::<[u8]>::new();
+//! dyn_push!(stack, [1u8, 2, 3]);
+//! dyn_push!(stack, [4u8, 5]);
+//! assert_eq!(&stack[0], &[1, 2, 3]);
+//! assert_eq!(&stack[1], &[4, 5]);
+//! ```
+//!
+//! `dynstack` only depends on `core` and `alloc`, so it can be used in `#![no_std]` crates by
+//! disabling the default `std` feature.
 
-#![cfg_attr(not(feature = "std"), no_std)]
+#![no_std]
+#![cfg_attr(dynstack_ptr_metadata, feature(ptr_metadata))]
 #![deny(rust_2018_idioms)]
 
 extern crate alloc;
+#[cfg(any(feature = "std", test))]
+extern crate std;
 
 use alloc::{
-    alloc::{alloc, dealloc, Layout},
+    alloc::{alloc, dealloc, handle_alloc_error, Layout},
+    boxed::Box,
     vec::Vec,
 };
 use core::{
+    cmp, fmt,
+    hash::{Hash, Hasher},
+    iter::{Enumerate, FromIterator, FusedIterator},
     marker::PhantomData,
     mem,
     ops::{Index, IndexMut},
-    ptr,
+    ptr::{self, NonNull},
+    slice,
+    sync::atomic::{AtomicUsize, Ordering},
 };
+#[cfg(test)]
+use std::{
+    format, println,
+    string::{String, ToString},
+    thread_local, vec,
+};
+#[cfg(test)]
+use test_util::{Aligned64, Item};
 
+mod allocator;
+mod builder;
+#[cfg(feature = "dyn-clone")]
+mod dyn_clone_impl;
+mod error;
 mod fatptr;
+mod frozen;
+#[cfg(feature = "rayon")]
+mod rayon_impl;
+#[cfg(feature = "serde")]
+mod serde_impl;
+mod small;
+#[cfg(test)]
+mod test_util;
+mod type_ids;
+
+pub use allocator::{AllocError, Allocator, Global};
+pub use builder::DynStackBuilder;
+pub use error::{PushError, ReplaceError, TryReserveError};
+pub use frozen::FrozenDynStack;
+#[cfg(feature = "rayon")]
+pub use rayon_impl::{DynStackParIter, DynStackParIterMut};
+pub use small::SmallDynStack;
+#[cfg(feature = "type-ids")]
+#[doc(hidden)]
+pub use type_ids::type_id_of as __type_id_of;
+use type_ids::TypeIds;
 
-/// Rounds up an integer to the nearest `align`
+/// Returns whether the value a pushing macro is about to push has a destructor.
+#[doc(hidden)]
+pub fn __needs_drop_of<U>(_: &U) -> bool {
+    mem::needs_drop::<U>()
+}
+
+/// Rounds up an integer to the nearest `align`, which must be a power of two.
+///
+/// Panics if the result doesn't fit in a `usize`, rather than wrapping around to a misaligned
+/// value. Any other `align`, including 0, produces a meaningless result, which debug builds
+/// catch with an assertion.
 fn align_up(num: usize, align: usize) -> usize {
-    let align_bits = align.trailing_zeros();
-    (num + align - 1) >> align_bits << align_bits
+    debug_assert!(align.is_power_of_two(), "alignment must be a power of two");
+    let mask = align - 1;
+    num.checked_add(mask).expect("capacity overflow") & !mask
 }
 
 #[test]
@@ -56,99 +126,384 @@ fn test_align_up() {
     }
 }
 
+#[test]
+fn test_align_up_large() {
+    for &alignment in &[2, 8, 64, 4096] {
+        let top = usize::MAX - (alignment - 1);
+        assert_eq!(align_up(top, alignment), top);
+        assert_eq!(align_up(top - 1, alignment), top);
+        assert_eq!(align_up(top - alignment + 1, alignment), top);
+    }
+    assert_eq!(align_up(usize::MAX, 1), usize::MAX);
+}
+
+#[test]
+#[should_panic(expected = "capacity overflow")]
+fn test_align_up_overflow() {
+    align_up(usize::MAX - 6, 8);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "alignment must be a power of two")]
+fn test_align_up_zero() {
+    align_up(5, 0);
+}
+
+#[test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "alignment must be a power of two")]
+fn test_align_up_not_power_of_two() {
+    align_up(5, 3);
+}
+
+/// Aborts through `handle_alloc_error` if an internal allocation failed.
+fn unwrap_alloc<R>(result: Result<R, Layout>) -> R {
+    match result {
+        Ok(out) => out,
+        Err(layout) => handle_alloc_error(layout),
+    }
+}
+
 /// Iterator over trait object references
-pub struct DynStackIter<'a, T: ?Sized> {
-    stack: &'a DynStack<T>,
+pub struct DynStackIter<'a, T: ?Sized, A: Allocator = Global> {
+    stack: &'a DynStack<T, A>,
     index: usize,
+    /// One past the index of the last item to yield.
+    end: usize,
 }
 
-impl<'a, T: 'a + ?Sized> Iterator for DynStackIter<'a, T> {
+impl<'a, T: 'a + ?Sized, A: Allocator> Iterator for DynStackIter<'a, T, A> {
     type Item = &'a T;
 
     fn next(&mut self) -> Option<&'a T> {
-        self.stack.get(self.index).map(|out| {
-            self.index += 1;
-            out
-        })
+        if self.index >= self.end {
+            return None;
+        }
+        let out = self.stack.get(self.index)?;
+        self.index += 1;
+        Some(out)
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        let size = self.stack.len() - self.index;
+        let size = self.end - self.index;
         (size, Some(size))
     }
+
+    fn nth(&mut self, n: usize) -> Option<&'a T> {
+        self.index = self.index.saturating_add(n).min(self.end);
+        self.next()
+    }
 }
 
-impl<'a, T: 'a + ?Sized> ExactSizeIterator for DynStackIter<'a, T> {}
+impl<'a, T: 'a + ?Sized, A: Allocator> ExactSizeIterator for DynStackIter<'a, T, A> {}
+
+impl<'a, T: 'a + ?Sized, A: Allocator> FusedIterator for DynStackIter<'a, T, A> {}
 
 /// Iterator over mutable trait object references
-pub struct DynStackIterMut<'a, T: ?Sized> {
-    stack: *mut DynStack<T>,
-    index: usize,
-    _spooky: PhantomData<&'a mut DynStack<T>>,
+///
+/// The iterator borrows the stack mutably, but only keeps its buffer pointer and the offset table
+/// entries of the items left to yield. Each item is reached from the buffer pointer and its own
+/// entry, and distinct items never share bytes, so the yielded references don't alias each other.
+pub struct DynStackIterMut<'a, T: ?Sized, A: Allocator = Global> {
+    data: *mut u8,
+    entries: slice::Iter<'a, (usize, usize)>,
+    _spooky: PhantomData<&'a mut DynStack<T, A>>,
+}
+
+impl<'a, T: ?Sized, A: Allocator> DynStackIterMut<'a, T, A> {
+    fn item(&self, &(offs, vtable): &(usize, usize)) -> &'a mut T {
+        unsafe { &mut *fatptr::recomp(self.data.add(offs), vtable) }
+    }
 }
 
-impl<'a, T: 'a + ?Sized> Iterator for DynStackIterMut<'a, T> {
+impl<'a, T: 'a + ?Sized, A: Allocator> Iterator for DynStackIterMut<'a, T, A> {
     type Item = &'a mut T;
 
     fn next(&mut self) -> Option<&'a mut T> {
-        unsafe {
-            (*self.stack).get_mut(self.index).map(|out| {
-                self.index += 1;
-                out
-            })
-        }
+        let entry = self.entries.next()?;
+        Some(self.item(entry))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.entries.size_hint()
+    }
+
+    fn nth(&mut self, n: usize) -> Option<&'a mut T> {
+        let entry = self.entries.nth(n)?;
+        Some(self.item(entry))
+    }
+}
+
+impl<'a, T: 'a + ?Sized, A: Allocator> ExactSizeIterator for DynStackIterMut<'a, T, A> {}
+
+impl<'a, T: 'a + ?Sized, A: Allocator> FusedIterator for DynStackIterMut<'a, T, A> {}
+
+/// Iterator over trait object references, from the top of the stack down
+pub struct DynStackIterRev<'a, T: ?Sized, A: Allocator = Global> {
+    stack: &'a DynStack<T, A>,
+    /// The number of items left, which is also one past the index of the next one.
+    remaining: usize,
+}
+
+impl<'a, T: 'a + ?Sized, A: Allocator> Iterator for DynStackIterRev<'a, T, A> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        self.remaining = self.remaining.checked_sub(1)?;
+        self.stack.get(self.remaining)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl<'a, T: 'a + ?Sized, A: Allocator> ExactSizeIterator for DynStackIterRev<'a, T, A> {}
+
+impl<'a, T: 'a + ?Sized, A: Allocator> FusedIterator for DynStackIterRev<'a, T, A> {}
+
+/// Iterator over mutable trait object references, from the top of the stack down
+///
+/// Like [`DynStackIterMut`], it only keeps the stack's buffer pointer and offset table entries.
+pub struct DynStackIterRevMut<'a, T: ?Sized, A: Allocator = Global> {
+    inner: DynStackIterMut<'a, T, A>,
+}
+
+impl<'a, T: 'a + ?Sized, A: Allocator> Iterator for DynStackIterRevMut<'a, T, A> {
+    type Item = &'a mut T;
+
+    fn next(&mut self) -> Option<&'a mut T> {
+        let entry = self.inner.entries.next_back()?;
+        Some(self.inner.item(entry))
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.inner.size_hint()
+    }
+}
+
+impl<'a, T: 'a + ?Sized, A: Allocator> ExactSizeIterator for DynStackIterRevMut<'a, T, A> {}
+
+impl<'a, T: 'a + ?Sized, A: Allocator> FusedIterator for DynStackIterRevMut<'a, T, A> {}
+
+/// Iterator moving the trait objects out of a stack into boxes, from the bottom up
+pub struct DynStackIntoIter<T: ?Sized, A: Allocator = Global> {
+    stack: DynStack<T, A>,
+    /// The number of items already moved out, which is also the index of the next one.
+    index: usize,
+}
+
+impl<T: ?Sized, A: Allocator> Iterator for DynStackIntoIter<T, A> {
+    type Item = Box<T>;
+
+    fn next(&mut self) -> Option<Box<T>> {
+        let item: *mut T = self.stack.get_mut(self.index)?;
+        self.index += 1;
+        Some(unsafe { DynStack::<T, A>::move_to_box(item) })
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        let size = unsafe { &*self.stack }.len() - self.index;
+        let size = self.stack.len() - self.index;
         (size, Some(size))
     }
 }
 
-impl<'a, T: 'a + ?Sized> ExactSizeIterator for DynStackIterMut<'a, T> {}
+impl<T: ?Sized, A: Allocator> ExactSizeIterator for DynStackIntoIter<T, A> {}
 
-pub struct DynStack<T: ?Sized> {
+impl<T: ?Sized, A: Allocator> FusedIterator for DynStackIntoIter<T, A> {}
+
+impl<T: ?Sized, A: Allocator> Drop for DynStackIntoIter<T, A> {
+    fn drop(&mut self) {
+        // Forget the moved out items, and let the stack drop the rest along with its buffer.
+        // Removing the bottom entries keeps the offset table in byte order if it was.
+        self.stack.offs_table.drain(..self.index);
+        self.stack.type_ids.remove_front(self.index);
+    }
+}
+
+/// A recorded length of a specific [`DynStack`], created by
+/// [`checkpoint`](DynStack::checkpoint) and consumed by [`rollback`](DynStack::rollback).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Checkpoint {
+    stack_id: usize,
+    len: usize,
+}
+
+/// A summary of how a [`DynStack`]'s buffer is used, returned by
+/// [`memory_report`](DynStack::memory_report).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryReport {
+    /// The size of the buffer, as returned by [`capacity`](DynStack::capacity).
+    pub capacity: usize,
+    /// The bytes in use, as returned by [`byte_len`](DynStack::byte_len).
+    pub used_bytes: usize,
+    /// The sum of `size_of_val` over all items.
+    pub payload_bytes: usize,
+    /// The bytes in use that aren't part of any item: alignment padding, and bytes left behind by
+    /// removed items.
+    pub padding_bytes: usize,
+    /// The number of items.
+    pub len: usize,
+    /// The strictest item alignment, as returned by [`max_alignment`](DynStack::max_alignment).
+    pub max_align: usize,
+}
+
+/// The source of stack ids for checkpoints. 0 means a stack has no id yet.
+static NEXT_STACK_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// Another name for [`DynStack`], for code that uses it as a vector of trait objects.
+pub type DynVec<T, A = Global> = DynStack<T, A>;
+
+pub struct DynStack<T: ?Sized, A: Allocator = Global> {
     offs_table: Vec<(usize, usize)>,
+    /// Whether `offs_table` is ordered by offset. If it is, the last entry is the last item in
+    /// the buffer. Otherwise, the only guarantee is that all items end before `dyn_size`.
+    offs_sorted: bool,
+    /// The type of each item, if the `type-ids` feature is enabled.
+    type_ids: TypeIds,
     dyn_data: *mut u8,
     dyn_size: usize,
     dyn_cap: usize,
     max_align: usize,
+    /// The alignment the buffer was allocated with. New buffers are aligned to `max_align`, but
+    /// items with a stricter alignment may have been pushed since.
+    buf_align: usize,
+    /// The alignment `max_align` starts out at, and the least alignment of any buffer.
+    base_align: usize,
+    /// The smallest buffer size to allocate.
+    min_cap: usize,
+    /// The `(numerator, denominator)` of the factor the buffer grows by when it is full.
+    growth_factor: (usize, usize),
+    /// Whether bytes in `[0, dyn_size)` that aren't part of an item are kept zeroed.
+    zero_padding: bool,
+    /// Whether any item may have a destructor. Only the items pushed through the macros or
+    /// `push_copy` are known not to, so `clear` can forget them instead of dropping each one.
+    needs_drop: bool,
+    /// Identifies the stack in checkpoints. Assigned on the first call to `checkpoint`.
+    id: AtomicUsize,
+    /// Called whenever items have moved, see `set_relocate_hook`.
+    relocate_hook: Option<Box<RelocateHook>>,
+    allocator: A,
     _spooky: PhantomData<T>,
 }
 
-unsafe impl<T: ?Sized + Send> Send for DynStack<T> {}
-unsafe impl<T: ?Sized + Sync> Sync for DynStack<T> {}
+/// A callback notified of the new buffer pointer and offset table after items have moved.
+type RelocateHook = dyn FnMut(*mut u8, &[(usize, usize)]) + Send;
+
+unsafe impl<T: ?Sized + Send, A: Allocator + Send> Send for DynStack<T, A> {}
+unsafe impl<T: ?Sized + Sync, A: Allocator + Sync> Sync for DynStack<T, A> {}
 
 impl<T: ?Sized> DynStack<T> {
-    fn make_layout(cap: usize) -> Layout {
-        unsafe { Layout::from_size_align_unchecked(cap, 16) }
+    /// Creates a new, empty, [`DynStack`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if pointers to `T` are neither thin, nor a data pointer and one `usize` of metadata.
+    pub fn new() -> Self {
+        Self::new_in(Global)
     }
-    fn layout(&self) -> Layout {
-        Self::make_layout(self.dyn_cap)
+
+    /// Creates a new, empty, [`DynStack`] which keeps its padding bytes zeroed.
+    ///
+    /// Normally, the bytes that pad items to their alignment, or that are left behind by removed
+    /// items, hold leftover data. A stack created with this method zeroes them, so that two stacks
+    /// built by pushing the same values have the same bytes in `[as_ptr(), as_ptr() + byte_len())`,
+    /// as long as none of the items need more than 16-byte alignment and the items themselves
+    /// contain no padding. This costs an extra write for every push and removal.
+    ///
+    /// # Panics
+    ///
+    /// Panics if pointers to `T` are neither thin, nor a data pointer and one `usize` of metadata.
+    pub fn new_zeroed() -> Self {
+        Self::new_zeroed_in(Global)
     }
 
-    /// Creates a new, empty, [`DynStack`].
+    /// Creates a stack from a snapshot of another stack's buffer and offset table, as returned by
+    /// [`as_bytes`](DynStack::as_bytes) and [`offsets`](DynStack::offsets).
+    ///
+    /// The bytes are copied into a new buffer aligned to `max_align`, which should be the
+    /// snapshotted stack's [`max_alignment`](DynStack::max_alignment), and the offset table is
+    /// copied as is.
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push};
+    /// # use std::fmt::Debug;
+    /// let mut stack = DynStack::<dyn Debug>::new_zeroed();
+    /// dyn_push!(stack, 1u8);
+    /// dyn_push!(stack, [2u64; 2]);
+    /// let bytes = unsafe { stack.as_bytes() }.to_vec();
+    /// let copy = unsafe {
+    ///     DynStack::<dyn Debug>::from_raw_parts(&bytes, stack.offsets(), stack.max_alignment())
+    /// };
+    /// assert_eq!(format!("{:?}", copy), "[1, [2, 2]]");
+    /// ```
+    ///
+    /// # Safety
+    ///
+    /// - Each `(offset, vtable)` entry must describe a valid `T` in `bytes`, and the items must
+    ///   not overlap. In particular, vtable addresses are only valid within the process that took
+    ///   the snapshot.
+    /// - Each item must be aligned once `bytes` is placed in a buffer aligned to `max_align`.
+    ///   That holds for a snapshot of a stack whose [`as_ptr`](DynStack::as_ptr) was aligned to
+    ///   its `max_alignment`, like one that was reallocated since its strictest item was pushed,
+    ///   or one built with that [`base_align`](DynStackBuilder::base_align).
+    /// - The new stack owns its items, and drops them like any other. Unless they are `Copy`, the
+    ///   items they were copied from must not be dropped as well, for example by forgetting them
+    ///   with [`forget_last`](DynStack::forget_last).
     ///
     /// # Panics
     ///
-    /// Panics if `T` is not a trait object.
-    pub fn new() -> Self {
-        assert_eq!(
-            mem::size_of::<*const T>(),
-            mem::size_of::<[usize; 2]>(),
-            "Used on non trait object!"
+    /// Panics if `max_align` is not a power of two.
+    pub unsafe fn from_raw_parts(
+        bytes: &[u8],
+        offsets: &[(usize, usize)],
+        max_align: usize,
+    ) -> Self {
+        assert!(
+            max_align.is_power_of_two(),
+            "alignment must be a power of two"
         );
-        // SAFETY: We verify above that T is indeed a trait object.
-        unsafe { Self::new_unchecked() }
+        let mut stack = Self::new();
+        stack.max_align = stack.max_align.max(max_align);
+        // Items always have a buffer to point into, even zero-sized ones.
+        if !bytes.is_empty() || !offsets.is_empty() {
+            unwrap_alloc(stack.allocate(bytes.len(), 1));
+            ptr::copy_nonoverlapping(bytes.as_ptr(), stack.dyn_data, bytes.len());
+        }
+        stack.dyn_size = bytes.len();
+        stack.offs_table.extend_from_slice(offsets);
+        for _ in offsets {
+            stack.type_ids.push();
+        }
+        stack.offs_sorted = offsets.windows(2).all(|pair| pair[0].0 <= pair[1].0);
+        // The items' types are unknown.
+        stack.needs_drop = true;
+        stack
+    }
+
+    /// Creates a new, empty, [`DynStack`] with at least `bytes` bytes of preallocated storage.
+    ///
+    /// Since the stored trait objects can all have different sizes, the capacity is a byte budget
+    /// rather than an element count. Like the automatic allocation on first push, the buffer size
+    /// is rounded up to a power of two, and is at least 16 bytes. No allocation is performed if
+    /// `bytes` is 0.
+    ///
+    /// # Panics
+    ///
+    /// Panics if pointers to `T` are neither thin, nor a data pointer and one `usize` of metadata.
+    pub fn with_capacity(bytes: usize) -> Self {
+        Self::with_capacity_in(bytes, Global)
     }
 
     /// Creates a new, empty, [`DynStack`]. This method is a `const fn`, so instances can be
-    /// statically initialized. This comes at the cost of no runtime sanity check that the stack
-    /// is properly used with trait objects, which is why it is unsafe to call.
+    /// statically initialized. This comes at the cost of no runtime sanity check of the layout of
+    /// pointers to `T`, which is why it is unsafe to call.
     ///
     /// # Safety
     ///
-    /// Must only be called with the generic, `T`, being a trait object.
+    /// Pointers to the generic, `T`, must either be thin, or consist of a data pointer followed by
+    /// one `usize` of metadata. That holds for sized types, trait objects, slices and `str`.
     ///
     /// # Example
     ///
@@ -156,12 +511,8 @@ impl<T: ?Sized> DynStack<T> {
     /// # use core::fmt::Display;
     /// # use dynstack::DynStack;
     ///
-    /// // SAFETY: Storing trait objects, as required
-    /// static CORRECT: DynStack<dyn Display + Sync> = unsafe { DynStack::new_unchecked() };
-    ///
-    /// // DONT do the following, Sting is not a trait object. This compiles
-    /// // but has undefined behavior.
-    /// static WRONG: DynStack<String> = unsafe { DynStack::new_unchecked() };
+    /// // SAFETY: Trait object pointers are a data pointer and a vtable pointer, as required
+    /// static STACK: DynStack<dyn Display + Sync> = unsafe { DynStack::new_unchecked() };
     /// ```
     ///
     /// Storing it directly in a static, like above, does of course not make much sense, because
@@ -170,348 +521,5688 @@ impl<T: ?Sized> DynStack<T> {
     /// accessible, modifiable stack.
     #[inline]
     pub const unsafe fn new_unchecked() -> Self {
+        Self::new_unchecked_in(Global)
+    }
+
+    /// Returns a builder for configuring the stack's buffer before creating it.
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push};
+    /// # use std::fmt::Debug;
+    /// let mut stack: DynStack<dyn Debug> = DynStack::builder()
+    ///     .min_capacity(256)
+    ///     .base_align(64)
+    ///     .build();
+    /// dyn_push!(stack, 1u8);
+    /// assert_eq!(stack.capacity(), 256);
+    /// assert_eq!(stack.as_ptr() as usize % 64, 0);
+    /// ```
+    pub fn builder() -> DynStackBuilder<T> {
+        DynStackBuilder::new()
+    }
+}
+
+impl<T: ?Sized, A: Allocator> DynStack<T, A> {
+    /// Panics if `cap` exceeds `isize::MAX` once rounded up to `align`.
+    fn make_layout(cap: usize, align: usize) -> Layout {
+        Layout::from_size_align(cap, align).expect("capacity overflow")
+    }
+    fn layout(&self) -> Layout {
+        Self::make_layout(self.dyn_cap, self.buf_align)
+    }
+
+    /// Creates a new, empty, [`DynStack`], which allocates its memory using `allocator`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if pointers to `T` are neither thin, nor a data pointer and one `usize` of metadata.
+    pub fn new_in(allocator: A) -> Self {
+        let ptr_size = mem::size_of::<*const T>();
+        assert!(
+            ptr_size == mem::size_of::<usize>() || ptr_size == mem::size_of::<[usize; 2]>(),
+            "Used on a type with unsupported pointer metadata!"
+        );
+        // SAFETY: We verify above that pointers to T have a supported layout.
+        unsafe { Self::new_unchecked_in(allocator) }
+    }
+
+    /// Creates a new, empty, [`DynStack`] which keeps its padding bytes zeroed, and allocates
+    /// its memory using `allocator`. See [`new_zeroed`](DynStack::new_zeroed).
+    ///
+    /// # Panics
+    ///
+    /// Panics if pointers to `T` are neither thin, nor a data pointer and one `usize` of metadata.
+    pub fn new_zeroed_in(allocator: A) -> Self {
+        let mut stack = Self::new_in(allocator);
+        stack.zero_padding = true;
+        stack
+    }
+
+    /// Creates a new, empty, [`DynStack`] with at least `bytes` bytes of storage preallocated
+    /// using `allocator`. See [`with_capacity`](DynStack::with_capacity).
+    ///
+    /// # Panics
+    ///
+    /// Panics if pointers to `T` are neither thin, nor a data pointer and one `usize` of metadata.
+    pub fn with_capacity_in(bytes: usize, allocator: A) -> Self {
+        let mut stack = Self::new_in(allocator);
+        if bytes > 0 {
+            unwrap_alloc(stack.allocate(bytes, 1));
+            // Assume elements of around 16 bytes when sizing the offset table.
+            stack.offs_table.reserve(bytes / 16);
+        }
+        stack
+    }
+
+    /// Creates a new, empty, [`DynStack`], which allocates its memory using `allocator`.
+    /// See [`new_unchecked`](DynStack::new_unchecked).
+    ///
+    /// # Safety
+    ///
+    /// Pointers to the generic, `T`, must either be thin, or consist of a data pointer followed by
+    /// one `usize` of metadata. That holds for sized types, trait objects, slices and `str`.
+    #[inline]
+    pub const unsafe fn new_unchecked_in(allocator: A) -> Self {
         Self {
             offs_table: Vec::new(),
+            offs_sorted: true,
+            type_ids: TypeIds::new(),
             dyn_data: ptr::null_mut(),
             dyn_size: 0,
             dyn_cap: 0,
             max_align: 16,
+            buf_align: 16,
+            base_align: 16,
+            min_cap: 16,
+            growth_factor: (2, 1),
+            zero_padding: false,
+            needs_drop: false,
+            id: AtomicUsize::new(0),
+            relocate_hook: None,
+            allocator,
             _spooky: PhantomData,
         }
     }
 
+    /// Returns a reference to the stack's allocator.
+    pub fn allocator(&self) -> &A {
+        &self.allocator
+    }
+
     /// Called on first push to allocate heap data.
     /// `DynStack::new` does not perform any allocation,
     /// since it makes creating `DynStack` instances a lot faster.
-    fn allocate(&mut self, item_size: usize) {
+    ///
+    /// The buffer is aligned to at least `item_align`, so that the first item starts at offset 0
+    /// without padding. `max_align` is raised to match, unless the allocation fails.
+    ///
+    /// On failure, returns the layout that couldn't be allocated.
+    fn allocate(&mut self, item_size: usize, item_align: usize) -> Result<(), Layout> {
+        let max_align = self.max_align;
+        self.max_align = max_align.max(item_align);
         // Always allocate a power of two size, fitting the first item.
-        // At least 16 bytes.
-        let alloc_size = item_size.next_power_of_two().max(16);
+        // At least `min_cap` bytes, 16 by default.
+        let result = self.allocate_exact(item_size.max(self.min_cap).next_power_of_two());
+        if result.is_err() {
+            self.max_align = max_align;
+        }
+        result
+    }
+
+    /// Allocate a buffer of exactly `alloc_size` bytes.
+    fn allocate_exact(&mut self, alloc_size: usize) -> Result<(), Layout> {
+        let layout = Self::make_layout(alloc_size, self.max_align);
+        let data = self.allocator.allocate(layout).map_err(|_| layout)?;
+        self.dyn_data = data.as_ptr();
         self.dyn_cap = alloc_size;
-        self.dyn_data = unsafe { alloc(Self::make_layout(alloc_size)) };
+        self.buf_align = self.max_align;
+        Ok(())
     }
 
     #[cfg(test)]
-    fn reallocate(&mut self, new_cap: usize) {
+    fn reallocate(&mut self, new_cap: usize) -> Result<(), Layout> {
         let old_layout = self.layout();
-        self.dyn_cap = new_cap;
+        let new_layout = Self::make_layout(new_cap, self.max_align);
         unsafe {
             // The point of this is to maximize the chances of having changed alignment
             // characteristics, for testing purposes.
-            let new_data = alloc(self.layout());
-            ptr::copy_nonoverlapping(self.dyn_data, new_data, self.dyn_size);
-            dealloc(self.dyn_data, old_layout);
+            let new_data = self
+                .allocator
+                .allocate(new_layout)
+                .map_err(|_| new_layout)?
+                .as_ptr();
+            ptr::copy_nonoverlapping(self.dyn_data, new_data, self.dyn_size.min(new_cap));
+            self.allocator
+                .deallocate(NonNull::new_unchecked(self.dyn_data), old_layout);
             self.dyn_data = new_data;
         }
+        self.dyn_cap = new_cap;
+        self.buf_align = self.max_align;
+        Ok(())
     }
 
     #[cfg(not(test))]
-    fn reallocate(&mut self, new_cap: usize) {
-        use alloc::alloc::realloc;
+    fn reallocate(&mut self, new_cap: usize) -> Result<(), Layout> {
+        let old_layout = self.layout();
+        let new_layout = Self::make_layout(new_cap, self.max_align);
+        let data = unsafe {
+            let data = NonNull::new_unchecked(self.dyn_data);
+            if new_cap >= self.dyn_cap {
+                self.allocator.grow(data, old_layout, new_layout)
+            } else {
+                self.allocator.shrink(data, old_layout, new_layout)
+            }
+        };
+        self.dyn_data = data.map_err(|_| new_layout)?.as_ptr();
         self.dyn_cap = new_cap;
-        self.dyn_data = unsafe { realloc(self.dyn_data, self.layout(), self.dyn_cap) };
+        self.buf_align = self.max_align;
+        Ok(())
     }
 
-    /// Double the stack's capacity
-    fn grow(&mut self) {
-        let align_mask = self.max_align - 1;
-        let prev_align = self.dyn_data as usize & align_mask;
+    /// The number of bytes a reallocation must leave free past `dyn_size`, since the buffer's
+    /// contents might have to be moved forward to stay aligned. Zero once the buffer is aligned to
+    /// `max_align`.
+    fn realign_room(&self) -> usize {
+        self.max_align.saturating_sub(self.buf_align)
+    }
+
+    /// Reallocate the stack's buffer to `new_cap` bytes. If that fails, the buffer is unchanged.
+    ///
+    /// The new buffer is aligned to `max_align`. `new_cap` must leave `realign_room()` bytes of
+    /// room past `dyn_size`.
+    fn resize(&mut self, new_cap: usize) -> Result<(), Layout> {
+        let prev_data = self.dyn_data;
+        self.reallocate(new_cap)?;
 
-        let new_cap = self.dyn_cap * 2;
-        self.reallocate(new_cap);
+        // A buffer that was resized in place keeps its alignment, so nothing has to move.
+        if self.dyn_data == prev_data {
+            self.relocated();
+            return Ok(());
+        }
 
+        let align_mask = self.max_align - 1;
+        let prev_align = prev_data as usize & align_mask;
         let new_align = self.dyn_data as usize & align_mask;
-        let mut align_diff = (new_align as isize) - (prev_align as isize);
 
-        if align_diff != 0 {
-            // It's possible that, if we have an item with alignment > 16, it becomes unaligned when
-            // reallocating our buffer (since we realloc with the default alignment of 16).
-            // If that happens, we need to realign all of our buffer contents with a memmove and adjust the
-            // offset table appropriately.
+        if new_align != prev_align && !self.offs_table.is_empty() {
+            // If an item with a stricter alignment than the old buffer's was pushed, it might have
+            // become unaligned when moving to the new buffer. If that happens, we need to realign
+            // all of our buffer contents with a memmove and adjust the offset table appropriately.
+            // The new buffer is aligned to `max_align`, so this only happens once per increase of
+            // `max_align`.
 
-            let first_offset = self.offs_table[0].0 as isize;
-            if align_diff > 0 || first_offset + align_diff < 0 {
-                // Not enough padding at the start of the buf; must move foreward to align
-                align_diff = ((align_diff as usize) & align_mask) as isize;
-            }
+            let first_offset = if self.offs_sorted {
+                self.offs_table[0].0
+            } else {
+                self.offs_table.iter().map(|&(offs, _)| offs).min().unwrap()
+            };
+            let forward = prev_align.wrapping_sub(new_align) & align_mask;
+            let align_diff = if first_offset + forward >= self.max_align {
+                // Enough padding at the start of the buf; move backward to align
+                forward as isize - self.max_align as isize
+            } else {
+                forward as isize
+            };
 
             unsafe {
-                let start_ptr = self.dyn_data.offset(first_offset);
+                let start_ptr = self.dyn_data.add(first_offset);
                 let dst = start_ptr.offset(align_diff);
+                let len = self.dyn_size - first_offset;
                 debug_assert!(dst as usize >= self.dyn_data as usize);
-                debug_assert!(dst as usize <= (self.dyn_data as usize) + self.dyn_cap);
-                ptr::copy(start_ptr, dst, self.dyn_size);
+                debug_assert!(dst as usize + len <= (self.dyn_data as usize) + self.dyn_cap);
+                ptr::copy(start_ptr, dst, len);
             }
             for (ref mut offs, _) in &mut self.offs_table {
                 *offs = offs.wrapping_add(align_diff as usize);
             }
+            self.dyn_size = self.dyn_size.wrapping_add(align_diff as usize);
+            // Moving forward leaves stale bytes before the first item.
+            self.zero_bytes(0, first_offset.wrapping_add(align_diff as usize));
         }
+        self.relocated();
+        Ok(())
     }
 
-    /// Push a trait object onto the stack.
-    ///
-    /// This method is unsafe because in lieu of moving a trait object onto `push`'s stack
-    /// (not possible in rust as of 1.30.0) we copy it from the provided mutable pointer.
-    ///
-    /// The user of this method must therefore either ensure that `item` has no `Drop` impl,
-    /// or explicitly call `std::mem::forget` on `item` after pushing.
-    ///
-    /// It is highly recommended to use the `dyn_push` macro instead of calling this directly.
-    pub unsafe fn push(&mut self, item: *mut T) {
-        let size = mem::size_of_val(&*item);
-        let align = mem::align_of_val(&*item);
-
-        // If we have not yet allocated any data, start by doing so.
-        if self.dyn_data.is_null() {
-            self.allocate(size);
+    /// Notify the relocation hook, if any, that items may have moved.
+    fn relocated(&mut self) {
+        if let Some(hook) = &mut self.relocate_hook {
+            hook(self.dyn_data, &self.offs_table);
         }
+    }
 
-        let align_offs = loop {
-            let curr_ptr = self.dyn_data as usize + self.dyn_size;
-            let aligned_ptr = align_up(curr_ptr, align);
-            let align_offs = aligned_ptr - curr_ptr;
-
-            if self.dyn_size + align_offs + size > self.dyn_cap {
-                self.grow();
-            } else {
-                break align_offs;
-            }
-        };
-
-        self.dyn_data
-            .add(self.dyn_size)
-            .add(align_offs)
-            .copy_from_nonoverlapping(item as *const u8, size);
-
-        let ptr_components = fatptr::decomp(item);
-        self.offs_table
-            .push((self.dyn_size + align_offs, ptr_components[1]));
+    /// Zero `len` bytes starting at `offs`, if this stack keeps its padding zeroed.
+    fn zero_bytes(&mut self, offs: usize, len: usize) {
+        if self.zero_padding && len > 0 {
+            unsafe { ptr::write_bytes(self.dyn_data.add(offs), 0, len) };
+        }
+    }
 
-        self.dyn_size += align_offs + size;
-        self.max_align = align.max(self.max_align);
+    /// Grow the stack's capacity by the growth factor, or more if that doesn't leave
+    /// `realign_room()` bytes free.
+    fn grow(&mut self) -> Result<(), Layout> {
+        // An item that is aligned more strictly than the buffer is normally at least as large as
+        // its alignment, so growing leaves enough room to realign it. A zero-sized item isn't,
+        // and can be stored in a buffer smaller than its alignment.
+        let required = self.dyn_size + self.realign_room();
+        let (numerator, denominator) = self.growth_factor;
+        let grown = (self.dyn_cap.saturating_mul(numerator) / denominator).max(self.dyn_cap + 1);
+        self.resize(grown.max(required))
     }
 
-    /// Remove the last trait object from the stack.
-    /// Returns true if any items were removed.
-    pub fn remove_last(&mut self) -> bool {
-        if let Some(last_item) = self.peek_mut() {
-            unsafe { ptr::drop_in_place(last_item) };
-        } else {
-            return false;
+    /// Returns the number of bytes that pushing `count` items of type `U` can take up, including
+    /// alignment padding.
+    ///
+    /// This is an upper bound: it assumes the first item needs the most padding its alignment
+    /// allows, `align_of::<U>() - 1` bytes. The items after it are packed without padding, since
+    /// the size of a type is a multiple of its alignment. Items pushed with
+    /// [`push_with_align`](DynStack::push_with_align) can need more.
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push};
+    /// # use std::fmt::Debug;
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// dyn_push!(stack, 1u8);
+    /// stack.reserve(DynStack::<dyn Debug>::capacity_for::<u64>(1000));
+    /// let capacity = stack.capacity();
+    /// for i in 0..1000u64 {
+    ///     dyn_push!(stack, i);
+    /// }
+    /// assert_eq!(stack.capacity(), capacity);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics with "capacity overflow" if the result doesn't fit in a `usize`.
+    pub fn capacity_for<U>(count: usize) -> usize {
+        if count == 0 {
+            return 0;
         }
-        let (last_offs, _) = self.offs_table.pop().unwrap();
-        self.dyn_size = last_offs;
-        true
+        mem::size_of::<U>()
+            .checked_mul(count)
+            .and_then(|bytes| bytes.checked_add(mem::align_of::<U>() - 1))
+            .expect("capacity overflow")
     }
 
-    /// mem::forget the last trait object from the stack.
-    /// Returns true if any items were forgotten.
-    pub fn forget_last(&mut self) -> bool {
-        if let Some((last_offs, _)) = self.offs_table.pop() {
-            self.dyn_size = last_offs;
-            true
-        } else {
-            false
+    /// Reserve capacity for at least `additional_bytes` more bytes to be pushed onto the stack.
+    ///
+    /// The buffer is reallocated at most once, to a power of two large enough to fit the
+    /// requested bytes.
+    ///
+    /// The reserved bytes are a budget that alignment padding is also taken from: an item with
+    /// alignment `align` can need up to `align - 1` bytes of padding before it. So `n` items of
+    /// type `U` are guaranteed to fit without reallocating after reserving
+    /// [`capacity_for::<U>(n)`](DynStack::capacity_for) bytes. See also
+    /// [`max_alignment`](DynStack::max_alignment).
+    pub fn reserve(&mut self, additional_bytes: usize) {
+        match self.try_reserve(additional_bytes) {
+            Ok(()) => {}
+            Err(TryReserveError::CapacityOverflow) => panic!("capacity overflow"),
+            Err(TryReserveError::AllocFailed { layout }) => handle_alloc_error(layout),
         }
     }
 
-    /// Retrieve a trait object reference at the provided index.
-    pub fn get<'a>(&'a self, index: usize) -> Option<&'a T> {
-        let item = self.offs_table.get(index)?;
-        let components = [self.dyn_data as usize + item.0, item.1];
-        let out = unsafe { &*fatptr::recomp(components) };
-        Some(out)
+    /// Reserve capacity for at least `count` more items of type `U` to be pushed onto the stack,
+    /// in both the buffer and the offset table.
+    ///
+    /// This reserves [`capacity_for::<U>(count)`](DynStack::capacity_for) bytes, so pushing the
+    /// items doesn't reallocate the buffer.
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push};
+    /// # use std::fmt::Debug;
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// stack.reserve_elements::<u64>(100);
+    /// let data = stack.as_ptr();
+    /// for i in 0..100u64 {
+    ///     dyn_push!(stack, i);
+    /// }
+    /// assert_eq!(stack.as_ptr(), data);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics with "capacity overflow" if the stack would grow past `isize::MAX` bytes.
+    pub fn reserve_elements<U>(&mut self, count: usize) {
+        self.reserve(Self::capacity_for::<U>(count));
+        self.offs_table.reserve(count);
     }
 
-    /// Retrieve a mutable trait object reference at the provided index.
-    pub fn get_mut<'a>(&'a mut self, index: usize) -> Option<&'a mut T> {
-        let item = self.offs_table.get(index)?;
-        let components = [self.dyn_data as usize + item.0, item.1];
-        let out = unsafe { &mut *fatptr::recomp(components) };
-        Some(out)
+    /// Try to reserve capacity for at least `additional_bytes` more bytes to be pushed onto the
+    /// stack, returning an error instead of panicking or aborting if that fails.
+    ///
+    /// On success, the capacity is the same as after [`reserve`](DynStack::reserve). On failure,
+    /// the stack is unchanged.
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, TryReserveError};
+    /// # use std::fmt::Debug;
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// stack.try_reserve(100).unwrap();
+    /// assert_eq!(stack.try_reserve(usize::MAX), Err(TryReserveError::CapacityOverflow));
+    /// ```
+    pub fn try_reserve(&mut self, additional_bytes: usize) -> Result<(), TryReserveError> {
+        let required = self
+            .dyn_size
+            .checked_add(additional_bytes)
+            .ok_or(TryReserveError::CapacityOverflow)?;
+        if required <= self.dyn_cap {
+            return Ok(());
+        }
+        let new_cap = if self.dyn_data.is_null() {
+            Some(required.max(self.min_cap))
+        } else {
+            // Leave room in case the contents have to be moved to stay aligned.
+            required.checked_add(self.realign_room())
+        };
+        let new_cap = new_cap
+            .and_then(usize::checked_next_power_of_two)
+            .filter(|&cap| Layout::from_size_align(cap, self.max_align).is_ok())
+            .ok_or(TryReserveError::CapacityOverflow)?;
+        let result = if self.dyn_data.is_null() {
+            self.allocate_exact(new_cap)
+        } else {
+            self.resize(new_cap)
+        };
+        result.map_err(|layout| TryReserveError::AllocFailed { layout })
     }
 
-    /// Retrieve the trait object reference at the top of the stack.
-    pub fn peek<'a>(&'a self) -> Option<&'a T> {
-        self.get(self.len().wrapping_sub(1))
+    /// Reserve capacity for exactly `additional_bytes` more bytes to be pushed onto the stack.
+    ///
+    /// Unlike [`reserve`](DynStack::reserve), the capacity isn't rounded up to a power of two, so
+    /// no memory is wasted if the number of bytes needed is known ahead of time. Bytes for moving
+    /// the contents to realign them are still added if needed, see
+    /// [`max_alignment`](DynStack::max_alignment). Once that capacity runs out, pushing doubles
+    /// it as usual.
+    pub fn reserve_exact(&mut self, additional_bytes: usize) {
+        let required = self
+            .dyn_size
+            .checked_add(additional_bytes)
+            .expect("capacity overflow");
+        if required <= self.dyn_cap {
+            return;
+        }
+        if self.dyn_data.is_null() {
+            unwrap_alloc(self.allocate_exact(required));
+            return;
+        }
+        unwrap_alloc(self.resize(required + self.realign_room()));
+    }
+
+    /// Push a trait object onto the stack, for the pushing macros.
+    ///
+    /// The macros call this rather than `push`, so that they fail to compile when given something
+    /// other than a `DynStack`, like a `Vec` of pointers.
+    #[doc(hidden)]
+    #[inline]
+    pub unsafe fn __dyn_push(&mut self, item: *mut T, needs_drop: bool) -> usize {
+        let needs_drop = self.needs_drop || needs_drop;
+        let index = self.push_raw_owned(item);
+        self.needs_drop = needs_drop;
+        index
+    }
+
+    /// Push a trait object onto the stack and return a reference to it, for `dyn_push_ref!`.
+    #[doc(hidden)]
+    #[inline]
+    pub unsafe fn __dyn_push_mut(&mut self, item: *mut T, needs_drop: bool) -> &mut T {
+        let index = self.__dyn_push(item, needs_drop);
+        self.get_unchecked_mut(index)
+    }
+
+    /// Reserve enough capacity for the items `iter` is known to yield, returning the stack to push
+    /// them onto. Used by `dyn_extend!`, which can't name the item type itself.
+    #[doc(hidden)]
+    pub fn __reserve_for_iter<I: Iterator>(&mut self, iter: &I) -> &mut Self {
+        self.reserve(Self::capacity_for::<I::Item>(iter.size_hint().0));
+        self
+    }
+
+    /// Shrink the stack's buffer as much as possible, while still fitting its contents.
+    ///
+    /// Like the automatic allocation on first push, the buffer size stays a power of two. If the
+    /// stack is empty, the buffer is freed entirely.
+    pub fn shrink_to_fit(&mut self) {
+        self.offs_table.shrink_to_fit();
+        self.shrink_to(0);
+    }
+
+    /// Shrink the stack's buffer to at least `min_bytes` bytes, while still fitting its contents.
+    /// Has no effect if the capacity is already that small.
+    ///
+    /// The resulting capacity may exceed `min_bytes`, since the buffer size stays a power of two,
+    /// and some room may be kept for moving the contents to realign them. If the stack is empty
+    /// and `min_bytes` is 0, the buffer is freed entirely.
+    pub fn shrink_to(&mut self, min_bytes: usize) {
+        if self.dyn_data.is_null() {
+            return;
+        }
+        if self.offs_table.is_empty() && min_bytes == 0 {
+            unsafe {
+                self.allocator
+                    .deallocate(NonNull::new_unchecked(self.dyn_data), self.layout())
+            };
+            self.dyn_data = ptr::null_mut();
+            self.dyn_cap = 0;
+            self.dyn_size = 0;
+            return;
+        }
+        // Leave room in case the contents have to be moved to stay aligned.
+        let new_cap = (self.dyn_size + self.realign_room())
+            .max(min_bytes)
+            .max(self.min_cap)
+            .next_power_of_two();
+        if new_cap < self.dyn_cap {
+            unwrap_alloc(self.resize(new_cap));
+        }
+    }
+
+    /// Push a trait object onto the stack, returning its index.
+    ///
+    /// This method is unsafe because in lieu of moving a trait object onto `push`'s stack
+    /// (not possible in rust as of 1.30.0) we copy it from the provided mutable pointer.
+    ///
+    /// The user of this method must therefore either ensure that `item` has no `Drop` impl,
+    /// or explicitly call `std::mem::forget` on `item` after pushing.
+    ///
+    /// It is highly recommended to use the `dyn_push` macro instead of calling this directly.
+    ///
+    /// # Safety
+    ///
+    /// `item` must point to a valid `T`. Ownership of the pointee moves into the stack, so the
+    /// caller must not drop it afterwards. See [`push_raw_owned`](DynStack::push_raw_owned) for
+    /// the full contract.
+    pub unsafe fn push(&mut self, item: *mut T) -> usize {
+        self.push_raw_owned(item)
+    }
+
+    /// Push a trait object onto the stack by copying its bytes, taking ownership of the pointee.
+    /// Returns the pushed item's index.
+    ///
+    /// This is the primitive the other pushing methods are built on. The bytes of `*item` are
+    /// copied into the stack's buffer, and from then on the copy is the only live instance of the
+    /// value: the stack will run its destructor when it is removed.
+    ///
+    /// # Safety
+    ///
+    /// - `item` must point to a valid, initialized `T`.
+    /// - The caller must not run the pointee's destructor afterwards, for example by wrapping it
+    ///   in `ManuallyDrop` or calling `mem::forget` on it.
+    /// - Any storage backing the pointee is not touched, and must still be freed separately by
+    ///   the caller, without dropping its contents. For a `Box`, that means deallocating the
+    ///   memory of `Box::into_raw`'s result.
+    pub unsafe fn push_raw_owned(&mut self, item: *mut T) -> usize {
+        unwrap_alloc(self.push_inner(item))
+    }
+
+    /// Push a trait object onto the stack, returning a mutable reference to the stored item.
+    ///
+    /// It is highly recommended to use the `dyn_push_ref` macro instead of calling this directly.
+    ///
+    /// # Safety
+    ///
+    /// `item` must point to a valid `T`. Ownership of the pointee moves into the stack, so the
+    /// caller must not drop it afterwards. See [`push_raw_owned`](DynStack::push_raw_owned) for
+    /// the full contract.
+    pub unsafe fn push_mut(&mut self, item: *mut T) -> &mut T {
+        let index = self.push_raw_owned(item);
+        self.get_unchecked_mut(index)
+    }
+
+    /// Insert a trait object at position `index`, moving the items after it up by one position.
+    ///
+    /// Like `Vec::insert`, this takes time linear in the number of items after `index`: their
+    /// bytes are moved up in the buffer to make room for the new item, keeping each of them
+    /// aligned. If the items are out of byte order, as after [`swap`](DynStack::swap) or
+    /// [`swap_remove`](DynStack::swap_remove), the new item's bytes go at the end of the buffer
+    /// instead, and only the offset table entries move.
+    ///
+    /// ```
+    /// # use dynstack::DynStack;
+    /// # use std::fmt::Debug;
+    /// # use std::mem::ManuallyDrop;
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// stack.push_copy(1u8, |x| x);
+    /// stack.push_copy(3u64, |x| x);
+    /// let mut item = ManuallyDrop::new(String::from("two"));
+    /// unsafe { stack.insert(1, &mut *item) };
+    /// assert_eq!(format!("{:?}", stack), r#"[1, "two", 3]"#);
+    /// ```
+    ///
+    /// # Safety
+    ///
+    /// `item` must point to a valid `T`. Ownership of the pointee moves into the stack, so the
+    /// caller must not drop it afterwards. See [`push_raw_owned`](DynStack::push_raw_owned) for
+    /// the full contract.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `index > len`.
+    pub unsafe fn insert(&mut self, index: usize, item: *mut T) {
+        let len = self.len();
+        assert!(
+            index <= len,
+            "insertion index (is {}) should be <= len (is {})",
+            index,
+            len
+        );
+        if index == len {
+            self.push_raw_owned(item);
+            return;
+        }
+        if !self.offs_sorted {
+            self.push_raw_owned(item);
+            let entry = self.offs_table.pop().unwrap();
+            self.offs_table.insert(index, entry);
+            self.type_ids.pop();
+            self.type_ids.insert(index);
+            return;
+        }
+
+        let size = mem::size_of_val(&*item);
+        let align = mem::align_of_val(&*item);
+        self.max_align = self.max_align.max(align);
+        // Reserving may move the contents to realign them, so lay the items out again after.
+        let (offsets, end) = loop {
+            let (offsets, end) = self.insert_layout(index, size, align);
+            if end <= self.dyn_cap {
+                break (offsets, end);
+            }
+            self.reserve(end - self.dyn_size);
+        };
+
+        // Items only move up, so moving them from the top down never overwrites one that hasn't
+        // moved yet.
+        for (index, &new_offs) in (index..len).zip(&offsets[1..]).rev() {
+            let entry = self.offs_table[index];
+            if new_offs != entry.0 {
+                let size = self.entry_layout(entry).size();
+                ptr::copy(
+                    self.dyn_data.add(entry.0),
+                    self.dyn_data.add(new_offs),
+                    size,
+                );
+                self.offs_table[index].0 = new_offs;
+            }
+        }
+        self.dyn_data
+            .add(offsets[0])
+            .copy_from_nonoverlapping(item as *const u8, size);
+        self.offs_table
+            .insert(index, (offsets[0], fatptr::decomp(item)[1]));
+        self.type_ids.insert(index);
+        self.needs_drop = true;
+        self.dyn_size = end;
+
+        if self.zero_padding {
+            let mut end = self.end_before(index);
+            for index in index..=len {
+                let entry = self.offs_table[index];
+                self.zero_bytes(end, entry.0 - end);
+                end = entry.0 + self.entry_layout(entry).size();
+            }
+        }
+        self.relocated();
+    }
+
+    /// Returns the offsets an item of the given layout inserted at `index` and the items after it
+    /// would have, and where the last of them would end. Items never move down.
+    fn insert_layout(&self, index: usize, size: usize, align: usize) -> (Vec<usize>, usize) {
+        let data = self.dyn_data as usize;
+        let mut offsets = Vec::with_capacity(self.len() - index + 1);
+        let new_offs = align_up(data + self.end_before(index), align) - data;
+        offsets.push(new_offs);
+        let mut end = new_offs + size;
+        for &entry in &self.offs_table[index..] {
+            let layout = self.entry_layout(entry);
+            let new_offs = (align_up(data + end, layout.align()) - data).max(entry.0);
+            offsets.push(new_offs);
+            end = new_offs + layout.size();
+        }
+        (offsets, end)
+    }
+
+    /// Returns where the item before `index` ends, or 0 for the first item.
+    fn end_before(&self, index: usize) -> usize {
+        match index.checked_sub(1) {
+            Some(prev) => {
+                let entry = self.offs_table[prev];
+                entry.0 + self.entry_layout(entry).size()
+            }
+            None => 0,
+        }
+    }
+
+    /// Push a trait object onto the stack, returning `true` if the buffer had to be allocated or
+    /// reallocated to fit it.
+    ///
+    /// A reallocation invalidates pointers previously returned by [`as_ptr`](DynStack::as_ptr)
+    /// and [`as_mut_ptr`](DynStack::as_mut_ptr), as well as pointers to the stored items. Whether
+    /// a push will reallocate can be predicted with
+    /// [`capacity_remaining`](DynStack::capacity_remaining).
+    ///
+    /// # Safety
+    ///
+    /// `item` must point to a valid `T`. Ownership of the pointee moves into the stack, so the
+    /// caller must not drop it afterwards. See [`push_raw_owned`](DynStack::push_raw_owned) for
+    /// the full contract.
+    pub unsafe fn push_reporting(&mut self, item: *mut T) -> bool {
+        let (prev_data, prev_cap) = (self.dyn_data, self.dyn_cap);
+        self.push_raw_owned(item);
+        self.dyn_data != prev_data || self.dyn_cap != prev_cap
+    }
+
+    /// Push a trait object onto the stack, returning an error instead of aborting if memory
+    /// allocation fails.
+    ///
+    /// ```
+    /// # use dynstack::DynStack;
+    /// # use std::fmt::Debug;
+    /// # use std::mem::ManuallyDrop;
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// let mut item = ManuallyDrop::new(String::from("hello"));
+    /// if unsafe { stack.try_push(&mut *item) }.is_err() {
+    ///     // The stack did not take ownership, so the item must still be dropped.
+    ///     unsafe { ManuallyDrop::drop(&mut item) };
+    /// }
+    /// ```
+    ///
+    /// # Safety
+    ///
+    /// `item` must point to a valid `T`. If `Ok` is returned, ownership of the pointee moved into
+    /// the stack, so the caller must not drop it afterwards. If an error is returned, the stack is
+    /// unchanged and the caller keeps ownership.
+    pub unsafe fn try_push(&mut self, item: *mut T) -> Result<usize, PushError> {
+        self.push_inner(item).map_err(|_| PushError::AllocFailed)
+    }
+
+    /// Push a trait object onto the stack, aligned to at least `align` bytes. Returns the pushed
+    /// item's index.
+    ///
+    /// This can over-align an item beyond its natural alignment, for example to put it at the
+    /// start of a cache line. Like a naturally aligned item, it raises the
+    /// [`max_alignment`](DynStack::max_alignment) of the stack, so it stays aligned when the
+    /// buffer is reallocated. Methods that move items within the buffer, like
+    /// [`compact`](DynStack::compact) and [`retain`](DynStack::retain), only keep items at their
+    /// natural alignment though.
+    ///
+    /// ```
+    /// # use dynstack::DynStack;
+    /// # use std::fmt::Debug;
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// let mut item = 5u32;
+    /// let index = unsafe { stack.push_with_align(&mut item, 64) };
+    /// assert_eq!(&stack[index] as *const dyn Debug as *const u8 as usize % 64, 0);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `align` is not a power of two.
+    ///
+    /// # Safety
+    ///
+    /// `item` must point to a valid `T`. Ownership of the pointee moves into the stack, so the
+    /// caller must not drop it afterwards. See [`push_raw_owned`](DynStack::push_raw_owned) for
+    /// the full contract.
+    pub unsafe fn push_with_align(&mut self, item: *mut T, align: usize) -> usize {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        let align = align.max(mem::align_of_val(&*item));
+        unwrap_alloc(self.push_inner_aligned(item, align))
+    }
+
+    /// Shared implementation of `push` and `try_push`. Returns the pushed item's index.
+    /// On failure, returns the layout that couldn't be allocated.
+    unsafe fn push_inner(&mut self, item: *mut T) -> Result<usize, Layout> {
+        self.push_inner_aligned(item, mem::align_of_val(&*item))
+    }
+
+    /// Push `item` at an offset aligned to `align`, which is at least its natural alignment.
+    unsafe fn push_inner_aligned(&mut self, item: *mut T, align: usize) -> Result<usize, Layout> {
+        let size = mem::size_of_val(&*item);
+
+        // Like `Vec`, never hold more than `isize::MAX` bytes, which is as far as `Layout` and
+        // pointer offsets go. The padding before the item is counted at its largest.
+        let max_end = self
+            .dyn_size
+            .checked_add(size)
+            .and_then(|end| end.checked_add(align - 1));
+        if !matches!(max_end, Some(end) if end <= isize::MAX as usize) {
+            panic!("capacity overflow");
+        }
+
+        // If we have not yet allocated any data, start by doing so, aligned for this item.
+        // Zero-sized items get the minimum 16 byte buffer, and never need to grow it after that,
+        // unless a later one is aligned more strictly than the buffer. Then it still takes up
+        // padding bytes to get an aligned address, like any other item.
+        if self.dyn_data.is_null() {
+            self.allocate(size, align)?;
+        }
+
+        let align_offs = loop {
+            let curr_ptr = self.dyn_data as usize + self.dyn_size;
+            let aligned_ptr = align_up(curr_ptr, align);
+            let align_offs = aligned_ptr - curr_ptr;
+
+            if self.dyn_size + align_offs + size > self.dyn_cap {
+                self.grow()?;
+            } else {
+                break align_offs;
+            }
+        };
+
+        self.zero_bytes(self.dyn_size, align_offs);
+        self.dyn_data
+            .add(self.dyn_size)
+            .add(align_offs)
+            .copy_from_nonoverlapping(item as *const u8, size);
+
+        let ptr_components = fatptr::decomp(item);
+        self.offs_table
+            .push((self.dyn_size + align_offs, ptr_components[1]));
+        self.type_ids.push();
+        self.needs_drop = true;
+
+        self.dyn_size += align_offs + size;
+        self.max_align = align.max(self.max_align);
+        Ok(self.offs_table.len() - 1)
+    }
+
+    /// Push a `Copy` value onto the stack.
+    ///
+    /// Unlike [`push`](DynStack::push), this method is safe: `Copy` types can't implement `Drop`,
+    /// so nothing needs to be forgotten after the value is copied onto the stack.
+    ///
+    /// Unsizing coercions can't be expressed as a trait bound on stable Rust, so `coerce` must
+    /// convert a reference to `item` into a trait object reference. A plain `|x| x` does that.
+    ///
+    /// ```
+    /// # use dynstack::DynStack;
+    /// # use std::fmt::Debug;
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// stack.push_copy(5u32, |x| x);
+    /// stack.push_copy([1u8, 2, 3], |x| x);
+    /// assert_eq!(format!("{:?}", &stack[1]), "[1, 2, 3]");
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `coerce` returns a reference to anything but `item`.
+    pub fn push_copy<U, F>(&mut self, mut item: U, coerce: F)
+    where
+        U: Copy,
+        F: FnOnce(&mut U) -> &mut T,
+    {
+        let item_addr = &mut item as *mut U as usize;
+        let coerced: *mut T = coerce(&mut item);
+        assert_eq!(
+            coerced as *mut u8 as usize, item_addr,
+            "coerce must return a reference to the pushed item"
+        );
+        let needs_drop = self.needs_drop;
+        unsafe { self.push_raw_owned(coerced) };
+        self.needs_drop = needs_drop;
+    }
+
+    /// Push a boxed trait object onto the stack.
+    ///
+    /// The object is moved out of the box and onto the stack, and the box's allocation is freed.
+    pub fn push_box(&mut self, item: Box<T>) {
+        let size = mem::size_of_val(&*item);
+        let align = mem::align_of_val(&*item);
+        let raw = Box::into_raw(item);
+        unsafe {
+            self.push_raw_owned(raw);
+            // The contents now belong to the stack, so only free the memory without dropping.
+            if size != 0 {
+                dealloc(
+                    raw as *mut u8,
+                    Layout::from_size_align_unchecked(size, align),
+                );
+            }
+        }
+    }
+
+    /// Make the stack read-only, for sharing it between threads. See [`FrozenDynStack`].
+    pub fn freeze(self) -> FrozenDynStack<T, A> {
+        FrozenDynStack::new(self)
+    }
+
+    /// Move every trait object into its own box, in stack order.
+    ///
+    /// The stack's buffer is freed afterwards.
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push};
+    /// # use std::fmt::Debug;
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// dyn_push!(stack, 1u8);
+    /// dyn_push!(stack, String::from("two"));
+    /// let boxes: Vec<Box<dyn Debug>> = stack.into_vec_boxed();
+    /// assert_eq!(format!("{:?}", boxes), "[1, \"two\"]");
+    /// ```
+    pub fn into_vec_boxed(mut self) -> Vec<Box<T>> {
+        let boxes = self
+            .iter_mut()
+            .map(|item| unsafe { Self::move_to_box(item) })
+            .collect();
+        // The items now belong to the boxes, so only the buffer is left to free.
+        self.offs_table.clear();
+        self.type_ids.truncate(0);
+        boxes
+    }
+
+    /// Remove the last trait object from the stack, moving it into a new box.
+    /// Returns `None` if the stack is empty.
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push};
+    /// # use std::fmt::Debug;
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// dyn_push!(stack, String::from("owned"));
+    /// let item: Box<dyn Debug> = stack.pop_boxed().unwrap();
+    /// assert!(stack.is_empty());
+    /// assert_eq!(format!("{:?}", item), "\"owned\"");
+    /// ```
+    pub fn pop_boxed(&mut self) -> Option<Box<T>> {
+        let last_item: *mut T = self.peek_mut()?;
+        let out = unsafe { Self::move_to_box(last_item) };
+        self.pop_entry();
+        Some(out)
+    }
+
+    /// Move a trait object into a new box, without dropping the original.
+    ///
+    /// # Safety
+    ///
+    /// `item` must point to a valid `T`, which the caller must not drop afterwards.
+    unsafe fn move_to_box(item: *mut T) -> Box<T> {
+        let layout = Layout::for_value(&*item);
+        let data = if layout.size() == 0 {
+            layout.align() as *mut u8
+        } else {
+            let data = alloc(layout);
+            if data.is_null() {
+                handle_alloc_error(layout);
+            }
+            data
+        };
+        ptr::copy_nonoverlapping(item as *const u8, data, layout.size());
+        let vtable = fatptr::decomp(item)[1];
+        Box::from_raw(fatptr::recomp(data, vtable))
+    }
+
+    /// Remove the last trait object from the stack.
+    /// Returns true if any items were removed.
+    pub fn remove_last(&mut self) -> bool {
+        match self.peek_mut() {
+            Some(last_item) => {
+                let last_item: *mut T = last_item;
+                unsafe { self.drop_last(last_item) };
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Drop `last_item`, which must be the last item, and pop its offset table entry.
+    ///
+    /// The entry is popped even if the item's destructor panics, so the stack never refers to a
+    /// dropped item.
+    unsafe fn drop_last(&mut self, last_item: *mut T) {
+        struct PopOnDrop<'a, T: ?Sized, A: Allocator>(&'a mut DynStack<T, A>);
+        impl<T: ?Sized, A: Allocator> Drop for PopOnDrop<'_, T, A> {
+            fn drop(&mut self) {
+                self.0.pop_entry();
+            }
+        }
+
+        let _pop = PopOnDrop(self);
+        ptr::drop_in_place(last_item);
+    }
+
+    /// Remove the last trait object from the stack, calling `f` on it just before it is dropped.
+    /// Returns `f`'s result, or `None` if the stack is empty.
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push};
+    /// # use std::fmt::Debug;
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// dyn_push!(stack, String::from("hello"));
+    /// assert_eq!(stack.pop_with(|item| format!("{:?}", item)).as_deref(), Some("\"hello\""));
+    /// assert_eq!(stack.pop_with(|item| format!("{:?}", item)), None);
+    /// ```
+    pub fn pop_with<R, F: FnOnce(&mut T) -> R>(&mut self, f: F) -> Option<R> {
+        let last_item: *mut T = self.peek_mut()?;
+        let out = f(unsafe { &mut *last_item });
+        unsafe { self.drop_last(last_item) };
+        Some(out)
+    }
+
+    /// Retain only the trait objects for which `f` returns `true`, dropping the rest.
+    ///
+    /// Like `Vec::retain`, `f` is called on every item in order, and the order of the retained
+    /// items is preserved. The retained items are then moved down to fill the freed bytes.
+    pub fn retain<F: FnMut(&T) -> bool>(&mut self, mut f: F) {
+        self.retain_entries(|item| f(item));
+    }
+
+    /// Retain only the trait objects for which `f` returns `true`, dropping the rest. Unlike
+    /// [`retain`](DynStack::retain), `f` can modify the items.
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push};
+    /// trait Ttl {
+    ///     fn tick(&mut self) -> u32;
+    /// }
+    /// impl Ttl for u32 {
+    ///     fn tick(&mut self) -> u32 {
+    ///         *self -= 1;
+    ///         *self
+    ///     }
+    /// }
+    ///
+    /// let mut stack = DynStack::<dyn Ttl>::new();
+    /// dyn_push!(stack, 1u32);
+    /// dyn_push!(stack, 3u32);
+    /// stack.retain_mut(|item| item.tick() > 0);
+    /// assert_eq!(stack.len(), 1);
+    /// ```
+    pub fn retain_mut<F: FnMut(&mut T) -> bool>(&mut self, f: F) {
+        self.retain_entries(f);
+    }
+
+    /// Shared implementation of the `retain` methods.
+    fn retain_entries<F: FnMut(&mut T) -> bool>(&mut self, mut f: F) {
+        /// The first `kept` entries are the kept items, followed by `index - kept` entries of
+        /// removed items, and the entries from `index` on are yet to be visited. Once done, or if
+        /// `f` or a destructor panics, the removed entries are dropped from the table and the
+        /// remaining items are packed, so that no dropped item stays reachable.
+        struct Retain<'a, T: ?Sized, A: Allocator> {
+            stack: &'a mut DynStack<T, A>,
+            index: usize,
+            kept: usize,
+        }
+        impl<T: ?Sized, A: Allocator> Drop for Retain<'_, T, A> {
+            fn drop(&mut self) {
+                let stack = &mut *self.stack;
+                let len = stack.len();
+                for from in self.index..len {
+                    let to = from - (self.index - self.kept);
+                    stack.offs_table[to] = stack.offs_table[from];
+                    stack.type_ids.move_down(from, to);
+                }
+                let new_len = len - (self.index - self.kept);
+                stack.offs_table.truncate(new_len);
+                stack.type_ids.truncate(new_len);
+                stack.pack();
+            }
+        }
+
+        let len = self.len();
+        let mut retain = Retain {
+            stack: self,
+            index: 0,
+            kept: 0,
+        };
+        while retain.index < len {
+            let entry @ (offs, vtable) = retain.stack.offs_table[retain.index];
+            let item = unsafe { fatptr::recomp::<T>(retain.stack.dyn_data.add(offs), vtable) };
+            if f(unsafe { &mut *item }) {
+                retain.stack.offs_table[retain.kept] = entry;
+                retain.stack.type_ids.move_down(retain.index, retain.kept);
+                retain.kept += 1;
+                retain.index += 1;
+            } else {
+                // Count the item as removed before dropping it, in case its destructor panics.
+                retain.index += 1;
+                unsafe { ptr::drop_in_place(item) };
+            }
+        }
+    }
+
+    /// Defragment the buffer, packing the trait objects next to each other in stack order with as
+    /// little alignment padding as possible.
+    ///
+    /// Removing items from the middle of the stack with [`swap_remove`](DynStack::swap_remove)
+    /// can leave unused bytes behind, and reordering the stack moves items out of byte order.
+    /// Compacting releases those bytes, so that `byte_len` only counts the items and the padding
+    /// between them. If the items are out of order, they are copied into a new buffer.
+    pub fn compact(&mut self) {
+        if self.offs_sorted {
+            self.pack();
+            return;
+        }
+
+        // The new buffer is aligned to `max_align`, so offsets from its start align the items.
+        let mut end = 0;
+        let mut moves = Vec::with_capacity(self.len());
+        for &entry in &self.offs_table {
+            let layout = self.entry_layout(entry);
+            let new_offs = align_up(end, layout.align());
+            moves.push((new_offs, layout.size()));
+            end = new_offs + layout.size();
+        }
+
+        let new_cap = end.max(self.min_cap).next_power_of_two().max(self.dyn_cap);
+        let new_layout = Self::make_layout(new_cap, self.max_align);
+        let new_data = unwrap_alloc(self.allocator.allocate(new_layout).map_err(|_| new_layout));
+        let (old_data, new_data) = (self.dyn_data, new_data.as_ptr());
+        unsafe {
+            if self.zero_padding {
+                ptr::write_bytes(new_data, 0, end);
+            }
+            for (entry, &(new_offs, size)) in self.offs_table.iter_mut().zip(&moves) {
+                ptr::copy_nonoverlapping(old_data.add(entry.0), new_data.add(new_offs), size);
+                entry.0 = new_offs;
+            }
+            self.allocator
+                .deallocate(NonNull::new_unchecked(self.dyn_data), self.layout());
+        }
+        self.dyn_data = new_data;
+        self.dyn_cap = new_cap;
+        self.buf_align = self.max_align;
+        self.dyn_size = end;
+        self.offs_sorted = true;
+        self.relocated();
+    }
+
+    /// Move all items as far down in the buffer as their alignment allows, closing any gaps.
+    fn pack(&mut self) {
+        if self.offs_sorted {
+            self.pack_in_order(0..self.len());
+        } else if self.offs_table.is_empty() {
+            self.offs_sorted = true;
+            self.dyn_size = 0;
+        } else {
+            // Moving items down in byte order never overwrites an item that hasn't moved yet.
+            let mut order: Vec<usize> = (0..self.len()).collect();
+            order.sort_unstable_by_key(|&index| self.offs_table[index].0);
+            self.pack_in_order(order.into_iter());
+        }
+    }
+
+    /// Pack the items at the given indices, which must be ordered by offset.
+    fn pack_in_order<I: Iterator<Item = usize>>(&mut self, order: I) {
+        let data = self.dyn_data as usize;
+        let mut end = 0;
+        let mut moved = false;
+        for index in order {
+            let offs = self.offs_table[index].0;
+            let layout = self.entry_layout(self.offs_table[index]);
+            let size = layout.size();
+            let new_offs = align_up(data + end, layout.align()) - data;
+            self.zero_bytes(end, new_offs.saturating_sub(end));
+            if new_offs != offs {
+                debug_assert!(size == 0 || new_offs < offs);
+                unsafe { ptr::copy(self.dyn_data.add(offs), self.dyn_data.add(new_offs), size) };
+                self.offs_table[index].0 = new_offs;
+                moved = true;
+            }
+            end = new_offs + size;
+        }
+        self.dyn_size = end;
+        if moved {
+            self.relocated();
+        }
+    }
+
+    /// Shorten the stack to `len` trait objects, removing the rest from the top down.
+    /// Has no effect if the stack already holds `len` or fewer trait objects.
+    pub fn truncate(&mut self, len: usize) {
+        while self.len() > len {
+            self.remove_last();
+        }
+    }
+
+    /// Split the stack in two at the given index, returning a new stack holding the trait objects
+    /// in `[at, len)` while `self` keeps those in `[0, at)`.
+    ///
+    /// The split-off trait objects are moved into a new buffer, which uses a clone of this stack's
+    /// allocator.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `at > len`.
+    pub fn split_off(&mut self, at: usize) -> Self
+    where
+        A: Clone,
+    {
+        assert!(at <= self.len(), "split index out of bounds");
+        let mut other = self.empty_clone();
+        for index in at..self.len() {
+            unsafe { other.push_raw_owned(self.get_unchecked_mut(index)) };
+        }
+        other.type_ids = self.type_ids.split_off(at);
+        other.needs_drop = self.needs_drop;
+
+        if at == self.len() {
+            return other;
+        }
+        self.offs_table.truncate(at);
+        if at == 0 {
+            self.offs_sorted = true;
+            self.dyn_size = 0;
+        } else if self.offs_sorted {
+            self.dyn_size = self.end_before(at);
+        } else {
+            self.pack();
+        }
+        // The buffer keeps its alignment, which is at least `base_align`, and any over-alignment
+        // requested with `push_with_align`.
+        self.max_align = self
+            .offs_table
+            .iter()
+            .map(|&entry| self.entry_layout(entry).align())
+            .fold(self.buf_align.max(self.base_align), usize::max);
+        other
+    }
+
+    /// Creates a new, empty, stack using a clone of this stack's allocator, with the same buffer
+    /// parameters as this one.
+    fn empty_clone(&self) -> Self
+    where
+        A: Clone,
+    {
+        let mut other = Self::new_in(self.allocator.clone());
+        other.base_align = self.base_align;
+        other.max_align = self.base_align;
+        other.buf_align = self.base_align;
+        other.min_cap = self.min_cap;
+        other.growth_factor = self.growth_factor;
+        other.zero_padding = self.zero_padding;
+        other
+    }
+
+    /// Returns the layout of the item an offset table entry describes.
+    ///
+    /// This is all that code moving items around needs to know about them. Only the vtable is
+    /// read, so it also works for items that have already been dropped.
+    fn entry_layout(&self, (offs, vtable): (usize, usize)) -> Layout {
+        let item = unsafe { &*fatptr::recomp::<T>(self.dyn_data.add(offs), vtable) };
+        Layout::for_value(item)
+    }
+
+    /// Check the invariants relating the offset table to the buffer.
+    #[cfg(test)]
+    fn assert_invariants(&self) {
+        assert!(self.dyn_size <= self.dyn_cap);
+        assert!(self.buf_align >= self.base_align && self.buf_align <= self.max_align);
+        assert_eq!(self.dyn_data as usize % self.buf_align, 0);
+
+        let mut items: Vec<(usize, usize)> = Vec::new();
+        for &entry in &self.offs_table {
+            let layout = self.entry_layout(entry);
+            let end = entry.0 + layout.size();
+            assert_eq!((self.dyn_data as usize + entry.0) % layout.align(), 0);
+            assert!(layout.align() <= self.max_align);
+            items.push((entry.0, end));
+        }
+        // The buffer is used up to the end of the last item in it.
+        let max_end = items.iter().map(|&(_, end)| end).max().unwrap_or(0);
+        assert_eq!(self.dyn_size, max_end);
+        // Items never overlap, and a sorted table lists them in byte order.
+        if !self.offs_sorted {
+            items.sort_unstable();
+        }
+        assert!(items.windows(2).all(|pair| pair[0].1 <= pair[1].0));
+        #[cfg(feature = "type-ids")]
+        assert_eq!(self.type_ids.len(), self.offs_table.len());
+    }
+
+    /// Pop the last offset table entry, releasing its bytes if it was the last item in the buffer.
+    fn pop_entry(&mut self) -> Option<(usize, usize)> {
+        let entry = self.offs_table.pop()?;
+        self.type_ids.pop();
+        let size = self.entry_layout(entry).size();
+        if self.offs_sorted {
+            self.dyn_size = self.end_before(self.len());
+        } else if entry.0 + size < self.dyn_size {
+            // Another item ends at `dyn_size`, so the item's bytes stay below it.
+            self.zero_bytes(entry.0, size);
+        } else {
+            self.trim_to_items();
+        }
+        Some(entry)
+    }
+
+    /// Lower `dyn_size` to the end of the last item in the buffer, after the one that ended there
+    /// was removed from an unsorted offset table, and check whether the table is sorted again.
+    fn trim_to_items(&mut self) {
+        let mut end = 0;
+        let mut prev_offs = 0;
+        let mut sorted = true;
+        for &entry in &self.offs_table {
+            end = end.max(entry.0 + self.entry_layout(entry).size());
+            sorted &= prev_offs <= entry.0;
+            prev_offs = entry.0;
+        }
+        self.dyn_size = end;
+        self.offs_sorted = sorted;
+    }
+
+    /// Swap the positions of two trait objects in the stack.
+    ///
+    /// Only the offset table entries are swapped, the items' bytes stay where they are.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `a` or `b` are out of bounds.
+    pub fn swap(&mut self, a: usize, b: usize) {
+        self.offs_table.swap(a, b);
+        self.type_ids.swap(a, b);
+        if a != b {
+            self.offs_sorted = false;
+        }
+    }
+
+    /// Rotate the trait objects in place, so that the first `n` move to the end of the stack.
+    ///
+    /// Like [`swap`](DynStack::swap), this only reorders the offset table, the items' bytes stay
+    /// where they are.
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_extend};
+    /// # use std::fmt::Debug;
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// dyn_extend!(stack, 0..5u8);
+    /// stack.rotate_left(2);
+    /// assert_eq!(format!("{:?}", stack), "[2, 3, 4, 0, 1]");
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n > len`.
+    pub fn rotate_left(&mut self, n: usize) {
+        self.offs_table.rotate_left(n);
+        self.type_ids.rotate_left(n);
+        if n != 0 && n != self.len() {
+            self.offs_sorted = false;
+        }
+    }
+
+    /// Rotate the trait objects in place, so that the last `n` move to the start of the stack.
+    /// See [`rotate_left`](DynStack::rotate_left).
+    ///
+    /// # Panics
+    ///
+    /// Panics if `n > len`.
+    pub fn rotate_right(&mut self, n: usize) {
+        assert!(n <= self.len(), "rotation amount out of bounds");
+        self.rotate_left(self.len() - n);
+    }
+
+    /// Sort the trait objects with a comparator function, preserving the order of equal items.
+    ///
+    /// Like [`swap`](DynStack::swap), this only reorders the offset table, the items' bytes stay
+    /// where they are.
+    pub fn sort_by<F: FnMut(&T, &T) -> cmp::Ordering>(&mut self, mut compare: F) {
+        let mut order: Vec<usize> = (0..self.len()).collect();
+        order.sort_by(|&a, &b| compare(&self[a], &self[b]));
+        if order.iter().enumerate().all(|(i, &index)| i == index) {
+            return;
+        }
+        self.offs_table = order.iter().map(|&index| self.offs_table[index]).collect();
+        self.type_ids.permute(&order);
+        self.offs_sorted = false;
+    }
+
+    /// Sort the trait objects by a key extracted from each of them, preserving the order of items
+    /// with equal keys. See [`sort_by`](DynStack::sort_by).
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push};
+    /// # use std::fmt::Debug;
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// dyn_push!(stack, "three");
+    /// dyn_push!(stack, 1u8);
+    /// dyn_push!(stack, [2u16; 2]);
+    /// stack.sort_by_key(|item| format!("{:?}", item).len());
+    /// assert_eq!(format!("{:?}", stack), r#"[1, [2, 2], "three"]"#);
+    /// ```
+    pub fn sort_by_key<K: Ord, F: FnMut(&T) -> K>(&mut self, mut f: F) {
+        self.sort_by(|a, b| f(a).cmp(&f(b)));
+    }
+
+    /// Replace the trait object at the provided index with `item`, dropping the old one.
+    ///
+    /// This only works if `item` has the same size and alignment as the item it replaces, so that
+    /// its bytes can be copied into the old item's place. Otherwise, or if `index` is out of
+    /// bounds, an error is returned and the stack is unchanged. Use
+    /// [`dyn_replace!`](crate::dyn_replace) rather than calling this directly.
+    ///
+    /// The old item is dropped after `item` takes its place in the offset table, so even if its
+    /// destructor panics, the stack holds `item` at `index` afterwards.
+    ///
+    /// # Safety
+    ///
+    /// `item` must point to a valid `T`. If `Ok` is returned, ownership of the pointee moved into
+    /// the stack, so the caller must not drop it afterwards. If an error is returned, the caller
+    /// keeps ownership. See [`push_raw_owned`](DynStack::push_raw_owned) for the full contract.
+    pub unsafe fn replace(&mut self, index: usize, item: *mut T) -> Result<(), ReplaceError> {
+        let &(offs, old_vtable) = self
+            .offs_table
+            .get(index)
+            .ok_or(ReplaceError::OutOfBounds)?;
+        if self.entry_layout((offs, old_vtable)) != Layout::for_value(&*item) {
+            return Err(ReplaceError::LayoutMismatch);
+        }
+
+        // Copies the new item's bytes over the old one's once it's dropped, or if dropping it
+        // panics.
+        struct CopyOnDrop {
+            src: *const u8,
+            dst: *mut u8,
+            size: usize,
+        }
+        impl Drop for CopyOnDrop {
+            fn drop(&mut self) {
+                unsafe { ptr::copy_nonoverlapping(self.src, self.dst, self.size) };
+            }
+        }
+
+        let old: *mut T = fatptr::recomp(self.dyn_data.add(offs), old_vtable);
+        self.offs_table[index].1 = fatptr::decomp(item)[1];
+        self.type_ids.unset(index);
+        self.needs_drop = true;
+        let _copy = CopyOnDrop {
+            src: item as *const u8,
+            dst: self.dyn_data.add(offs),
+            size: mem::size_of_val(&*item),
+        };
+        ptr::drop_in_place(old);
+        Ok(())
+    }
+
+    /// Replace the trait object at the provided index, for `dyn_replace!`.
+    #[doc(hidden)]
+    #[inline]
+    pub unsafe fn __dyn_replace(
+        &mut self,
+        index: usize,
+        item: *mut T,
+        needs_drop: bool,
+    ) -> Result<(), ReplaceError> {
+        let needs_drop = self.needs_drop || needs_drop;
+        self.replace(index, item)?;
+        self.needs_drop = needs_drop;
+        Ok(())
+    }
+
+    /// Remove the trait object at the provided index, moving the items after it down by one
+    /// position. Returns true if any items were removed.
+    ///
+    /// Like `Vec::remove`, this preserves the order of the stack's items, and takes linear time:
+    /// the items are moved down in the buffer to fill the removed item's bytes, as far as their
+    /// alignment allows.
+    ///
+    /// If the item's destructor panics, the item is still removed, and the panic is propagated
+    /// after the remaining items are moved.
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push};
+    /// # use std::fmt::Debug;
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// dyn_push!(stack, 1u8);
+    /// dyn_push!(stack, "two");
+    /// dyn_push!(stack, 3u32);
+    /// assert!(stack.remove(1));
+    /// assert!(!stack.remove(2));
+    /// assert_eq!(format!("{:?}", stack), "[1, 3]");
+    /// ```
+    pub fn remove(&mut self, index: usize) -> bool {
+        if index >= self.len() {
+            return false;
+        }
+        struct PackOnDrop<'a, T: ?Sized, A: Allocator>(&'a mut DynStack<T, A>);
+        impl<T: ?Sized, A: Allocator> Drop for PackOnDrop<'_, T, A> {
+            fn drop(&mut self) {
+                self.0.pack();
+            }
+        }
+
+        let (offs, vtable) = self.offs_table.remove(index);
+        self.type_ids.remove(index);
+        let stack = PackOnDrop(self);
+        unsafe { ptr::drop_in_place(fatptr::recomp::<T>(stack.0.dyn_data.add(offs), vtable)) };
+        true
+    }
+
+    /// Remove the trait object at the provided index, replacing it with the last trait object.
+    /// Returns true if any items were removed.
+    ///
+    /// Like `Vec::swap_remove`, this does not preserve the order of the stack's items. The last
+    /// item is moved into the removed item's bytes if it fits there. Otherwise, it stays in place
+    /// and the removed item's bytes are left unused.
+    ///
+    /// If the item's destructor panics, the item is still removed.
+    pub fn swap_remove(&mut self, index: usize) -> bool {
+        let last = match self.len().checked_sub(1) {
+            Some(last) if index <= last => last,
+            _ => return false,
+        };
+        if index == last {
+            return self.remove_last();
+        }
+
+        struct FillOnDrop<'a, T: ?Sized, A: Allocator>(&'a mut DynStack<T, A>, usize);
+        impl<T: ?Sized, A: Allocator> Drop for FillOnDrop<'_, T, A> {
+            fn drop(&mut self) {
+                self.0.fill_removed(self.1);
+            }
+        }
+
+        let stack = FillOnDrop(self, index);
+        unsafe { ptr::drop_in_place(stack.0.get_unchecked_mut(index)) };
+        true
+    }
+
+    /// Move the last item into the place of the item at `index`, which has been dropped and isn't
+    /// the last one, for `swap_remove`.
+    fn fill_removed(&mut self, index: usize) {
+        let last = self.len() - 1;
+        // The last item takes the removed one's place in either case below.
+        self.type_ids.swap(index, last);
+
+        let (last_offs, last_vtable) = self.offs_table[last];
+        if self.offs_sorted {
+            let layout = self.entry_layout((last_offs, last_vtable));
+            let (size, align) = (layout.size(), layout.align());
+            let hole_start = self.offs_table[index].0;
+            let hole_end = self.offs_table[index + 1].0;
+            let new_ptr = align_up(self.dyn_data as usize + hole_start, align);
+            let new_offs = new_ptr - self.dyn_data as usize;
+
+            if new_offs + size <= hole_end {
+                self.zero_bytes(hole_start, hole_end - hole_start);
+                unsafe {
+                    let src = self.dyn_data.add(last_offs);
+                    ptr::copy(src, self.dyn_data.add(new_offs), size);
+                }
+                self.offs_table[index] = (new_offs, last_vtable);
+                self.pop_entry();
+                self.relocated();
+                return;
+            }
+        }
+
+        // The last item doesn't fit in the hole, so only its offset table entry moves.
+        let removed = self.offs_table[index];
+        let removed_size = self.entry_layout(removed).size();
+        self.zero_bytes(removed.0, removed_size);
+        self.offs_table[index] = (last_offs, last_vtable);
+        self.offs_table.pop();
+        self.type_ids.pop();
+        self.offs_sorted = false;
+        if removed.0 + removed_size == self.dyn_size {
+            self.trim_to_items();
+        }
+    }
+
+    /// Remove all trait objects from the stack, dropping them in the same order as repeated calls
+    /// to `remove_last` would.
+    ///
+    /// The allocated buffer is kept, so subsequent pushes can reuse it.
+    ///
+    /// If an item's destructor panics, the panic is propagated after removing that item, and the
+    /// items below it are kept.
+    pub fn clear(&mut self) {
+        if self.needs_drop {
+            while self.remove_last() {}
+        } else {
+            // None of the items have destructors, so there is nothing to do but forget them.
+            self.offs_table.clear();
+            self.type_ids.truncate(0);
+            self.offs_sorted = true;
+        }
+        self.dyn_size = 0;
+        self.needs_drop = false;
+    }
+
+    /// Remove all trait objects from the stack, from the top down, calling `f` on each one just
+    /// before it is dropped.
+    ///
+    /// If `f` returns `false`, no more items are passed to it, and the remaining ones are dropped
+    /// as by [`clear`](DynStack::clear). The stack is empty afterwards, even if `f` panics.
+    ///
+    /// This takes a callback rather than returning an iterator, since each item is dropped right
+    /// after `f` sees it, and so must not be reachable once `f` returns.
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push};
+    /// # use std::fmt::Debug;
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// dyn_push!(stack, 1u8);
+    /// dyn_push!(stack, "two");
+    /// dyn_push!(stack, 3.0f32);
+    ///
+    /// let mut seen = Vec::new();
+    /// stack.drain_with(|item| {
+    ///     seen.push(format!("{:?}", item));
+    ///     seen.len() < 2
+    /// });
+    /// assert_eq!(seen, ["3.0", "\"two\""]);
+    /// assert!(stack.is_empty());
+    /// ```
+    pub fn drain_with<F: FnMut(&mut T) -> bool>(&mut self, mut f: F) {
+        struct ClearOnDrop<'a, T: ?Sized, A: Allocator>(&'a mut DynStack<T, A>);
+        impl<T: ?Sized, A: Allocator> Drop for ClearOnDrop<'_, T, A> {
+            fn drop(&mut self) {
+                self.0.clear();
+            }
+        }
+
+        let stack = ClearOnDrop(self);
+        while let Some(true) = stack.0.pop_with(&mut f) {}
+    }
+
+    /// Record the stack's current length, so that the items pushed after this call can be
+    /// removed with [`rollback`](DynStack::rollback).
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push};
+    /// # use std::fmt::Debug;
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// dyn_push!(stack, 1u8);
+    /// let checkpoint = stack.checkpoint();
+    /// dyn_push!(stack, "temporary");
+    /// dyn_push!(stack, 2.5f32);
+    /// stack.rollback(checkpoint);
+    /// assert_eq!(format!("{:?}", stack), "[1]");
+    /// ```
+    pub fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            stack_id: self.id(),
+            len: self.len(),
+        }
+    }
+
+    /// Remove all trait objects pushed since `checkpoint` was taken, from the top down.
+    ///
+    /// Checkpoints can be nested, and rolling back to one makes the checkpoints taken after it
+    /// unusable until the stack grows past them again.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `checkpoint` was taken on a different stack, or if the stack has been shortened
+    /// below the checkpoint's length since it was taken.
+    pub fn rollback(&mut self, checkpoint: Checkpoint) {
+        assert_eq!(
+            checkpoint.stack_id,
+            self.id(),
+            "checkpoint belongs to a different stack"
+        );
+        assert!(
+            checkpoint.len <= self.len(),
+            "stack was shortened past the checkpoint"
+        );
+        self.truncate(checkpoint.len);
+    }
+
+    /// Returns the stack's unique id, assigning one if it doesn't have one yet.
+    fn id(&self) -> usize {
+        let id = self.id.load(Ordering::Relaxed);
+        if id != 0 {
+            return id;
+        }
+        let new_id = NEXT_STACK_ID.fetch_add(1, Ordering::Relaxed);
+        match self
+            .id
+            .compare_exchange(0, new_id, Ordering::Relaxed, Ordering::Relaxed)
+        {
+            Ok(_) => new_id,
+            Err(id) => id,
+        }
+    }
+
+    /// mem::forget the last trait object from the stack.
+    /// Returns true if any items were forgotten.
+    pub fn forget_last(&mut self) -> bool {
+        self.pop_entry().is_some()
+    }
+
+    /// Retrieve a trait object reference at the provided index.
+    pub fn get(&self, index: usize) -> Option<&T> {
+        let item = self.offs_table.get(index)?;
+        let out = unsafe { &*fatptr::recomp(self.dyn_data.add(item.0), item.1) };
+        Some(out)
+    }
+
+    /// Retrieve a mutable trait object reference at the provided index.
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        let item = self.offs_table.get(index)?;
+        let out = unsafe { &mut *fatptr::recomp(self.dyn_data.add(item.0), item.1) };
+        Some(out)
+    }
+
+    /// Retrieve a trait object reference at the provided index, without bounds checking.
+    ///
+    /// # Safety
+    ///
+    /// `index` must be less than `self.len()`.
+    #[inline]
+    pub unsafe fn get_unchecked(&self, index: usize) -> &T {
+        let item = self.offs_table.get_unchecked(index);
+        &*fatptr::recomp(self.dyn_data.add(item.0), item.1)
+    }
+
+    /// Retrieve a mutable trait object reference at the provided index, without bounds checking.
+    ///
+    /// # Safety
+    ///
+    /// `index` must be less than `self.len()`.
+    #[inline]
+    pub unsafe fn get_unchecked_mut(&mut self, index: usize) -> &mut T {
+        let item = self.offs_table.get_unchecked(index);
+        &mut *fatptr::recomp(self.dyn_data.add(item.0), item.1)
+    }
+
+    /// Retrieve mutable trait object references at several indices at once.
+    ///
+    /// Returns `None` if any index is out of bounds, or if an index appears more than once.
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push};
+    /// # use std::fmt::Write;
+    /// let mut stack = DynStack::<dyn Write>::new();
+    /// dyn_push!(stack, String::new());
+    /// dyn_push!(stack, String::new());
+    /// let [first, second] = stack.get_many_mut([0, 1]).unwrap();
+    /// write!(first, "one").unwrap();
+    /// write!(second, "two").unwrap();
+    /// assert!(stack.get_many_mut([1, 1]).is_none());
+    /// ```
+    pub fn get_many_mut<const N: usize>(&mut self, indices: [usize; N]) -> Option<[&mut T; N]> {
+        for (i, &index) in indices.iter().enumerate() {
+            if index >= self.len() || indices[..i].contains(&index) {
+                return None;
+            }
+        }
+        // Distinct items never share bytes, so the references don't alias.
+        let stack: *mut Self = self;
+        Some(core::array::from_fn(|i| unsafe {
+            (*stack).get_unchecked_mut(indices[i])
+        }))
+    }
+
+    /// Retrieve mutable trait object references at two different indices.
+    ///
+    /// Returns `None` if either index is out of bounds, or if they are equal. See
+    /// [`get_many_mut`](DynStack::get_many_mut) for more than two items.
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push};
+    /// # use std::fmt::Write;
+    /// let mut stack = DynStack::<dyn Write>::new();
+    /// dyn_push!(stack, String::new());
+    /// dyn_push!(stack, String::new());
+    /// let (first, second) = stack.get_pair_mut(0, 1).unwrap();
+    /// write!(first, "one").unwrap();
+    /// write!(second, "two").unwrap();
+    /// assert!(stack.get_pair_mut(1, 1).is_none());
+    /// ```
+    pub fn get_pair_mut(&mut self, i: usize, j: usize) -> Option<(&mut T, &mut T)> {
+        let [a, b] = self.get_many_mut([i, j])?;
+        Some((a, b))
+    }
+
+    /// Retrieve the trait object reference at the top of the stack.
+    pub fn peek(&self) -> Option<&T> {
+        let index = self.len().checked_sub(1)?;
+        self.get(index)
+    }
+
+    /// Retrieve the mutable trait object reference at the top of the stack.
+    pub fn peek_mut(&mut self) -> Option<&mut T> {
+        let index = self.len().checked_sub(1)?;
+        self.get_mut(index)
+    }
+
+    /// Returns `true` if the stack holds an item equal to `item`.
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push};
+    /// trait Id {
+    ///     fn id(&self) -> u32;
+    /// }
+    /// impl Id for u32 {
+    ///     fn id(&self) -> u32 {
+    ///         *self
+    ///     }
+    /// }
+    /// impl PartialEq for dyn Id {
+    ///     fn eq(&self, other: &Self) -> bool {
+    ///         self.id() == other.id()
+    ///     }
+    /// }
+    ///
+    /// let mut stack = DynStack::<dyn Id>::new();
+    /// dyn_push!(stack, 1u32);
+    /// dyn_push!(stack, 2u32);
+    /// assert!(stack.contains(&2u32));
+    /// assert!(!stack.contains(&3u32));
+    /// ```
+    pub fn contains(&self, item: &T) -> bool
+    where
+        T: PartialEq,
+    {
+        self.iter().any(|x| x == item)
+    }
+
+    /// Returns the number of buffer bytes taken up by the trait object at the provided index, or
+    /// `None` if the index is out of bounds.
+    ///
+    /// This is the item's slot size: its own size plus any padding after it, up to the next item
+    /// in the buffer or the end of the used bytes. For just the item's own size, see
+    /// [`element_payload_size`](DynStack::element_payload_size).
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push};
+    /// # use std::fmt::Debug;
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// dyn_push!(stack, 1u8);
+    /// dyn_push!(stack, 2u32);
+    /// assert_eq!(stack.element_size(0), Some(4));
+    /// assert_eq!(stack.element_payload_size(0), Some(1));
+    /// assert_eq!(stack.element_size(1), Some(4));
+    /// assert_eq!(stack.element_size(2), None);
+    /// ```
+    pub fn element_size(&self, index: usize) -> Option<usize> {
+        let entry = *self.offs_table.get(index)?;
+        let next = if self.offs_sorted {
+            self.offs_table
+                .get(index + 1)
+                .map_or(self.dyn_size, |&(offs, _)| offs)
+        } else {
+            let end = entry.0 + self.entry_layout(entry).size();
+            self.offs_table
+                .iter()
+                .enumerate()
+                .filter(|&(other, &(offs, _))| other != index && offs >= end)
+                .map(|(_, &(offs, _))| offs)
+                .min()
+                .unwrap_or(self.dyn_size)
+        };
+        Some(next - entry.0)
+    }
+
+    /// Returns the size of the trait object at the provided index, as `size_of_val` would, or
+    /// `None` if the index is out of bounds.
+    pub fn element_payload_size(&self, index: usize) -> Option<usize> {
+        self.get(index).map(|item| mem::size_of_val(item))
+    }
+
+    /// Returns the number of trait objects stored on the stack.
+    pub fn len(&self) -> usize {
+        self.offs_table.len()
+    }
+
+    /// Summarize how the stack's buffer is used, to help decide whether calling
+    /// [`compact`](DynStack::compact) or [`shrink_to_fit`](DynStack::shrink_to_fit) is worth it.
+    ///
+    /// This walks over all items, so it takes linear time.
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push};
+    /// # use std::fmt::Debug;
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// dyn_push!(stack, 1u8);
+    /// dyn_push!(stack, 2u64);
+    /// let report = stack.memory_report();
+    /// assert_eq!(report.payload_bytes, 9);
+    /// assert_eq!(report.padding_bytes, 7);
+    /// ```
+    pub fn memory_report(&self) -> MemoryReport {
+        let payload_bytes = self
+            .offs_table
+            .iter()
+            .map(|&entry| self.entry_layout(entry).size())
+            .sum();
+        MemoryReport {
+            capacity: self.dyn_cap,
+            used_bytes: self.dyn_size,
+            payload_bytes,
+            padding_bytes: self.dyn_size - payload_bytes,
+            len: self.len(),
+            max_align: self.max_align,
+        }
+    }
+
+    /// Returns the strictest alignment required by any item pushed so far, or the base alignment
+    /// if no item required more. The base alignment is 16, unless set through
+    /// [`DynStackBuilder::base_align`].
+    ///
+    /// New buffers are allocated with this alignment. If an item with a stricter alignment than
+    /// the current buffer's is pushed, the next reallocation may have to move the buffer's contents
+    /// to keep the items aligned, so `reserve` adds up to `max_alignment()` minus the base
+    /// alignment bytes of room for that.
+    #[inline]
+    pub fn max_alignment(&self) -> usize {
+        self.max_align
+    }
+
+    /// Returns the number of bytes the stack can hold without reallocating.
+    ///
+    /// This is a byte budget for items and the padding between them. See
+    /// [`reserve`](DynStack::reserve).
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.dyn_cap
+    }
+
+    /// Returns the number of bytes that can still be pushed without reallocating.
+    ///
+    /// Alignment padding is taken from these bytes too, so this is only a lower bound on what an
+    /// item will consume: pushing an item of type `U` is guaranteed not to reallocate if
+    /// `size_of::<U>() + align_of::<U>() - 1` bytes remain, and may or may not if only
+    /// `size_of::<U>()` bytes do.
+    #[inline]
+    pub fn capacity_remaining(&self) -> usize {
+        self.dyn_cap - self.dyn_size
+    }
+
+    /// Returns the number of bytes currently used by the stored trait objects.
+    ///
+    /// This includes the padding inserted between elements to keep each of them aligned, so it
+    /// will generally be larger than the sum of `size_of_val` over all elements.
+    #[inline]
+    pub fn byte_len(&self) -> usize {
+        self.dyn_size
+    }
+
+    /// Returns a pointer to the start of the stack's buffer.
+    ///
+    /// Item offsets from [`offsets`](DynStack::offsets) are relative to this pointer. It is null
+    /// if nothing has been allocated yet, and is invalidated whenever the buffer is reallocated.
+    #[inline]
+    pub fn as_ptr(&self) -> *const u8 {
+        self.dyn_data
+    }
+
+    /// Returns a mutable pointer to the start of the stack's buffer.
+    ///
+    /// See [`as_ptr`](DynStack::as_ptr).
+    #[inline]
+    pub fn as_mut_ptr(&mut self) -> *mut u8 {
+        self.dyn_data
+    }
+
+    /// Returns the used part of the stack's buffer, the first [`byte_len`](DynStack::byte_len)
+    /// bytes, as a slice.
+    ///
+    /// Together with the [`offsets`](DynStack::offsets), these bytes describe the whole stack, so
+    /// they can be copied aside and written back later to restore the stack's items. The offset
+    /// table holds vtable addresses, which are only meaningful within the running process, so a
+    /// snapshot can't be restored by another process unless `T` is a slice or `str`, whose
+    /// metadata is a length.
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push};
+    /// # use std::fmt::Debug;
+    /// let mut stack = DynStack::<dyn Debug>::new_zeroed();
+    /// dyn_push!(stack, 1u32);
+    /// dyn_push!(stack, [2u16; 2]);
+    /// let snapshot = unsafe { stack.as_bytes() }.to_vec();
+    /// assert_eq!(snapshot.len(), 8);
+    /// ```
+    ///
+    /// # Safety
+    ///
+    /// Every byte in the range must be initialized. The bytes that pad items to their alignment,
+    /// or that removed items leave behind, are only guaranteed to be if the stack was created with
+    /// [`new_zeroed`](DynStack::new_zeroed). The items must not contain uninitialized bytes
+    /// either, like the padding inside a struct, so this is meant for stacks of plain data.
+    pub unsafe fn as_bytes(&self) -> &[u8] {
+        if self.dyn_data.is_null() {
+            return &[];
+        }
+        slice::from_raw_parts(self.dyn_data, self.dyn_size)
+    }
+
+    /// Returns the stack's offset table, with one `(offset, vtable)` entry per item.
+    ///
+    /// `offset` is the item's byte offset from [`as_ptr`](DynStack::as_ptr), and `vtable` is the
+    /// address of the item's vtable: the metadata half of a `*const T`. Entries are in stack
+    /// order, but their offsets are not necessarily increasing.
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push};
+    /// # use std::fmt::Debug;
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// dyn_push!(stack, 1u8);
+    /// dyn_push!(stack, 2u32);
+    /// let item_ptr = &stack[1] as *const dyn Debug as *const u8;
+    /// assert_eq!(stack.as_ptr().wrapping_add(stack.offsets()[1].0), item_ptr);
+    /// ```
+    #[inline]
+    pub fn offsets(&self) -> &[(usize, usize)] {
+        &self.offs_table
+    }
+
+    /// Set the number of items and the number of used bytes of the stack, without dropping or
+    /// moving anything.
+    ///
+    /// This is the low-level counterpart of [`byte_len`](DynStack::byte_len), for code that
+    /// writes to the buffer through [`as_mut_ptr`](DynStack::as_mut_ptr). Items past `new_len`
+    /// are forgotten, like with [`forget_last`](DynStack::forget_last), and the bytes past
+    /// `new_byte_size` are free for the next push. The offset table only holds the entries of
+    /// pushed items, so it can't grow this way: items are added with
+    /// [`push_raw_owned`](DynStack::push_raw_owned) or
+    /// [`from_raw_parts`](DynStack::from_raw_parts).
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push};
+    /// # use std::fmt::Debug;
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// dyn_push!(stack, 1u32);
+    /// dyn_push!(stack, 2u32);
+    /// unsafe {
+    ///     stack.as_mut_ptr().cast::<u32>().write(3);
+    ///     stack.set_len(1, 4);
+    /// }
+    /// assert_eq!(format!("{:?}", stack), "[3]");
+    /// ```
+    ///
+    /// # Safety
+    ///
+    /// - `new_len` must be at most [`len`](DynStack::len).
+    /// - `new_byte_size` must be at most [`capacity`](DynStack::capacity), and the first
+    ///   `new_len` items must lie within the first `new_byte_size` bytes.
+    /// - Those items must be initialized, valid `T`s, including any bytes written to them through
+    ///   the buffer pointer.
+    pub unsafe fn set_len(&mut self, new_len: usize, new_byte_size: usize) {
+        debug_assert!(new_len <= self.len());
+        debug_assert!(new_byte_size <= self.dyn_cap);
+        self.offs_table.truncate(new_len);
+        self.type_ids.truncate(new_len);
+        if self.offs_table.is_empty() {
+            self.offs_sorted = true;
+        }
+        self.dyn_size = new_byte_size;
+    }
+
+    /// Set a callback to be notified whenever items move within or out of the stack's buffer,
+    /// replacing any previous one.
+    ///
+    /// This is for code that keeps raw pointers to the stack's items. The callback is called with
+    /// the buffer pointer, as returned by [`as_mut_ptr`](DynStack::as_mut_ptr), and the offset
+    /// table, as returned by [`offsets`](DynStack::offsets), after every reallocation of the
+    /// buffer, and after any method that moves items within it, like
+    /// [`compact`](DynStack::compact), [`retain`](DynStack::retain) or
+    /// [`swap_remove`](DynStack::swap_remove). Pointers to the items can be recomputed from them.
+    ///
+    /// The callback is not carried over to stacks created from this one, like by
+    /// [`split_off`](DynStack::split_off).
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push};
+    /// # use std::{fmt::Debug, sync::{Arc, Mutex}};
+    /// let first_item = Arc::new(Mutex::new(None));
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// let hook_first_item = first_item.clone();
+    /// stack.set_relocate_hook(move |data, offsets| {
+    ///     *hook_first_item.lock().unwrap() = Some(data.wrapping_add(offsets[0].0) as usize);
+    /// });
+    ///
+    /// dyn_push!(stack, [1u8; 16]);
+    /// dyn_push!(stack, 2u8);
+    /// let expected = &stack[0] as *const dyn Debug as *const u8 as usize;
+    /// assert_eq!(*first_item.lock().unwrap(), Some(expected));
+    /// ```
+    pub fn set_relocate_hook<F>(&mut self, hook: F)
+    where
+        F: FnMut(*mut u8, &[(usize, usize)]) + Send + 'static,
+    {
+        self.relocate_hook = Some(Box::new(hook));
+    }
+
+    /// Remove the callback set by [`set_relocate_hook`](DynStack::set_relocate_hook).
+    pub fn remove_relocate_hook(&mut self) {
+        self.relocate_hook = None;
+    }
+
+    /// Returns `true` if the stack holds no trait objects.
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push};
+    /// # use std::fmt::Debug;
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// assert!(stack.is_empty());
+    /// dyn_push!(stack, 1u8);
+    /// assert!(!stack.is_empty());
+    /// ```
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.offs_table.is_empty()
+    }
+}
+
+impl<'a, T: 'a + ?Sized, A: Allocator> DynStack<T, A> {
+    /// Returns an iterator over trait object references
+    ///
+    /// Items are yielded in stack order: the order they were pushed in, unless the stack was
+    /// reordered with methods like [`swap`](DynStack::swap) or [`sort_by`](DynStack::sort_by).
+    /// Growing or otherwise reallocating the buffer never changes that order, even when the items
+    /// have to be moved to stay aligned.
+    pub fn iter(&'a self) -> DynStackIter<'a, T, A> {
+        self.range(0, self.len())
+    }
+
+    /// Returns an iterator over mutable trait object references
+    pub fn iter_mut(&'a mut self) -> DynStackIterMut<'a, T, A> {
+        let len = self.len();
+        self.range_mut(0, len)
+    }
+
+    /// Returns an iterator over the trait object references at indices `[start, end)`.
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_extend};
+    /// # use std::fmt::Debug;
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// dyn_extend!(stack, 0..10u8);
+    /// let window: Vec<String> = stack.range(3, 6).map(|item| format!("{:?}", item)).collect();
+    /// assert_eq!(window, ["3", "4", "5"]);
+    /// ```
+    ///
+    /// # Panics
+    ///
+    /// Like slicing, panics if `start > end` or `end > len`.
+    pub fn range(&'a self, start: usize, end: usize) -> DynStackIter<'a, T, A> {
+        Self::check_range(start, end, self.len());
+        DynStackIter {
+            stack: self,
+            index: start,
+            end,
+        }
+    }
+
+    /// Returns an iterator over the mutable trait object references at indices `[start, end)`.
+    ///
+    /// # Panics
+    ///
+    /// Like slicing, panics if `start > end` or `end > len`.
+    pub fn range_mut(&'a mut self, start: usize, end: usize) -> DynStackIterMut<'a, T, A> {
+        Self::check_range(start, end, self.len());
+        let data = self.dyn_data;
+        let stack: &'a Self = self;
+        DynStackIterMut {
+            data,
+            entries: stack.offs_table[start..end].iter(),
+            _spooky: PhantomData,
+        }
+    }
+
+    /// Returns an iterator over the top `n` trait object references, from the bottom up, or `None`
+    /// if the stack holds fewer than `n` items.
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_extend};
+    /// # use std::fmt::Debug;
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// dyn_extend!(stack, 0..5u8);
+    /// let top: Vec<String> = stack.peek_n(2).unwrap().map(|item| format!("{:?}", item)).collect();
+    /// assert_eq!(top, ["3", "4"]);
+    /// assert!(stack.peek_n(6).is_none());
+    /// ```
+    pub fn peek_n(&'a self, n: usize) -> Option<DynStackIter<'a, T, A>> {
+        let start = self.len().checked_sub(n)?;
+        Some(self.range(start, self.len()))
+    }
+
+    /// Returns an iterator over the top `n` mutable trait object references, from the bottom up,
+    /// or `None` if the stack holds fewer than `n` items.
+    pub fn peek_n_mut(&'a mut self, n: usize) -> Option<DynStackIterMut<'a, T, A>> {
+        let len = self.len();
+        let start = len.checked_sub(n)?;
+        Some(self.range_mut(start, len))
+    }
+
+    fn check_range(start: usize, end: usize, len: usize) {
+        assert!(
+            start <= end,
+            "range start {} is past its end {}",
+            start,
+            end
+        );
+        assert!(
+            end <= len,
+            "range end {} is out of bounds for length {}",
+            end,
+            len
+        );
+    }
+
+    /// Returns an iterator over trait object references, starting with the most recently pushed
+    /// one.
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push};
+    /// # use std::fmt::Debug;
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// dyn_push!(stack, 1u8);
+    /// dyn_push!(stack, "two");
+    /// let newest = stack.iter_rev().next().unwrap();
+    /// assert_eq!(format!("{:?}", newest), "\"two\"");
+    /// ```
+    pub fn iter_rev(&'a self) -> DynStackIterRev<'a, T, A> {
+        DynStackIterRev {
+            stack: self,
+            remaining: self.len(),
+        }
+    }
+
+    /// Returns an iterator over mutable trait object references, starting with the most recently
+    /// pushed one.
+    pub fn iter_rev_mut(&'a mut self) -> DynStackIterRevMut<'a, T, A> {
+        DynStackIterRevMut {
+            inner: self.iter_mut(),
+        }
+    }
+
+    /// Returns an iterator over trait object references and their indices.
+    ///
+    /// The indices are positions in the stack, as accepted by [`get`](DynStack::get) and
+    /// indexing.
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push};
+    /// # use std::fmt::Debug;
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// dyn_push!(stack, 1u8);
+    /// dyn_push!(stack, "two");
+    /// for (index, item) in stack.iter_indexed() {
+    ///     assert_eq!(format!("{:?}", item), format!("{:?}", &stack[index]));
+    /// }
+    /// ```
+    pub fn iter_indexed(&'a self) -> Enumerate<DynStackIter<'a, T, A>> {
+        self.iter().enumerate()
+    }
+
+    /// Returns an iterator over mutable trait object references and their indices.
+    pub fn iter_indexed_mut(&'a mut self) -> Enumerate<DynStackIterMut<'a, T, A>> {
+        self.iter_mut().enumerate()
+    }
+
+    /// Call `f` on every trait object in order, passing its index and a mutable reference to it.
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push};
+    /// # use std::fmt::Write;
+    /// let mut stack = DynStack::<dyn Write>::new();
+    /// dyn_push!(stack, String::new());
+    /// dyn_push!(stack, String::new());
+    /// stack.for_each_mut(|index, item| write!(item, "item {}", index).unwrap());
+    /// ```
+    pub fn for_each_mut<F: FnMut(usize, &mut T)>(&mut self, mut f: F) {
+        for index in 0..self.len() {
+            f(index, unsafe { self.get_unchecked_mut(index) });
+        }
+    }
+
+    /// Combine every trait object into an accumulator, in stack order, like `Iterator::fold`.
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push};
+    /// # use std::fmt::Debug;
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// dyn_push!(stack, 1u8);
+    /// dyn_push!(stack, "two");
+    /// let joined = stack.fold(String::new(), |acc, item| format!("{}{:?}", acc, item));
+    /// assert_eq!(joined, r#"1"two""#);
+    /// ```
+    pub fn fold<B, F: FnMut(B, &T) -> B>(&self, init: B, mut f: F) -> B {
+        let data = self.dyn_data;
+        self.offs_table.iter().fold(init, |acc, &(offs, vtable)| {
+            f(acc, unsafe { &*fatptr::recomp(data.add(offs), vtable) })
+        })
+    }
+
+    /// Returns the sum of `f` over every trait object.
+    ///
+    /// Like `Iterator::sum`, this panics on overflow if debug assertions are enabled.
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push};
+    /// let mut stack = DynStack::<[u8]>::new();
+    /// dyn_push!(stack, [1u8, 2, 3]);
+    /// dyn_push!(stack, [4u8]);
+    /// assert_eq!(stack.sum_by(|item| item.len()), 4);
+    /// ```
+    pub fn sum_by<F: FnMut(&T) -> usize>(&self, mut f: F) -> usize {
+        self.fold(0, |sum, item| sum + f(item))
+    }
+}
+
+impl<T: ?Sized, A: Allocator + Default> Default for DynStack<T, A> {
+    /// Creates a new, empty, [`DynStack`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if pointers to `T` are neither thin, nor a data pointer and one `usize` of metadata.
+    fn default() -> Self {
+        Self::new_in(A::default())
+    }
+}
+
+/// Collects boxed trait objects, moving each of them onto the stack.
+///
+/// Unsizing coercions can't be expressed as trait bounds on stable Rust, so collecting concrete
+/// values straight into a stack isn't possible. Use [`dyn_extend!`] for those instead.
+///
+/// ```
+/// # use dynstack::DynStack;
+/// # use std::fmt::Debug;
+/// let boxes: Vec<Box<dyn Debug>> = vec![Box::new(1u8), Box::new("two")];
+/// let stack: DynStack<dyn Debug> = boxes.into_iter().collect();
+/// assert_eq!(format!("{:?}", stack), r#"[1, "two"]"#);
+/// ```
+impl<T: ?Sized, A: Allocator + Default> FromIterator<Box<T>> for DynStack<T, A> {
+    fn from_iter<I: IntoIterator<Item = Box<T>>>(iter: I) -> Self {
+        let mut stack = Self::new_in(A::default());
+        stack.extend(iter);
+        stack
+    }
+}
+
+/// Moves each boxed trait object onto the stack, freeing the boxes' allocations.
+/// See [`push_box`](DynStack::push_box).
+impl<T: ?Sized, A: Allocator> Extend<Box<T>> for DynStack<T, A> {
+    fn extend<I: IntoIterator<Item = Box<T>>>(&mut self, iter: I) {
+        let iter = iter.into_iter();
+        self.offs_table.reserve(iter.size_hint().0);
+        for item in iter {
+            self.push_box(item);
+        }
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, A: Allocator> fmt::Debug for DynStack<T, A> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+/// Stacks are equal if they hold the same number of items and each pair of items is equal.
+/// Padding and the items' positions in the buffer are not compared.
+impl<T: ?Sized + PartialEq, A: Allocator, B: Allocator> PartialEq<DynStack<T, B>>
+    for DynStack<T, A>
+{
+    fn eq(&self, other: &DynStack<T, B>) -> bool {
+        self.len() == other.len() && self.iter().zip(other.iter()).all(|(a, b)| a == b)
+    }
+}
+
+impl<T: ?Sized + Eq, A: Allocator> Eq for DynStack<T, A> {}
+
+/// Hashes the length, then each item in order, like a slice. Like `PartialEq`, only the items
+/// themselves are considered, not the bytes between them.
+impl<T: ?Sized + Hash, A: Allocator> Hash for DynStack<T, A> {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.len().hash(state);
+        for item in self {
+            item.hash(state);
+        }
+    }
+}
+
+/// Panics if the index is out of bounds.
+///
+/// With the `unchecked-index` feature, the index is only checked in builds with debug assertions.
+/// Indexing out of bounds in a release build is then undefined behavior, even though it is safe
+/// code. Since Cargo features are shared by every crate in the build, enabling the feature anywhere
+/// makes every `stack[index]` in the build rely on being in bounds, including in dependencies that
+/// didn't ask for it. Prefer [`get_unchecked`](DynStack::get_unchecked) for individual hot paths.
+impl<T: ?Sized, A: Allocator> Index<usize> for DynStack<T, A> {
+    type Output = T;
+
+    #[cfg(not(feature = "unchecked-index"))]
+    fn index(&self, idx: usize) -> &T {
+        self.get(idx).unwrap()
+    }
+
+    #[cfg(feature = "unchecked-index")]
+    #[inline]
+    fn index(&self, idx: usize) -> &T {
+        debug_assert!(
+            idx < self.len(),
+            "index {} out of bounds for length {}",
+            idx,
+            self.len()
+        );
+        unsafe { self.get_unchecked(idx) }
+    }
+}
+
+/// Panics if the index is out of bounds, or with the `unchecked-index` feature, only checks the
+/// index in builds with debug assertions. See the [`Index`] implementation.
+impl<T: ?Sized, A: Allocator> IndexMut<usize> for DynStack<T, A> {
+    #[cfg(not(feature = "unchecked-index"))]
+    fn index_mut(&mut self, idx: usize) -> &mut T {
+        self.get_mut(idx).unwrap()
+    }
+
+    #[cfg(feature = "unchecked-index")]
+    #[inline]
+    fn index_mut(&mut self, idx: usize) -> &mut T {
+        debug_assert!(
+            idx < self.len(),
+            "index {} out of bounds for length {}",
+            idx,
+            self.len()
+        );
+        unsafe { self.get_unchecked_mut(idx) }
+    }
+}
+
+impl<'a, T: 'a + ?Sized, A: Allocator> IntoIterator for &'a DynStack<T, A> {
+    type Item = &'a T;
+    type IntoIter = DynStackIter<'a, T, A>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a, T: 'a + ?Sized, A: Allocator> IntoIterator for &'a mut DynStack<T, A> {
+    type Item = &'a mut T;
+    type IntoIter = DynStackIterMut<'a, T, A>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+/// Moves the trait objects out of the stack, each into its own box, from the bottom up.
+impl<T: ?Sized, A: Allocator> IntoIterator for DynStack<T, A> {
+    type Item = Box<T>;
+    type IntoIter = DynStackIntoIter<T, A>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        DynStackIntoIter {
+            stack: self,
+            index: 0,
+        }
+    }
+}
+
+impl<T: ?Sized, A: Allocator> Drop for DynStack<T, A> {
+    fn drop(&mut self) {
+        // If an item's destructor panics, keep dropping the rest of them while unwinding, and
+        // still free the buffer.
+        struct FreeOnDrop<'a, T: ?Sized, A: Allocator>(&'a mut DynStack<T, A>);
+        impl<T: ?Sized, A: Allocator> Drop for FreeOnDrop<'_, T, A> {
+            fn drop(&mut self) {
+                let stack = &mut *self.0;
+                stack.clear();
+                if let Some(data) = NonNull::new(stack.dyn_data) {
+                    unsafe { stack.allocator.deallocate(data, stack.layout()) }
+                }
+            }
+        }
+
+        let stack = FreeOnDrop(self);
+        stack.0.clear();
+    }
+}
+
+/// Push an item onto the back of the specified stack
+///
+/// The stack must be a [`DynStack`] or a [`SmallDynStack`], and the item a sized value that
+/// coerces to the stack's item type. Anything else is rejected at compile time:
+///
+/// ```compile_fail
+/// # use dynstack::dyn_push;
+/// // Not a stack, even though it has a `push` method.
+/// let mut pointers: Vec<*mut u8> = Vec::new();
+/// dyn_push!(pointers, 1u8);
+/// ```
+///
+/// ```compile_fail
+/// # use dynstack::{DynStack, dyn_push};
+/// # use std::fmt::Debug;
+/// struct NotDebug;
+/// let mut stack = DynStack::<dyn Debug>::new();
+/// dyn_push!(stack, NotDebug);
+/// ```
+///
+/// References are values like any other, and are stored as references rather than copies of
+/// what they point to. The stack's lifetime bounds still apply to them, so they can't dangle: a
+/// `DynStack<dyn Debug>` only holds `'static` items.
+///
+/// ```compile_fail
+/// # use dynstack::{DynStack, dyn_push};
+/// # use std::fmt::Debug;
+/// let mut stack = DynStack::<dyn Debug>::new();
+/// let local = String::from("borrowed");
+/// dyn_push!(stack, &local);
+/// # drop(local);
+/// # drop(stack);
+/// ```
+#[macro_export]
+macro_rules! dyn_push {
+    { $stack:expr, $item:expr } => {{
+        let mut t = core::mem::ManuallyDrop::new($item);
+
+        #[allow(clippy::macro_metavars_in_unsafe)]
+        unsafe { $stack.__dyn_push(&mut *t, $crate::__needs_drop_of(&*t)) };
+    }}
+}
+
+/// Push an item onto the back of the specified stack, evaluating to the item's index.
+///
+/// ```
+/// # use dynstack::{DynStack, dyn_push_indexed};
+/// # use std::fmt::Debug;
+/// let mut stack = DynStack::<dyn Debug>::new();
+/// let first = dyn_push_indexed!(stack, "first");
+/// let second = dyn_push_indexed!(stack, 2u8);
+/// assert_eq!((first, second), (0, 1));
+/// assert_eq!(format!("{:?}", &stack[second]), "2");
+/// ```
+#[macro_export]
+macro_rules! dyn_push_indexed {
+    { $stack:expr, $item:expr } => {{
+        let mut t = core::mem::ManuallyDrop::new($item);
+
+        #[allow(clippy::macro_metavars_in_unsafe)]
+        unsafe { $stack.__dyn_push(&mut *t, $crate::__needs_drop_of(&*t)) }
+    }}
+}
+
+/// Push an item onto the back of the specified stack, evaluating to a mutable reference to the
+/// stored item.
+///
+/// ```
+/// # use dynstack::{DynStack, dyn_push_ref};
+/// # use std::fmt::Write;
+/// let mut stack = DynStack::<dyn Write>::new();
+/// let item = dyn_push_ref!(stack, String::new());
+/// write!(item, "hello").unwrap();
+/// ```
+#[macro_export]
+macro_rules! dyn_push_ref {
+    { $stack:expr, $item:expr } => {{
+        let mut t = core::mem::ManuallyDrop::new($item);
+
+        #[allow(clippy::macro_metavars_in_unsafe)]
+        unsafe { $stack.__dyn_push_mut(&mut *t, $crate::__needs_drop_of(&*t)) }
+    }}
+}
+
+/// Push an item onto the back of the specified stack and record its concrete type, evaluating to
+/// the item's index. Only available with the `type-ids` feature.
+///
+/// Unlike the other pushing macros, this requires the item to be `'static`. The recorded type is
+/// returned by [`DynStack::type_id_at`], and lets [`DynStack::get_as`] downcast the item.
+///
+/// ```
+/// # use dynstack::{DynStack, dyn_push, dyn_push_typed};
+/// # use std::fmt::Debug;
+/// let mut stack = DynStack::<dyn Debug>::new();
+/// let index = dyn_push_typed!(stack, 1u32);
+/// dyn_push!(stack, 2u32);
+/// assert!(stack.is::<u32>(index));
+/// assert!(!stack.is::<u32>(1));
+/// ```
+#[cfg(feature = "type-ids")]
+#[macro_export]
+macro_rules! dyn_push_typed {
+    { $stack:expr, $item:expr } => {{
+        let mut t = core::mem::ManuallyDrop::new($item);
+
+        #[allow(clippy::macro_metavars_in_unsafe)]
+        unsafe {
+            $stack.__dyn_push_typed(
+                &mut *t,
+                $crate::__type_id_of(&*t),
+                $crate::__needs_drop_of(&*t),
+            )
+        }
+    }}
+}
+
+/// Replace the item at the specified index of a stack with a new one of the same size and
+/// alignment, dropping the old item. Evaluates to a `Result<(), ReplaceError>`.
+///
+/// If the item can't replace the old one, the stack is unchanged and the new item is dropped. See
+/// [`DynStack::replace`].
+///
+/// ```
+/// # use dynstack::{DynStack, ReplaceError, dyn_push, dyn_replace};
+/// # use std::fmt::Debug;
+/// let mut stack = DynStack::<dyn Debug>::new();
+/// dyn_push!(stack, 1u32);
+/// dyn_push!(stack, "two");
+/// dyn_replace!(stack, 0, 5i32).unwrap();
+/// assert_eq!(dyn_replace!(stack, 1, 3u8), Err(ReplaceError::LayoutMismatch));
+/// assert_eq!(dyn_replace!(stack, 2, 4u32), Err(ReplaceError::OutOfBounds));
+/// assert_eq!(format!("{:?}", stack), r#"[5, "two"]"#);
+/// ```
+#[macro_export]
+macro_rules! dyn_replace {
+    { $stack:expr, $index:expr, $item:expr } => {{
+        let mut t = core::mem::ManuallyDrop::new($item);
+
+        #[allow(clippy::macro_metavars_in_unsafe)]
+        let result = unsafe { $stack.__dyn_replace($index, &mut *t, $crate::__needs_drop_of(&*t)) };
+        if result.is_err() {
+            unsafe { core::mem::ManuallyDrop::drop(&mut t) };
+        }
+        result
+    }}
+}
+
+/// Push every item of an iterator onto the back of the specified stack.
+///
+/// All of the items have the same type, so enough capacity for the iterator's known length is
+/// reserved up front.
+///
+/// ```
+/// # use dynstack::{DynStack, dyn_extend};
+/// # use std::fmt::Debug;
+/// let mut stack = DynStack::<dyn Debug>::new();
+/// dyn_extend!(stack, 0..3u32);
+/// dyn_extend!(stack, vec!["a", "b"]);
+/// assert_eq!(format!("{:?}", stack), r#"[0, 1, 2, "a", "b"]"#);
+/// ```
+#[macro_export]
+macro_rules! dyn_extend {
+    { $stack:expr, $iter:expr } => {{
+        let iter = core::iter::IntoIterator::into_iter($iter);
+        let stack = $stack.__reserve_for_iter(&iter);
+        for item in iter {
+            $crate::dyn_push!(stack, item);
+        }
+    }}
+}
+
+#[test]
+fn test_push_pop() {
+    use std::fmt::Debug;
+    let mut stack = DynStack::<dyn Debug>::new();
+    let bunch = vec![1u32, 2, 3, 4, 5, 6, 7, 8, 9];
+    dyn_push!(stack, 1u8);
+    dyn_push!(stack, 1u32);
+    dyn_push!(stack, 1u16);
+    dyn_push!(stack, 1u64);
+    dyn_push!(stack, 1u128);
+    dyn_push!(stack, bunch);
+    dyn_push!(stack, {
+        #[derive(Debug)]
+        struct Zst;
+        Zst
+    });
+
+    if let Some(item) = stack.peek() {
+        println!("{:?}", item);
+        assert!(format!("{:?}", item) == "Zst");
+    } else {
+        unreachable!();
+    }
+    assert!(stack.remove_last());
+
+    if let Some(item) = stack.peek() {
+        println!("{:?}", item);
+        assert!(format!("{:?}", item) == "[1, 2, 3, 4, 5, 6, 7, 8, 9]");
+    }
+    assert!(stack.remove_last());
+
+    for _i in 0..5 {
+        if let Some(item) = stack.peek() {
+            println!("{:?}", item);
+            assert!(format!("{:?}", item) == "1");
+        } else {
+            unreachable!();
+        }
+        assert!(stack.remove_last());
+    }
+
+    assert!(stack.is_empty());
+    assert!(stack.dyn_size == 0);
+}
+
+#[test]
+fn test_is_empty() {
+    use std::fmt::Debug;
+    let mut stack = DynStack::<dyn Debug>::new();
+    assert!(stack.is_empty());
+
+    dyn_push!(stack, 1u8);
+    dyn_push!(stack, String::from("two"));
+    assert!(!stack.is_empty());
+
+    assert!(stack.remove_last());
+    assert!(!stack.is_empty());
+    assert!(stack.remove_last());
+    assert!(stack.is_empty());
+}
+
+#[test]
+fn test_with_capacity() {
+    use std::fmt::Debug;
+    let stack = DynStack::<dyn Debug>::with_capacity(0);
+    assert!(stack.dyn_data.is_null());
+    assert_eq!(stack.dyn_cap, 0);
+
+    let stack = DynStack::<dyn Debug>::with_capacity(3);
+    assert_eq!(stack.dyn_cap, 16);
+
+    let mut stack = DynStack::<dyn Debug>::with_capacity(8000);
+    assert_eq!(stack.dyn_cap, 8192);
+    assert_eq!(stack.dyn_size, 0);
+
+    let data = stack.dyn_data;
+    for i in 0..1000u64 {
+        dyn_push!(stack, i);
+    }
+    assert_eq!(stack.dyn_data, data);
+    assert_eq!(stack.dyn_cap, 8192);
+}
+
+#[test]
+fn test_capacity() {
+    use std::fmt::Debug;
+    let mut stack = DynStack::<dyn Debug>::new();
+    assert_eq!(stack.capacity(), 0);
+    assert_eq!(stack.byte_len(), 0);
+
+    dyn_push!(stack, 1u8);
+    assert_eq!(stack.capacity(), 16);
+    assert_eq!(stack.byte_len(), 1);
+
+    // The u64 needs to be aligned, so 7 bytes of padding are inserted.
+    dyn_push!(stack, 2u64);
+    assert_eq!(stack.capacity(), 16);
+    assert_eq!(stack.byte_len(), 16);
+
+    dyn_push!(stack, 3u8);
+    assert_eq!(stack.capacity(), 32);
+    assert_eq!(stack.byte_len(), 17);
+}
+
+#[test]
+fn test_reserve() {
+    use std::fmt::Debug;
+    let mut stack = DynStack::<dyn Debug>::new();
+    stack.reserve(0);
+    assert!(stack.dyn_data.is_null());
+
+    stack.reserve(100);
+    assert_eq!(stack.capacity(), 128);
+
+    for i in 0..10u32 {
+        dyn_push!(stack, i);
+    }
+    stack.reserve(900);
+    assert_eq!(stack.capacity(), 1024);
+    stack.reserve(960);
+    assert_eq!(stack.capacity(), 1024);
+
+    let data = stack.dyn_data;
+    for i in 10..250u32 {
+        dyn_push!(stack, i);
+    }
+    assert_eq!(stack.dyn_data, data);
+    for (i, item) in stack.iter().enumerate() {
+        assert_eq!(format!("{:?}", item), i.to_string());
+    }
+}
+
+#[test]
+fn test_reserve_within_capacity() {
+    use std::fmt::Debug;
+    let mut stack = DynStack::<dyn Debug>::with_capacity(64);
+    dyn_push!(stack, 1u8);
+    dyn_push!(stack, 2u64);
+    let (data, cap) = (stack.as_ptr(), stack.capacity());
+
+    // Reserving what is already free, padding included, never reallocates.
+    for additional in [0, 1, stack.capacity_remaining()] {
+        stack.reserve(additional);
+        stack.reserve_exact(additional);
+        stack.try_reserve(additional).unwrap();
+        assert_eq!((stack.as_ptr(), stack.capacity()), (data, cap));
+    }
+    stack.reserve(stack.capacity_remaining() + 1);
+    assert_eq!(stack.capacity(), cap * 2);
+}
+
+#[test]
+fn test_reserve_elements() {
+    use std::fmt::Debug;
+
+    let mut stack = DynStack::<dyn Debug>::new();
+    dyn_push!(stack, 1u8);
+    stack.reserve_elements::<u64>(1000);
+    let data = stack.as_ptr();
+    let capacity = stack.capacity();
+    let offsets = stack.offsets().as_ptr();
+    for i in 0..1000u64 {
+        dyn_push!(stack, i);
+    }
+    assert_eq!(stack.as_ptr(), data);
+    assert_eq!(stack.capacity(), capacity);
+    assert_eq!(stack.offsets().as_ptr(), offsets);
+    assert_eq!(stack.len(), 1001);
+    assert_eq!(format!("{:?}", &stack[1000]), "999");
+}
+
+#[test]
+fn test_capacity_for() {
+    use std::fmt::Debug;
+    #[derive(Debug)]
+    #[repr(align(16))]
+    struct Aligned16(#[allow(dead_code)] [u8; 3]);
+
+    type Stack = DynStack<dyn Debug>;
+    assert_eq!(Stack::capacity_for::<u32>(0), 0);
+    assert_eq!(Stack::capacity_for::<u32>(10), 43);
+    assert_eq!(Stack::capacity_for::<Aligned16>(2), 47);
+    assert_eq!(Stack::capacity_for::<()>(5), 0);
+
+    // Pushing after an odd number of bytes needs the most padding.
+    let mut stack = Stack::new();
+    dyn_push!(stack, 1u8);
+    for count in [1, 7, 100] {
+        let before = stack.byte_len();
+        let predicted = Stack::capacity_for::<Aligned16>(count);
+        stack.reserve(predicted);
+        let capacity = stack.capacity();
+        for _ in 0..count {
+            dyn_push!(stack, Aligned16([0; 3]));
+        }
+        assert!(stack.byte_len() - before <= predicted);
+        assert_eq!(stack.capacity(), capacity);
+        dyn_push!(stack, 2u8);
+    }
+}
+
+#[test]
+#[should_panic(expected = "capacity overflow")]
+fn test_capacity_for_overflow() {
+    DynStack::<dyn core::fmt::Debug>::capacity_for::<u64>(usize::MAX / 4);
+}
+
+#[test]
+fn test_reserve_realign() {
+    let mut stack = DynStack::<dyn Item>::new();
+    for i in 0..8u8 {
+        dyn_push!(stack, i);
+        dyn_push!(stack, Aligned64(i as u32 + 100));
+    }
+
+    // Each reallocation has a chance of changing the buffer's alignment mod 64.
+    for i in 0..10 {
+        stack.reserve(stack.capacity() + i);
+        for (n, item) in stack.iter().enumerate() {
+            let thin_ptr = item as *const dyn Item as *const () as usize;
+            assert_eq!(thin_ptr & (item.alignment() - 1), 0);
+            let expected = if n % 2 == 0 { n / 2 } else { n / 2 + 100 };
+            assert_eq!(item.value(), expected as u32);
+        }
+        assert!(stack.byte_len() <= stack.capacity());
+    }
+}
+
+#[test]
+fn test_grow_aligned() {
+    use std::fmt::Debug;
+
+    let mut stack = DynStack::<dyn Debug>::new();
+    dyn_push!(stack, 1u8);
+    dyn_push!(stack, Aligned64(2));
+    // The first reallocation after pushing a 64-byte aligned item aligns the buffer to 64 bytes.
+    stack.reserve(stack.capacity() + 1);
+    assert_eq!(stack.as_ptr() as usize % 64, 0);
+    assert_eq!(stack.buf_align, 64);
+
+    // From then on, the contents never have to be moved within the buffer.
+    for i in 0..64 {
+        dyn_push!(stack, Aligned64(i));
+        dyn_push!(stack, i as u8);
+    }
+    let offsets = stack.offsets().to_vec();
+    for _ in 0..4 {
+        stack.reserve(stack.capacity() + 1);
+        assert_eq!(stack.offsets(), &offsets[..]);
+    }
+    stack.truncate(2);
+    stack.shrink_to_fit();
+    assert_eq!(stack.capacity(), 128);
+    assert_eq!(format!("{:?}", stack), "[1, Aligned64(2)]");
+}
+
+#[test]
+fn test_builder() {
+    use std::fmt::Debug;
+
+    let mut stack: DynStack<dyn Debug> =
+        DynStack::builder().min_capacity(100).base_align(64).build();
+    assert_eq!(stack.max_alignment(), 64);
+    dyn_push!(stack, 0u8);
+    assert_eq!(stack.capacity(), 128);
+    assert_eq!(stack.as_ptr() as usize % 64, 0);
+
+    // The buffer is 64-byte aligned from the start, so growing never moves the contents.
+    let mut offsets = vec![(0, 0)];
+    for i in 1..64 {
+        dyn_push!(stack, Aligned64(i));
+        offsets.push((stack.offsets()[i as usize].0, i as usize));
+        let moved = offsets
+            .iter()
+            .any(|&(offs, index)| stack.offsets()[index].0 != offs);
+        assert!(!moved);
+    }
+    assert_eq!(stack.offsets()[0].0, 0);
+    assert_eq!(stack.buf_align, 64);
+
+    // The tail of a split keeps the layout parameters.
+    let tail = stack.split_off(32);
+    assert_eq!(tail.max_alignment(), 64);
+    assert_eq!(tail.min_cap, 100);
+    stack.clear();
+    stack.shrink_to(1);
+    assert_eq!(stack.capacity(), 128);
+}
+
+#[test]
+fn test_growth_factor() {
+    let mut stack: DynStack<dyn core::fmt::Debug> = DynStack::builder().growth_factor(3, 2).build();
+    let mut capacities = vec![];
+    for i in 0..100u8 {
+        dyn_push!(stack, i);
+        if capacities.last() != Some(&stack.capacity()) {
+            capacities.push(stack.capacity());
+        }
+    }
+    assert_eq!(capacities, [16, 24, 36, 54, 81, 121]);
+    stack.assert_invariants();
+
+    // An item larger than the grown buffer grows it repeatedly until it fits.
+    dyn_push!(stack, [0u8; 200]);
+    assert_eq!(stack.capacity(), 406);
+    assert_eq!(stack.split_off(0).growth_factor, (3, 2));
+
+    // The smallest factor still grows the buffer.
+    let mut stack: DynStack<dyn core::fmt::Debug> =
+        DynStack::builder().growth_factor(101, 100).build();
+    for i in 0..40u8 {
+        dyn_push!(stack, i);
+    }
+    assert_eq!(stack.capacity(), 40);
+}
+
+#[test]
+#[should_panic(expected = "growth factor must be greater than 1")]
+fn test_builder_invalid_growth_factor() {
+    DynStack::<dyn core::fmt::Debug>::builder().growth_factor(2, 2);
+}
+
+#[test]
+#[should_panic(expected = "base alignment must be a power of two")]
+fn test_builder_invalid_align() {
+    DynStack::<dyn core::fmt::Debug>::builder().base_align(48);
+}
+
+#[test]
+fn test_max_alignment() {
+    use std::fmt::Debug;
+
+    let mut stack = DynStack::<dyn Debug>::new();
+    assert_eq!(stack.max_alignment(), 16);
+    dyn_push!(stack, 1u8);
+    dyn_push!(stack, 2u128);
+    assert_eq!(stack.max_alignment(), 16);
+    dyn_push!(stack, Aligned64(3));
+    assert_eq!(stack.max_alignment(), 64);
+
+    // Reserving the documented worst case is enough for a run of aligned items.
+    let count = 10;
+    stack.reserve(count * core::mem::size_of::<Aligned64>() + 64 - 1);
+    let capacity = stack.capacity();
+    for i in 0..count {
+        dyn_push!(stack, Aligned64(i as u32));
+    }
+    assert_eq!(stack.capacity(), capacity);
+    assert_eq!(format!("{:?}", stack.peek().unwrap()), "Aligned64(9)");
+}
+
+#[test]
+fn test_reserve_exact() {
+    use std::fmt::Debug;
+
+    let mut stack = DynStack::<dyn Debug>::new();
+    stack.reserve_exact(0);
+    assert!(stack.as_ptr().is_null());
+
+    stack.reserve_exact(1000);
+    assert_eq!(stack.capacity(), 1000);
+    dyn_push!(stack, [7u8; 1000]);
+    assert_eq!(stack.capacity(), 1000);
+    assert_eq!(stack.byte_len(), 1000);
+
+    stack.reserve_exact(24);
+    assert_eq!(stack.capacity(), 1024);
+    dyn_push!(stack, [1u64; 3]);
+    assert_eq!(stack.capacity(), 1024);
+
+    // Running out of exact capacity doubles it.
+    dyn_push!(stack, 2u8);
+    assert_eq!(stack.capacity(), 2048);
+    assert_eq!(format!("{:?}", stack.peek().unwrap()), "2");
+}
+
+#[test]
+fn test_shrink_to_fit() {
+    let mut stack = DynStack::<dyn Item>::new();
+    stack.shrink_to_fit();
+    assert!(stack.dyn_data.is_null());
+
+    dyn_push!(stack, 1u32);
+    dyn_push!(stack, Aligned64(2));
+    for i in 0..100 {
+        dyn_push!(stack, i as u32);
+    }
+    let cap = stack.capacity();
+    while stack.len() > 2 {
+        stack.remove_last();
+    }
+
+    stack.shrink_to_fit();
+    assert!(stack.capacity() < cap);
+    assert!(stack.byte_len() <= stack.capacity());
+    assert_eq!(stack.len(), 2);
+    for (item, expected) in stack.iter().zip(&[1, 2]) {
+        let thin_ptr = item as *const dyn Item as *const () as usize;
+        assert_eq!(thin_ptr & (item.alignment() - 1), 0);
+        assert_eq!(item.value(), *expected);
+    }
+
+    stack.clear();
+    stack.shrink_to_fit();
+    assert!(stack.dyn_data.is_null());
+    assert_eq!(stack.capacity(), 0);
+
+    dyn_push!(stack, 3u32);
+    assert_eq!(stack[0].value(), 3);
+}
+
+#[test]
+fn test_shrink_to() {
+    use std::fmt::Debug;
+
+    let mut stack = DynStack::<dyn Debug>::new();
+    stack.shrink_to(100);
+    assert_eq!(stack.capacity(), 0);
+
+    stack.reserve(1024);
+    dyn_push!(stack, [1u64; 4]);
+    stack.shrink_to(100);
+    assert_eq!(stack.capacity(), 128);
+    // Never shrinks below the contents, and never grows.
+    stack.shrink_to(0);
+    assert_eq!(stack.capacity(), 32);
+    stack.shrink_to(100);
+    assert_eq!(stack.capacity(), 32);
+    assert_eq!(format!("{:?}", stack), "[[1, 1, 1, 1]]");
+
+    stack.clear();
+    stack.shrink_to(16);
+    assert_eq!(stack.capacity(), 16);
+    stack.shrink_to(0);
+    assert_eq!(stack.capacity(), 0);
+    assert!(stack.as_ptr().is_null());
+}
+
+#[test]
+fn test_push_copy() {
+    use std::fmt::Debug;
+    let mut stack = DynStack::<dyn Debug>::new();
+    stack.push_copy(1u8, |x| x);
+    stack.push_copy(0x1234u64, |x| x);
+    stack.push_copy([1u16, 2, 3], |x| x);
+    stack.push_copy("hello", |x| x);
+
+    let strings: Vec<_> = stack.iter().map(|item| format!("{:?}", item)).collect();
+    assert_eq!(strings, ["1", "4660", "[1, 2, 3]", "\"hello\""]);
+}
+
+#[test]
+#[should_panic(expected = "coerce must return a reference to the pushed item")]
+fn test_push_copy_wrong_ref() {
+    use std::fmt::Debug;
+    let mut stack = DynStack::<dyn Debug>::new();
+    stack.push_copy(1u8, |_| Box::leak(Box::new(String::from("leaked"))));
+}
+
+#[test]
+fn test_push_raw_owned() {
+    use std::fmt::Debug;
+
+    // Push a value out of manually managed storage, then free the storage without dropping.
+    let layout = Layout::new::<String>();
+    let mut stack = DynStack::<dyn Debug>::new();
+    unsafe {
+        let storage = alloc(layout) as *mut String;
+        storage.write(String::from("owned"));
+        assert_eq!(stack.push_raw_owned(storage as *mut dyn Debug), 0);
+        dealloc(storage as *mut u8, layout);
+    }
+    assert_eq!(format!("{:?}", stack), "[\"owned\"]");
+}
+
+#[test]
+fn test_push_box() {
+    use std::fmt::Debug;
+    let mut stack = DynStack::<dyn Debug>::new();
+    stack.push_box(Box::new(String::from("boxed")));
+    stack.push_box(Box::new(()));
+    stack.push_box(Box::new(vec![1, 2, 3]) as Box<dyn Debug>);
+
+    assert_eq!(format!("{:?}", &stack[0]), "\"boxed\"");
+    assert_eq!(format!("{:?}", &stack[1]), "()");
+    assert_eq!(format!("{:?}", &stack[2]), "[1, 2, 3]");
+}
+
+#[test]
+fn test_into_vec_boxed() {
+    use std::cell::Cell;
+
+    thread_local!(static DROPS: Cell<usize> = const { Cell::new(0) });
+
+    trait Shape {
+        fn area(&self) -> u64;
+    }
+    #[repr(align(64))]
+    struct Square(u64);
+    impl Shape for Square {
+        fn area(&self) -> u64 {
+            self.0 * self.0
+        }
+    }
+    struct Counted(u64);
+    impl Shape for Counted {
+        fn area(&self) -> u64 {
+            self.0
+        }
+    }
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPS.with(|drops| drops.set(drops.get() + 1));
+        }
+    }
+    struct Point;
+    impl Shape for Point {
+        fn area(&self) -> u64 {
+            0
+        }
+    }
+
+    let mut stack = DynStack::<dyn Shape>::new();
+    dyn_push!(stack, Square(3));
+    dyn_push!(stack, Counted(5));
+    dyn_push!(stack, Point);
+    dyn_push!(stack, Square(4));
+
+    let boxes = stack.into_vec_boxed();
+    assert_eq!(DROPS.with(|drops| drops.get()), 0);
+    let areas: Vec<u64> = boxes.iter().map(|shape| shape.area()).collect();
+    assert_eq!(areas, [9, 5, 0, 16]);
+    assert_eq!(&*boxes[0] as *const dyn Shape as *const u8 as usize % 64, 0);
+
+    drop(boxes);
+    assert_eq!(DROPS.with(|drops| drops.get()), 1);
+}
+
+#[test]
+fn test_pop_boxed() {
+    use std::{fmt::Debug, rc::Rc};
+
+    #[repr(align(32))]
+    #[derive(Debug)]
+    struct Aligned32(#[allow(dead_code)] u8);
+
+    let shared = Rc::new(5);
+    let mut stack = DynStack::<dyn Debug>::new();
+    dyn_push!(stack, Rc::clone(&shared));
+    dyn_push!(stack, ());
+    dyn_push!(stack, Aligned32(3));
+
+    let aligned = stack.pop_boxed().unwrap();
+    assert_eq!(&*aligned as *const dyn Debug as *const u8 as usize % 32, 0);
+    assert_eq!(format!("{:?}", aligned), "Aligned32(3)");
+    assert_eq!(format!("{:?}", stack.pop_boxed().unwrap()), "()");
+
+    // Ownership moves into the box, so the value is dropped exactly once, with the box.
+    let boxed = stack.pop_boxed().unwrap();
+    assert!(stack.is_empty());
+    assert_eq!(Rc::strong_count(&shared), 2);
+    assert_eq!(format!("{:?}", boxed), "5");
+    drop(boxed);
+    assert_eq!(Rc::strong_count(&shared), 1);
+    assert!(stack.pop_boxed().is_none());
+}
+
+#[test]
+fn test_drop_unallocated() {
+    use std::fmt::Debug;
+
+    /// Fails the test if asked to free anything.
+    struct NoFree;
+    unsafe impl Allocator for NoFree {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+            Global.allocate(layout)
+        }
+        unsafe fn deallocate(&self, _: NonNull<u8>, _: Layout) {
+            panic!("deallocated a buffer");
+        }
+    }
+
+    // A stack that never allocated has a null buffer, which must not be passed to the allocator.
+    drop(DynStack::<dyn Debug, _>::new_in(NoFree));
+    drop(DynStack::<dyn Debug, _>::with_capacity_in(0, NoFree));
+    let mut stack = DynStack::<dyn Debug, _>::new_in(NoFree);
+    stack.shrink_to_fit();
+    stack.clear();
+    drop(stack);
+
+    // Neither must a buffer that was already freed.
+    let mut stack = DynStack::<dyn Debug>::new();
+    dyn_push!(stack, 1u8);
+    stack.remove_last();
+    stack.shrink_to_fit();
+    assert!(stack.as_ptr().is_null());
+    drop(stack);
+}
+
+#[test]
+fn test_default() {
+    use std::fmt::Debug;
+
+    #[derive(Default)]
+    struct Holder {
+        stack: DynStack<dyn Debug>,
+    }
+
+    let mut holder = Holder::default();
+    assert!(holder.stack.is_empty());
+    dyn_push!(holder.stack, 1u8);
+    assert_eq!(holder.stack.len(), 1);
+}
+
+#[test]
+fn test_swap_remove() {
+    use std::fmt::Debug;
+    let mut stack = DynStack::<dyn Debug>::new();
+    assert!(!stack.swap_remove(0));
+
+    dyn_push!(stack, String::from("zero"));
+    dyn_push!(stack, 1u8);
+    dyn_push!(stack, [2u64; 4]);
+    dyn_push!(stack, 3u16);
+    dyn_push!(stack, String::from("four"));
+    assert!(!stack.swap_remove(5));
+
+    // The last String fits where the first one was
+    assert!(stack.swap_remove(0));
+    assert!(stack.offs_sorted);
+    let strings: Vec<_> = stack.iter().map(|item| format!("{:?}", item)).collect();
+    assert_eq!(strings, ["\"four\"", "1", "[2, 2, 2, 2]", "3"]);
+
+    // The u16 fits where the u8 was
+    assert!(stack.swap_remove(1));
+    assert!(stack.offs_sorted);
+    let strings: Vec<_> = stack.iter().map(|item| format!("{:?}", item)).collect();
+    assert_eq!(strings, ["\"four\"", "3", "[2, 2, 2, 2]"]);
+
+    // The array doesn't fit where the String was
+    assert!(stack.swap_remove(0));
+    assert!(!stack.offs_sorted);
+    let strings: Vec<_> = stack.iter().map(|item| format!("{:?}", item)).collect();
+    assert_eq!(strings, ["[2, 2, 2, 2]", "3"]);
+
+    // Removing items from an unsorted stack must not free bytes still in use.
+    let size = stack.byte_len();
+    assert!(stack.remove_last());
+    assert_eq!(stack.byte_len(), size);
+    dyn_push!(stack, String::from("five"));
+    let strings: Vec<_> = stack.iter().map(|item| format!("{:?}", item)).collect();
+    assert_eq!(strings, ["[2, 2, 2, 2]", "\"five\""]);
+
+    assert!(stack.swap_remove(0));
+    assert!(stack.remove_last());
+    assert!(stack.offs_sorted);
+    assert_eq!(stack.byte_len(), 0);
+}
+
+#[test]
+fn test_swap_remove_reuses_space() {
+    use std::fmt::Debug;
+    let mut stack = DynStack::<dyn Debug>::new();
+    dyn_push!(stack, 0u8);
+    dyn_push!(stack, 1u16);
+    dyn_push!(stack, [2u64; 4]);
+    // The array doesn't fit where the u8 was, leaving the table unsorted
+    assert!(stack.swap_remove(0));
+    assert!(!stack.offs_sorted);
+    stack.assert_invariants();
+
+    // Items pushed and removed again must not leave their bytes behind
+    let (size, capacity) = (stack.byte_len(), stack.capacity());
+    for i in 0..100u32 {
+        dyn_push!(stack, i);
+        assert!(stack.remove_last());
+        stack.assert_invariants();
+    }
+    assert_eq!(stack.byte_len(), size);
+    assert_eq!(stack.capacity(), capacity);
+    assert_eq!(format!("{:?}", stack), "[[2, 2, 2, 2], 1]");
+
+    // Removing the item at the end of the buffer releases the gap below it as well
+    assert!(stack.swap_remove(0));
+    assert!(stack.offs_sorted);
+    assert_eq!(stack.byte_len(), 4);
+    stack.assert_invariants();
+}
+
+#[test]
+fn test_pop_with() {
+    use std::cell::Cell;
+
+    thread_local!(static DROPPED: Cell<bool> = const { Cell::new(false) });
+
+    trait Named {
+        fn name(&self) -> &str;
+    }
+    struct Item(String);
+    impl Named for Item {
+        fn name(&self) -> &str {
+            &self.0
+        }
+    }
+    impl Drop for Item {
+        fn drop(&mut self) {
+            DROPPED.with(|dropped| dropped.set(true));
+        }
+    }
+
+    let mut stack = DynStack::<dyn Named>::new();
+    dyn_push!(stack, Item(String::from("bottom")));
+    dyn_push!(stack, Item(String::from("top")));
+
+    let name = stack.pop_with(|item| {
+        assert!(!DROPPED.with(|dropped| dropped.get()));
+        item.name().to_string()
+    });
+    assert_eq!(name.as_deref(), Some("top"));
+    assert!(DROPPED.with(|dropped| dropped.get()));
+    assert_eq!(stack.len(), 1);
+    assert_eq!(stack[0].name(), "bottom");
+
+    DROPPED.with(|dropped| dropped.set(false));
+    assert_eq!(stack.pop_with(|item| item.name().len()), Some(6));
+    assert!(DROPPED.with(|dropped| dropped.get()));
+    assert_eq!(stack.pop_with(|item| item.name().len()), None);
+}
+
+#[test]
+fn test_offsets() {
+    use core::fmt::Debug;
+
+    let mut stack = DynStack::<dyn Debug>::new();
+    assert!(stack.as_ptr().is_null());
+    assert!(stack.offsets().is_empty());
+
+    dyn_push!(stack, 1u8);
+    dyn_push!(stack, [2u64, 3]);
+    dyn_push!(stack, "four");
+    assert_eq!(stack.as_ptr(), stack.as_mut_ptr() as *const u8);
+
+    let base = stack.as_ptr() as usize;
+    for (&(offset, vtable), item) in stack.offsets().iter().zip(stack.iter()) {
+        let [data, metadata] = unsafe { fatptr::decomp(item as *const dyn Debug) };
+        assert_eq!(data, base + offset);
+        assert_eq!(metadata, vtable);
+    }
+}
+
+#[test]
+fn test_new_zeroed() {
+    use core::fmt::Debug;
+
+    fn build(stack: &mut DynStack<dyn Debug>) {
+        dyn_push!(stack, 1u8);
+        dyn_push!(stack, 2u64);
+        dyn_push!(stack, 3u16);
+        dyn_push!(stack, [4u32; 3]);
+        dyn_push!(stack, 5u8);
+        dyn_push!(stack, 6u64);
+        // Moves the last item into the hole.
+        stack.swap_remove(1);
+        dyn_push!(stack, [7u64; 2]);
+        // Leaves a hole, since the last item doesn't fit.
+        stack.swap_remove(0);
+        stack.remove_last();
+    }
+    fn bytes(stack: &DynStack<dyn Debug>) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(stack.as_ptr(), stack.byte_len()) }
+    }
+
+    let mut fresh = DynStack::<dyn Debug>::new_zeroed();
+    build(&mut fresh);
+
+    // Fill a buffer with nonzero bytes first, so that stale data would show up as differences.
+    let mut reused = DynStack::<dyn Debug>::new_zeroed();
+    for _ in 0..8 {
+        dyn_push!(reused, [0xFFu8; 16]);
+    }
+    reused.clear();
+    build(&mut reused);
+
+    assert_eq!(format!("{:?}", fresh), format!("{:?}", reused));
+    assert_eq!(bytes(&fresh), bytes(&reused));
+}
+
+#[test]
+fn test_as_bytes() {
+    trait Value {
+        fn get(&self) -> u64;
+        fn set(&mut self, value: u64);
+    }
+    impl Value for u8 {
+        fn get(&self) -> u64 {
+            *self as u64
+        }
+        fn set(&mut self, value: u64) {
+            *self = value as u8;
+        }
+    }
+    impl Value for [u32; 3] {
+        fn get(&self) -> u64 {
+            self.iter().map(|&x| x as u64).sum()
+        }
+        fn set(&mut self, value: u64) {
+            *self = [value as u32; 3];
+        }
+    }
+
+    let mut stack = DynStack::<dyn Value>::new_zeroed();
+    assert!(unsafe { stack.as_bytes() }.is_empty());
+    dyn_push!(stack, 1u8);
+    dyn_push!(stack, [2u32, 3, 4]);
+    dyn_push!(stack, 5u8);
+    dyn_push!(stack, [6u32; 3]);
+    stack.swap_remove(1);
+
+    let bytes = unsafe { stack.as_bytes() }.to_vec();
+    let offsets = stack.offsets().to_vec();
+    assert_eq!(bytes.len(), stack.byte_len());
+    assert_eq!(&bytes[..5], [1, 0, 0, 0, 6]);
+
+    // Restore the snapshot into the same stack after changing its items.
+    for item in stack.iter_mut() {
+        item.set(0);
+    }
+    let values = |stack: &DynStack<dyn Value>| stack.iter().map(|v| v.get()).collect::<Vec<_>>();
+    assert_eq!(values(&stack), [0, 0, 0]);
+    unsafe {
+        ptr::copy_nonoverlapping(bytes.as_ptr(), stack.as_mut_ptr(), bytes.len());
+    }
+    assert_eq!(stack.offsets(), &offsets[..]);
+    assert_eq!(values(&stack), [1, 18, 5]);
+}
+
+#[test]
+fn test_from_raw_parts() {
+    use core::fmt::Debug;
+
+    let restore = |stack: &DynStack<dyn Debug>| unsafe {
+        let bytes = stack.as_bytes().to_vec();
+        DynStack::<dyn Debug>::from_raw_parts(&bytes, stack.offsets(), stack.max_alignment())
+    };
+
+    let empty = restore(&DynStack::new_zeroed());
+    assert!(empty.is_empty() && empty.as_ptr().is_null());
+
+    let mut stack = DynStack::<dyn Debug>::new_zeroed();
+    dyn_push!(stack, 1u8);
+    dyn_push!(stack, Aligned64(2));
+    // Align the buffer to the strictest item.
+    stack.reserve(stack.capacity() + 1);
+    dyn_push!(stack, ());
+    dyn_push!(stack, [3u16; 5]);
+    dyn_push!(stack, 4u32);
+
+    let mut copy = restore(&stack);
+    copy.assert_invariants();
+    assert_eq!(format!("{:?}", copy), format!("{:?}", stack));
+    assert_eq!(copy.offsets(), stack.offsets());
+    assert_eq!(copy.max_alignment(), 64);
+    dyn_push!(copy, Aligned64(5));
+    // The last item doesn't fit in the removed item's bytes.
+    copy.swap_remove(3);
+    assert!(!copy.offs_sorted);
+
+    // An unsorted offset table stays unsorted.
+    let mut copy = restore(&copy);
+    copy.assert_invariants();
+    assert!(!copy.offs_sorted);
+    assert_eq!(
+        format!("{:?}", copy),
+        "[1, Aligned64(2), (), Aligned64(5), 4]"
+    );
+    copy.remove_last();
+    dyn_push!(copy, 6u8);
+    copy.assert_invariants();
+}
+
+#[test]
+fn test_set_len() {
+    use core::cell::Cell;
+    use std::fmt::Debug;
+
+    thread_local!(static DROPS: Cell<usize> = const { Cell::new(0) });
+
+    #[derive(Debug)]
+    #[allow(dead_code)]
+    struct Droppable(u32);
+    impl Drop for Droppable {
+        fn drop(&mut self) {
+            DROPS.with(|drops| drops.set(drops.get() + 1));
+        }
+    }
+
+    let mut stack = DynStack::<dyn Debug>::new();
+    dyn_push!(stack, 1u32);
+    dyn_push!(stack, 2u32);
+    dyn_push!(stack, Droppable(3));
+
+    // Overwrite the second item in place, and forget the third without dropping it.
+    unsafe {
+        let second = stack.as_mut_ptr().add(stack.offsets()[1].0);
+        second.cast::<u32>().write(20);
+        stack.set_len(2, 8);
+    }
+    stack.assert_invariants();
+    assert_eq!(DROPS.with(Cell::get), 0);
+    assert_eq!(format!("{:?}", stack), "[1, 20]");
+
+    // Reserve bytes past the items by hand, and push after them.
+    unsafe {
+        stack.as_mut_ptr().add(8).write_bytes(0xff, 4);
+        stack.set_len(2, 12);
+    }
+    dyn_push!(stack, 4u32);
+    stack.assert_invariants();
+    assert_eq!(stack.offsets()[2].0, 12);
+    assert_eq!(unsafe { *stack.as_ptr().add(8) }, 0xff);
+    assert_eq!(format!("{:?}", stack), "[1, 20, 4]");
+
+    unsafe { stack.set_len(0, 0) };
+    stack.assert_invariants();
+    assert!(stack.is_empty());
+    assert_eq!(stack.byte_len(), 0);
+    assert_eq!(DROPS.with(Cell::get), 0);
+}
+
+#[test]
+fn test_drain_with() {
+    use std::cell::RefCell;
+
+    thread_local!(static DROPPED: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) });
+
+    trait Id {
+        fn id(&self) -> usize;
+    }
+    struct Item(usize);
+    impl Id for Item {
+        fn id(&self) -> usize {
+            self.0
+        }
+    }
+    impl Drop for Item {
+        fn drop(&mut self) {
+            DROPPED.with(|dropped| dropped.borrow_mut().push(self.0));
+        }
+    }
+
+    let mut stack = DynStack::<dyn Id>::new();
+    for i in 0..5 {
+        dyn_push!(stack, Item(i));
+    }
+
+    let mut seen = Vec::new();
+    stack.drain_with(|item| {
+        // Every item is seen before it is dropped.
+        assert!(!DROPPED.with(|dropped| dropped.borrow().contains(&item.id())));
+        seen.push(item.id());
+        item.id() != 3
+    });
+    assert_eq!(seen, [4, 3]);
+    assert!(stack.is_empty());
+    assert_eq!(stack.byte_len(), 0);
+    DROPPED.with(|dropped| assert_eq!(*dropped.borrow(), [4, 3, 2, 1, 0]));
+
+    // The stack is also emptied if the callback panics.
+    for i in 5..8 {
+        dyn_push!(stack, Item(i));
+    }
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        stack.drain_with(|item| {
+            if item.id() == 6 {
+                panic!("stop");
+            }
+            true
+        })
+    }));
+    assert!(result.is_err());
+    assert!(stack.is_empty());
+    DROPPED.with(|dropped| assert_eq!(*dropped.borrow(), [4, 3, 2, 1, 0, 7, 6, 5]));
+}
+
+#[test]
+fn test_drop_panic() {
+    use std::{
+        cell::RefCell,
+        panic::{catch_unwind, AssertUnwindSafe},
+    };
+
+    thread_local!(static DROPPED: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) });
+
+    trait Id {
+        fn id(&self) -> usize;
+    }
+    /// Panics when dropped if `panics` is set.
+    struct Item {
+        id: usize,
+        panics: bool,
+    }
+    impl Id for Item {
+        fn id(&self) -> usize {
+            self.id
+        }
+    }
+    impl Drop for Item {
+        fn drop(&mut self) {
+            DROPPED.with(|dropped| dropped.borrow_mut().push(self.id));
+            if self.panics {
+                panic!("drop {}", self.id);
+            }
+        }
+    }
+    fn push(stack: &mut DynStack<dyn Id>, id: usize, panics: bool) {
+        dyn_push!(stack, Item { id, panics });
+    }
+    fn ids(stack: &DynStack<dyn Id>) -> Vec<usize> {
+        stack.iter().map(|item| item.id()).collect()
+    }
+    let dropped = || DROPPED.with(|dropped| dropped.borrow_mut().split_off(0));
+
+    let mut stack = DynStack::<dyn Id>::new();
+    push(&mut stack, 0, false);
+    push(&mut stack, 1, true);
+    assert!(catch_unwind(AssertUnwindSafe(|| stack.remove_last())).is_err());
+    assert_eq!(ids(&stack), [0]);
+    assert_eq!(dropped(), [1]);
+
+    // The stack is still usable, and reuses the popped item's bytes.
+    push(&mut stack, 2, false);
+    assert_eq!(ids(&stack), [0, 2]);
+    stack.assert_invariants();
+
+    push(&mut stack, 3, true);
+    push(&mut stack, 4, false);
+    assert!(catch_unwind(AssertUnwindSafe(|| stack.truncate(1))).is_err());
+    assert_eq!(ids(&stack), [0, 2]);
+    assert_eq!(dropped(), [4, 3]);
+
+    push(&mut stack, 5, true);
+    push(&mut stack, 6, false);
+    assert!(catch_unwind(AssertUnwindSafe(|| stack.clear())).is_err());
+    assert_eq!(ids(&stack), [0, 2]);
+    assert_eq!(dropped(), [6, 5]);
+    stack.assert_invariants();
+
+    // The last item still takes a panicking item's place.
+    push(&mut stack, 9, true);
+    push(&mut stack, 10, false);
+    assert!(catch_unwind(AssertUnwindSafe(|| stack.swap_remove(2))).is_err());
+    assert_eq!(ids(&stack), [0, 2, 10]);
+    assert_eq!(dropped(), [9]);
+    stack.assert_invariants();
+    assert!(stack.remove_last());
+    assert_eq!(dropped(), [10]);
+
+    // Dropping the stack drops the items below a panicking one too.
+    push(&mut stack, 7, true);
+    push(&mut stack, 8, false);
+    assert!(catch_unwind(AssertUnwindSafe(|| drop(stack))).is_err());
+    assert_eq!(dropped(), [8, 7, 2, 0]);
+}
+
+#[test]
+fn test_get_many_mut() {
+    trait Value {
+        fn get(&self) -> u64;
+        fn set(&mut self, value: u64);
+    }
+    impl Value for u8 {
+        fn get(&self) -> u64 {
+            *self as u64
+        }
+        fn set(&mut self, value: u64) {
+            *self = value as u8;
+        }
+    }
+    impl Value for [u64; 2] {
+        fn get(&self) -> u64 {
+            self[0] + self[1]
+        }
+        fn set(&mut self, value: u64) {
+            *self = [value, 0];
+        }
+    }
+
+    let mut stack = DynStack::<dyn Value>::new();
+    dyn_push!(stack, 1u8);
+    dyn_push!(stack, [2u64, 3]);
+    dyn_push!(stack, 6u8);
+
+    let [last, first] = stack.get_many_mut([2, 0]).unwrap();
+    let (a, b) = (last.get(), first.get());
+    last.set(b);
+    first.set(a);
+    let [pair] = stack.get_many_mut([1]).unwrap();
+    pair.set(pair.get() * 2);
+    let values: Vec<u64> = stack.iter().map(|item| item.get()).collect();
+    assert_eq!(values, [6, 10, 1]);
+
+    assert!(stack.get_many_mut([0, 2, 0]).is_none());
+    assert!(stack.get_many_mut([1, 3]).is_none());
+    assert_eq!(stack.get_many_mut([]).map(|items| items.len()), Some(0));
+}
+
+#[test]
+fn test_get_pair_mut() {
+    let mut stack = DynStack::<dyn AsMut<[u32]>>::new();
+    dyn_push!(stack, [1u32, 2]);
+    dyn_push!(stack, vec![3u32, 4, 5]);
+
+    let (a, b) = stack.get_pair_mut(1, 0).unwrap();
+    a.as_mut()[..2].swap_with_slice(b.as_mut());
+    assert_eq!(stack[0].as_mut(), [3, 4]);
+    assert_eq!(stack[1].as_mut(), [1, 2, 5]);
+
+    assert!(stack.get_pair_mut(0, 0).is_none());
+    assert!(stack.get_pair_mut(0, 2).is_none());
+    assert!(stack.get_pair_mut(2, 1).is_none());
+}
+
+#[test]
+fn test_swap() {
+    use std::fmt::Debug;
+
+    let mut stack = DynStack::<dyn Debug>::new();
+    dyn_push!(stack, 1u8);
+    dyn_push!(stack, String::from("two"));
+    dyn_push!(stack, [3u64; 2]);
+    let ptr = stack.as_ptr();
+
+    stack.swap(0, 2);
+    assert_eq!(format!("{:?}", stack), r#"[[3, 3], "two", 1]"#);
+    assert_eq!(stack.as_ptr(), ptr);
+    stack.swap(1, 1);
+    stack.assert_invariants();
+
+    // Pushing and removing items over the swapped ones doesn't grow the buffer, once it has
+    // room for one of them.
+    let size = stack.byte_len();
+    stack.reserve(8);
+    let capacity = stack.capacity();
+    for i in 0..100u32 {
+        dyn_push!(stack, i);
+        assert!(stack.remove_last());
+    }
+    assert_eq!(stack.byte_len(), size);
+    assert_eq!(stack.capacity(), capacity);
+    stack.assert_invariants();
+
+    // The swapped items can still be removed, and pushed over.
+    stack.remove_last();
+    dyn_push!(stack, 4u32);
+    stack.assert_invariants();
+    assert_eq!(format!("{:?}", stack), r#"[[3, 3], "two", 4]"#);
+    stack.swap(2, 0);
+    assert_eq!(format!("{:?}", stack.get(0)), "Some(4)");
+}
+
+#[test]
+#[should_panic]
+fn test_index_out_of_bounds() {
+    let mut stack = DynStack::<dyn core::fmt::Debug>::new();
+    dyn_push!(stack, 1u8);
+    let _ = &stack[1];
+}
+
+#[test]
+fn test_rotate() {
+    use std::fmt::Debug;
+
+    let mut stack: DynVec<dyn Debug> = DynVec::new();
+    dyn_push!(stack, 0u8);
+    dyn_push!(stack, "one");
+    dyn_push!(stack, [2u16; 2]);
+    dyn_push!(stack, 3u64);
+    dyn_push!(stack, String::from("four"));
+
+    stack.rotate_left(0);
+    stack.rotate_right(5);
+    assert!(stack.offs_sorted);
+    stack.rotate_left(2);
+    assert_eq!(format!("{:?}", stack), r#"[[2, 2], 3, "four", 0, "one"]"#);
+    stack.assert_invariants();
+    stack.rotate_right(3);
+    assert_eq!(format!("{:?}", stack), r#"["four", 0, "one", [2, 2], 3]"#);
+    stack.rotate_right(1);
+    assert_eq!(format!("{:?}", stack), r#"[3, "four", 0, "one", [2, 2]]"#);
+    stack.assert_invariants();
+
+    // Pushing and removing items over the rotated ones doesn't grow the buffer, once it has
+    // room for one of them.
+    let size = stack.byte_len();
+    stack.reserve(8);
+    let capacity = stack.capacity();
+    for i in 0..100u32 {
+        dyn_push!(stack, i);
+        assert!(stack.remove_last());
+    }
+    assert_eq!(stack.byte_len(), size);
+    assert_eq!(stack.capacity(), capacity);
+    stack.assert_invariants();
+
+    // The items stay usable, and removing them releases their bytes once the stack is empty.
+    stack.remove_last();
+    dyn_push!(stack, 5u8);
+    stack.compact();
+    assert_eq!(format!("{:?}", stack), r#"[3, "four", 0, "one", 5]"#);
+    stack.clear();
+    assert_eq!(stack.byte_len(), 0);
+}
+
+#[test]
+#[should_panic]
+fn test_rotate_out_of_bounds() {
+    let mut stack = DynStack::<dyn core::fmt::Debug>::new();
+    dyn_push!(stack, 1u8);
+    stack.rotate_right(2);
+}
+
+#[test]
+#[should_panic]
+fn test_swap_out_of_bounds() {
+    let mut stack = DynStack::<dyn core::fmt::Debug>::new();
+    dyn_push!(stack, 1u8);
+    stack.swap(0, 1);
+}
+
+#[test]
+fn test_sort() {
+    use std::fmt::Display;
+
+    trait Number: Display {
+        fn value(&self) -> i64;
+    }
+    impl Number for u8 {
+        fn value(&self) -> i64 {
+            *self as i64
+        }
+    }
+    impl Number for i64 {
+        fn value(&self) -> i64 {
+            *self
+        }
+    }
+    impl Number for f32 {
+        fn value(&self) -> i64 {
+            *self as i64
+        }
+    }
+
+    let mut stack = DynStack::<dyn Number>::new();
+    dyn_push!(stack, 5u8);
+    dyn_push!(stack, -3i64);
+    dyn_push!(stack, 5.5f32);
+    dyn_push!(stack, 0u8);
+    dyn_push!(stack, 100i64);
+    let ptr = stack.as_ptr();
+    let strings = |stack: &DynStack<dyn Number>| {
+        stack
+            .iter()
+            .map(|item| item.to_string())
+            .collect::<Vec<_>>()
+    };
+
+    // 5 and 5.5 have equal keys, and keep their order.
+    stack.sort_by_key(|item| item.value());
+    assert_eq!(strings(&stack), ["-3", "0", "5", "5.5", "100"]);
+    assert_eq!(stack.as_ptr(), ptr);
+    stack.assert_invariants();
+
+    stack.sort_by(|a, b| b.value().cmp(&a.value()));
+    assert_eq!(strings(&stack), ["100", "5", "5.5", "0", "-3"]);
+
+    // Pushing and removing items over the sorted ones doesn't grow the buffer, once it has
+    // room for one of them.
+    let size = stack.byte_len();
+    stack.reserve(8);
+    let capacity = stack.capacity();
+    for i in 0..100u8 {
+        dyn_push!(stack, i);
+        assert!(stack.remove_last());
+    }
+    assert_eq!(stack.byte_len(), size);
+    assert_eq!(stack.capacity(), capacity);
+    stack.assert_invariants();
+
+    // Sorting an already sorted stack keeps its offset table in byte order.
+    let mut sorted = DynStack::<dyn Number>::new();
+    for i in 0..4u8 {
+        dyn_push!(sorted, i);
+    }
+    sorted.sort_by_key(|item| item.value());
+    assert!(sorted.offs_sorted);
+
+    while stack.remove_last() {
+        stack.assert_invariants();
+    }
+}
+
+#[test]
+fn test_push_indexed() {
+    use std::fmt::Debug;
+    let mut stack = DynStack::<dyn Debug>::new();
+    assert_eq!(unsafe { stack.push(&mut 1u8 as *mut u8) }, 0);
+    assert_eq!(dyn_push_indexed!(stack, String::from("two")), 1);
+    assert_eq!(dyn_push_indexed!(stack, [3u64; 4]), 2);
+    stack.remove_last();
+    assert_eq!(dyn_push_indexed!(stack, 4u16), 2);
+    assert_eq!(format!("{:?}", &stack[1]), "\"two\"");
+    assert_eq!(format!("{:?}", &stack[2]), "4");
+}
+
+#[test]
+fn test_checkpoint() {
+    use std::fmt::Debug;
+
+    let mut stack = DynStack::<dyn Debug>::new();
+    dyn_push!(stack, 0u8);
+    let outer = stack.checkpoint();
+    dyn_push!(stack, String::from("one"));
+    let inner = stack.checkpoint();
+    dyn_push!(stack, [2u64; 8]);
+    dyn_push!(stack, 3u16);
+    assert_eq!(stack.checkpoint(), stack.checkpoint());
+
+    stack.rollback(inner);
+    assert_eq!(format!("{:?}", stack), "[0, \"one\"]");
+    // Rolling back to the same checkpoint again is allowed.
+    stack.rollback(inner);
+    assert_eq!(stack.len(), 2);
+
+    dyn_push!(stack, 4u32);
+    stack.rollback(outer);
+    assert_eq!(format!("{:?}", stack), "[0]");
+
+    dyn_push!(stack, 5u8);
+    assert_eq!(format!("{:?}", stack), "[0, 5]");
+}
+
+#[test]
+#[should_panic(expected = "checkpoint belongs to a different stack")]
+fn test_checkpoint_other_stack() {
+    use std::fmt::Debug;
+
+    let first = DynStack::<dyn Debug>::new();
+    let mut second = DynStack::<dyn Debug>::new();
+    let checkpoint = first.checkpoint();
+    second.checkpoint();
+    second.rollback(checkpoint);
+}
+
+#[test]
+#[should_panic(expected = "stack was shortened past the checkpoint")]
+fn test_checkpoint_shortened() {
+    use std::fmt::Debug;
+
+    let mut stack = DynStack::<dyn Debug>::new();
+    dyn_push!(stack, 1u8);
+    let checkpoint = stack.checkpoint();
+    stack.remove_last();
+    stack.rollback(checkpoint);
+}
+
+#[test]
+fn test_memory_report() {
+    use std::fmt::Debug;
+
+    let mut stack = DynStack::<dyn Debug>::new();
+    assert_eq!(
+        stack.memory_report(),
+        MemoryReport {
+            capacity: 0,
+            used_bytes: 0,
+            payload_bytes: 0,
+            padding_bytes: 0,
+            len: 0,
+            max_align: 16,
+        }
+    );
+
+    dyn_push!(stack, 1u8);
+    dyn_push!(stack, 2u64);
+    dyn_push!(stack, [3u64; 2]);
+    let report = stack.memory_report();
+    assert_eq!(report.capacity, 32);
+    assert_eq!(report.used_bytes, 32);
+    assert_eq!(report.payload_bytes, 25);
+    assert_eq!(report.padding_bytes, 7);
+    assert_eq!(report.len, 3);
+    assert_eq!(report.max_align, 16);
+
+    // The array doesn't fit in the u8's bytes, so they are left behind until compacting.
+    stack.swap_remove(0);
+    let report = stack.memory_report();
+    assert_eq!((report.payload_bytes, report.padding_bytes), (24, 8));
+    stack.compact();
+    let report = stack.memory_report();
+    assert_eq!((report.payload_bytes, report.padding_bytes), (24, 0));
+}
+
+#[test]
+fn test_element_size() {
+    use std::fmt::Debug;
+
+    let mut stack = DynStack::<dyn Debug>::new();
+    dyn_push!(stack, 0u8);
+    dyn_push!(stack, [1u64; 2]);
+    dyn_push!(stack, ());
+    dyn_push!(stack, 3u16);
+    dyn_push!(stack, [4u16; 5]);
+
+    let slots: Vec<_> = (0..5).map(|i| stack.element_size(i).unwrap()).collect();
+    let payloads: Vec<_> = (0..5)
+        .map(|i| stack.element_payload_size(i).unwrap())
+        .collect();
+    assert_eq!(slots, [8, 16, 0, 2, 10]);
+    assert_eq!(payloads, [1, 16, 0, 2, 10]);
+    assert_eq!(slots.iter().sum::<usize>(), stack.byte_len());
+
+    // The first array doesn't fit in the u8's slot, so it stays in place and the table becomes
+    // unsorted.
+    stack.swap_remove(0);
+    assert!(!stack.offs_sorted);
+    assert_eq!(stack.element_size(0), Some(10));
+    assert_eq!(stack.element_size(1), Some(16));
+    assert_eq!(stack.element_size(2), Some(0));
+    assert_eq!(stack.element_size(3), Some(2));
+    assert_eq!(stack.element_size(4), None);
+    assert_eq!(stack.element_payload_size(4), None);
+}
+
+#[test]
+fn test_truncate() {
+    use std::cell::RefCell;
+    use std::fmt::Debug;
+
+    thread_local!(static DROPPED: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) });
+
+    #[derive(Debug)]
+    struct Droppable(usize);
+    impl Drop for Droppable {
+        fn drop(&mut self) {
+            DROPPED.with(|dropped| dropped.borrow_mut().push(self.0));
+        }
+    }
+
+    let mut stack = DynStack::<dyn Debug>::new();
+    for i in 0..6 {
+        dyn_push!(stack, Droppable(i));
+        dyn_push!(stack, i as u8);
+    }
+
+    stack.truncate(20);
+    assert_eq!(stack.len(), 12);
+    DROPPED.with(|dropped| assert!(dropped.borrow().is_empty()));
+
+    stack.truncate(5);
+    assert_eq!(stack.len(), 5);
+    DROPPED.with(|dropped| assert_eq!(*dropped.borrow(), [5, 4, 3]));
+    let strings: Vec<_> = stack.iter().map(|item| format!("{:?}", item)).collect();
+    assert_eq!(
+        strings,
+        ["Droppable(0)", "0", "Droppable(1)", "1", "Droppable(2)"]
+    );
+
+    stack.truncate(0);
+    assert!(stack.is_empty());
+    assert_eq!(stack.byte_len(), 0);
+    DROPPED.with(|dropped| assert_eq!(*dropped.borrow(), [5, 4, 3, 2, 1, 0]));
+}
+
+#[test]
+fn test_get_unchecked() {
+    let mut stack = DynStack::<dyn Fn() -> usize>::new();
+    for i in 0..50 {
+        dyn_push!(stack, move || i);
+        dyn_push!(stack, move || i as u8 as usize);
+    }
+
+    for i in 0..stack.len() {
+        let checked = stack.get(i).unwrap()();
+        assert_eq!(unsafe { stack.get_unchecked(i) }(), checked);
+        assert_eq!(unsafe { stack.get_unchecked_mut(i) }(), checked);
+    }
+}
+
+#[test]
+fn test_try_reserve() {
+    use std::fmt::Debug;
+
+    /// Only allows allocating up to `limit` bytes at once.
+    struct Limited {
+        limit: usize,
+    }
+    unsafe impl Allocator for Limited {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+            if layout.size() > self.limit {
+                return Err(AllocError);
+            }
+            Global.allocate(layout)
+        }
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            Global.deallocate(ptr, layout)
+        }
+    }
+
+    let mut stack = DynStack::<dyn Debug, _>::new_in(Limited { limit: 64 });
+    assert_eq!(
+        stack.try_reserve(65),
+        Err(TryReserveError::AllocFailed {
+            layout: Layout::from_size_align(128, 16).unwrap()
+        })
+    );
+    assert!(stack.as_ptr().is_null());
+    assert_eq!(stack.try_reserve(20), Ok(()));
+    assert_eq!(stack.capacity(), 32);
+
+    dyn_push!(stack, 1u8);
+    dyn_push!(stack, [2u64; 4]);
+    assert_eq!(stack.try_reserve(20), Ok(()));
+    assert_eq!(stack.capacity(), 64);
+    let ptr = stack.as_ptr();
+
+    // A failed reallocation leaves the stack untouched.
+    assert!(matches!(
+        stack.try_reserve(100),
+        Err(TryReserveError::AllocFailed { .. })
+    ));
+    assert_eq!(
+        stack.try_reserve(usize::MAX - 8),
+        Err(TryReserveError::CapacityOverflow)
+    );
+    assert_eq!(
+        stack.try_reserve(isize::MAX as usize),
+        Err(TryReserveError::CapacityOverflow)
+    );
+    assert_eq!(stack.capacity(), 64);
+    assert_eq!(stack.as_ptr(), ptr);
+    assert_eq!(format!("{:?}", stack), "[1, [2, 2, 2, 2]]");
+    stack.assert_invariants();
+}
+
+#[test]
+fn test_small_dyn_stack() {
+    use std::{cell::RefCell, fmt::Debug};
+
+    thread_local!(static DROPPED: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) });
+
+    #[derive(Debug)]
+    struct Item(usize);
+    impl Drop for Item {
+        fn drop(&mut self) {
+            DROPPED.with(|dropped| dropped.borrow_mut().push(self.0));
+        }
+    }
+    #[repr(align(32))]
+    #[derive(Debug)]
+    struct Aligned32(#[allow(dead_code)] u8);
+
+    let mut stack = SmallDynStack::<dyn Debug, 64>::new();
+    assert_eq!(dyn_push_indexed!(stack, Item(0)), 0);
+    dyn_push!(stack, 1u8);
+    dyn_push!(stack, Item(2));
+    assert!(!stack.spilled());
+    assert_eq!(stack.len(), 3);
+
+    // The items move along with the stack.
+    let mut stack = *Box::new(stack);
+    assert_eq!(format!("{:?}", stack), "[Item(0), 1, Item(2)]");
+    assert!(stack.remove_last());
+    DROPPED.with(|dropped| assert_eq!(*dropped.borrow(), [2]));
+    dyn_push!(stack, [3u16; 4]);
+    assert_eq!(format!("{:?}", stack.peek()), "Some([3, 3, 3, 3])");
+
+    // Too large to fit inline.
+    dyn_push!(stack, [4u64; 8]);
+    assert!(stack.spilled());
+    assert_eq!(
+        format!("{:?}", stack),
+        "[Item(0), 1, [3, 3, 3, 3], [4, 4, 4, 4, 4, 4, 4, 4]]"
+    );
+    drop(stack);
+    DROPPED.with(|dropped| assert_eq!(*dropped.borrow(), [2, 0]));
+
+    // Aligned too strictly to be stored inline.
+    let mut stack = SmallDynStack::<dyn Debug, 64>::new();
+    dyn_push!(stack, Aligned32(5));
+    assert!(stack.spilled());
+    assert_eq!(&stack[0] as *const dyn Debug as *const u8 as usize % 32, 0);
+
+    let mut stack = SmallDynStack::<dyn Debug, 16>::new();
+    dyn_push!(stack, Item(6));
+    dyn_push!(stack, "seven");
+    let stack = stack.into_dyn_stack();
+    assert_eq!(format!("{:?}", stack), r#"[Item(6), "seven"]"#);
+    stack.assert_invariants();
+}
+
+#[test]
+fn test_allocator() {
+    use core::cell::Cell;
+    use std::fmt::Debug;
+
+    #[derive(Default)]
+    struct Counting {
+        allocated: Cell<usize>,
+        live: Cell<usize>,
+    }
+    unsafe impl Allocator for &Counting {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+            self.allocated.set(self.allocated.get() + 1);
+            self.live.set(self.live.get() + 1);
+            Global.allocate(layout)
+        }
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            self.live.set(self.live.get() - 1);
+            Global.deallocate(ptr, layout)
+        }
+    }
+
+    let counting = Counting::default();
+    {
+        let mut stack = DynStack::<dyn Debug, _>::new_in(&counting);
+        assert_eq!(counting.allocated.get(), 0);
+        for i in 0..100u32 {
+            dyn_push!(stack, i);
+        }
+        assert!(counting.allocated.get() > 1);
+        assert_eq!(counting.live.get(), 1);
+        assert_eq!(format!("{:?}", &stack[99]), "99");
+    }
+    assert_eq!(counting.live.get(), 0);
+
+    let allocated = counting.allocated.get();
+    {
+        let mut stack = DynStack::<dyn Debug, _>::with_capacity_in(512, &counting);
+        for i in 0..100u32 {
+            dyn_push!(stack, i);
+        }
+    }
+    assert_eq!(counting.allocated.get(), allocated + 1);
+    assert_eq!(counting.live.get(), 0);
+}
+
+#[test]
+fn test_try_push() {
+    use std::fmt::Debug;
+
+    /// Only allows allocating up to `limit` bytes at once.
+    struct Limited {
+        limit: usize,
+    }
+    unsafe impl Allocator for Limited {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+            if layout.size() > self.limit {
+                return Err(AllocError);
+            }
+            Global.allocate(layout)
+        }
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            Global.deallocate(ptr, layout)
+        }
+    }
+
+    let mut stack = DynStack::<dyn Debug, _>::new_in(Limited { limit: 32 });
+    let mut big = core::mem::ManuallyDrop::new([0u8; 64]);
+    assert_eq!(
+        unsafe { stack.try_push(&mut *big) },
+        Err(PushError::AllocFailed)
+    );
+    assert!(stack.is_empty());
+    assert!(stack.dyn_data.is_null());
+
+    for i in 0..4u64 {
+        let mut item = core::mem::ManuallyDrop::new(i);
+        assert_eq!(unsafe { stack.try_push(&mut *item) }, Ok(i as usize));
+    }
+    assert_eq!(stack.capacity(), 32);
+
+    let mut string = core::mem::ManuallyDrop::new(String::from("too much"));
+    assert_eq!(
+        unsafe { stack.try_push(&mut *string) },
+        Err(PushError::AllocFailed)
+    );
+    unsafe { core::mem::ManuallyDrop::drop(&mut string) };
+
+    assert_eq!(stack.len(), 4);
+    assert_eq!(stack.capacity(), 32);
+    let strings: Vec<_> = stack.iter().map(|item| format!("{:?}", item)).collect();
+    assert_eq!(strings, ["0", "1", "2", "3"]);
+}
+
+#[test]
+fn test_first_push_aligned() {
+    use std::fmt::Debug;
+
+    let mut stack = DynStack::<dyn Debug>::new();
+    dyn_push!(stack, Aligned64(1));
+    stack.assert_invariants();
+    assert_eq!(stack.offsets()[0].0, 0);
+    assert_eq!(stack.as_ptr() as usize % 64, 0);
+    assert_eq!(stack.max_alignment(), 64);
+    assert_eq!(stack.byte_len(), 64);
+
+    // Zero-sized items too.
+    let mut stack = DynStack::<dyn Debug>::new();
+    dyn_push!(stack, [0u128; 0]);
+    stack.assert_invariants();
+    assert_eq!(stack.byte_len(), 0);
+
+    // A failed allocation leaves the alignment as it was.
+    struct Failing;
+    unsafe impl Allocator for Failing {
+        fn allocate(&self, _: Layout) -> Result<NonNull<u8>, AllocError> {
+            Err(AllocError)
+        }
+        unsafe fn deallocate(&self, _: NonNull<u8>, _: Layout) {}
+    }
+    let mut stack = DynStack::<dyn Debug, _>::new_in(Failing);
+    let mut item = core::mem::ManuallyDrop::new(Aligned64(2));
+    assert_eq!(
+        unsafe { stack.try_push(&mut *item) },
+        Err(PushError::AllocFailed)
+    );
+    assert_eq!(stack.max_alignment(), 16);
+}
+
+#[test]
+fn test_extend() {
+    use std::fmt::Debug;
+
+    let mut stack = DynStack::<dyn Debug>::new();
+    dyn_push!(stack, 0u8);
+    let boxes: Vec<Box<dyn Debug>> = vec![
+        Box::new(String::from("one")),
+        Box::new(()),
+        Box::new([2u64; 2]),
+    ];
+    stack.extend(boxes);
+    stack.extend(core::iter::once(Box::new(3i32) as Box<dyn Debug>));
+
+    let items: Vec<String> = stack.iter().map(|item| format!("{:?}", item)).collect();
+    assert_eq!(items, ["0", "\"one\"", "()", "[2, 2]", "3"]);
+}
+
+#[test]
+fn test_debug() {
+    use std::fmt::Debug;
+    let mut stack = DynStack::<dyn Debug>::new();
+    assert_eq!(format!("{:?}", stack), "[]");
+
+    dyn_push!(stack, 1u8);
+    dyn_push!(stack, "two");
+    dyn_push!(stack, [3u16, 4]);
+    assert_eq!(format!("{:?}", stack), "[1, \"two\", [3, 4]]");
+}
+
+#[test]
+fn test_eq() {
+    use std::fmt::Debug;
+
+    trait Value: Debug {
+        fn get(&self) -> u64;
+    }
+    impl Value for u8 {
+        fn get(&self) -> u64 {
+            *self as u64
+        }
+    }
+    impl Value for u64 {
+        fn get(&self) -> u64 {
+            *self
+        }
+    }
+    impl PartialEq for dyn Value {
+        fn eq(&self, other: &Self) -> bool {
+            self.get() == other.get()
+        }
+    }
+
+    let mut a = DynStack::<dyn Value>::new();
+    let mut b = DynStack::<dyn Value>::new();
+    assert_eq!(a, b);
+
+    dyn_push!(a, 1u8);
+    dyn_push!(a, 2u64);
+    // Different types and padding, but equal values.
+    dyn_push!(b, 1u64);
+    dyn_push!(b, 2u8);
+    assert_eq!(a, b);
+    assert!(a.byte_len() != b.byte_len());
+
+    dyn_push!(b, 3u8);
+    assert_ne!(a, b);
+    dyn_push!(a, 4u8);
+    assert_ne!(a, b);
+    a.remove_last();
+    dyn_push!(a, 3u64);
+    assert_eq!(a, b);
+}
+
+#[test]
+fn test_hash() {
+    use std::{collections::HashSet, fmt::Debug};
+
+    trait Value: Debug {
+        fn get(&self) -> u64;
+    }
+    impl Value for u8 {
+        fn get(&self) -> u64 {
+            *self as u64
+        }
+    }
+    impl Value for u64 {
+        fn get(&self) -> u64 {
+            *self
+        }
+    }
+    impl PartialEq for dyn Value {
+        fn eq(&self, other: &Self) -> bool {
+            self.get() == other.get()
+        }
+    }
+    impl Eq for dyn Value {}
+    impl Hash for dyn Value {
+        fn hash<H: Hasher>(&self, state: &mut H) {
+            self.get().hash(state);
+        }
+    }
+
+    let mut a = DynStack::<dyn Value>::new();
+    dyn_push!(a, 1u8);
+    dyn_push!(a, 2u64);
+    // Different types and padding, but equal values.
+    let mut b = DynStack::<dyn Value>::new();
+    dyn_push!(b, 1u64);
+    dyn_push!(b, 2u8);
+    let mut c = DynStack::<dyn Value>::new();
+    dyn_push!(c, 2u64);
+    dyn_push!(c, 1u8);
+
+    // The checkpoint id is the only interior mutability, and isn't hashed.
+    #[allow(clippy::mutable_key_type)]
+    let mut set = HashSet::new();
+    assert!(set.insert(a));
+    assert!(!set.insert(b));
+    assert!(set.insert(c));
+    assert!(set.insert(DynStack::new()));
+    assert_eq!(set.len(), 3);
+}
+
+#[test]
+fn test_dyn_extend() {
+    use std::fmt::Debug;
+    let mut extended = DynStack::<dyn Debug>::new();
+    let mut pushed = DynStack::<dyn Debug>::new();
+
+    dyn_extend!(extended, 0..1000usize);
+    for i in 0..1000usize {
+        dyn_push!(pushed, i);
+    }
+    assert_eq!(extended.capacity(), 8192);
+    assert_eq!(format!("{:?}", extended), format!("{:?}", pushed));
+
+    dyn_extend!(extended, vec![String::from("a"), String::from("b")]);
+    dyn_extend!(extended, core::iter::empty::<u8>());
+    assert_eq!(extended.len(), 1002);
+    assert_eq!(format!("{:?}", &extended[1001]), "\"b\"");
+
+    // The stack expression is only evaluated once.
+    let mut stacks = [DynStack::<dyn Debug>::new(), DynStack::new()];
+    let mut stacks_iter = stacks.iter_mut();
+    dyn_extend!(stacks_iter.next().unwrap(), 0..3u8);
+    assert_eq!(stacks[0].len(), 3);
+    assert!(stacks[1].is_empty());
+
+    // The stack can also be a mutable reference.
+    fn extend(stack: &mut DynStack<dyn Debug>) {
+        dyn_extend!(stack, 3..5u8);
+    }
+    extend(&mut stacks[0]);
+    assert_eq!(format!("{:?}", stacks[0]), "[0, 1, 2, 3, 4]");
+}
+
+#[test]
+fn test_from_iter() {
+    use std::fmt::Debug;
+    let stack: DynStack<dyn Debug> = (0..10u32)
+        .map(|i| -> Box<dyn Debug> {
+            if i % 2 == 0 {
+                Box::new(i)
+            } else {
+                Box::new(i.to_string())
+            }
+        })
+        .collect();
+    assert_eq!(
+        format!("{:?}", stack),
+        r#"[0, "1", 2, "3", 4, "5", 6, "7", 8, "9"]"#
+    );
+}
+
+#[test]
+fn test_retain() {
+    use std::cell::Cell;
+
+    thread_local!(static DROPS: Cell<usize> = const { Cell::new(0) });
+
+    #[repr(align(32))]
+    struct Aligned32(u32);
+    struct Counted(u32);
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPS.with(|drops| drops.set(drops.get() + 1));
+        }
+    }
+
+    impl Item for Aligned32 {
+        fn value(&self) -> u32 {
+            self.0
+        }
+    }
+    impl Item for Counted {
+        fn value(&self) -> u32 {
+            self.0
+        }
+    }
+
+    let mut stack = DynStack::<dyn Item>::new();
+    for i in 0..40u32 {
+        match i % 4 {
+            0 => dyn_push!(stack, i as u8),
+            1 => dyn_push!(stack, Aligned32(i)),
+            2 => dyn_push!(stack, Counted(i)),
+            _ => dyn_push!(stack, i as u64),
+        }
+    }
+    let size = stack.byte_len();
+
+    let mut visited = Vec::new();
+    stack.retain(|item| {
+        visited.push(item.value());
+        item.value() % 3 == 0
+    });
+    assert_eq!(visited, (0..40).collect::<Vec<_>>());
+    assert!(stack.byte_len() < size);
+
+    let expected: Vec<u32> = (0..40).filter(|i| i % 3 == 0).collect();
+    let values: Vec<u32> = stack.iter().map(|item| item.value()).collect();
+    assert_eq!(values, expected);
+    for item in stack.iter() {
+        let thin_ptr = item as *const dyn Item as *const () as usize;
+        assert_eq!(thin_ptr & (item.alignment() - 1), 0);
+    }
+    // Counted items were pushed for 2, 6, 10, ..., 38; 6, 18 and 30 were retained.
+    DROPS.with(|drops| assert_eq!(drops.get(), 7));
+
+    // Pushing after retaining must not overwrite the retained items.
+    dyn_push!(stack, Aligned32(100));
+    let values: Vec<u32> = stack.iter().map(|item| item.value()).collect();
+    assert_eq!(values.last(), Some(&100));
+    assert_eq!(values[..values.len() - 1], expected[..]);
+
+    stack.retain(|_| false);
+    assert!(stack.is_empty());
+    assert_eq!(stack.byte_len(), 0);
+    DROPS.with(|drops| assert_eq!(drops.get(), 10));
+}
+
+#[test]
+fn test_retain_panic() {
+    use std::{
+        cell::RefCell,
+        panic::{catch_unwind, AssertUnwindSafe},
+    };
+
+    thread_local!(static DROPPED: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) });
+
+    trait Id {
+        fn id(&self) -> usize;
+    }
+    /// Panics when dropped if `panics` is set.
+    struct Item {
+        id: usize,
+        panics: bool,
+    }
+    impl Id for Item {
+        fn id(&self) -> usize {
+            self.id
+        }
+    }
+    impl Drop for Item {
+        fn drop(&mut self) {
+            DROPPED.with(|dropped| dropped.borrow_mut().push(self.id));
+            if self.panics {
+                panic!("drop {}", self.id);
+            }
+        }
+    }
+    fn ids(stack: &DynStack<dyn Id>) -> Vec<usize> {
+        stack.iter().map(|item| item.id()).collect()
+    }
+    let dropped = || DROPPED.with(|dropped| dropped.borrow_mut().split_off(0));
+
+    let mut stack = DynStack::<dyn Id>::new();
+    for id in 0..5 {
+        dyn_push!(
+            stack,
+            Item {
+                id,
+                panics: id == 1
+            }
+        );
+    }
+    // Item 1 is removed even though its destructor panics, and the items after it are kept.
+    let result = catch_unwind(AssertUnwindSafe(|| stack.retain(|item| item.id() != 1)));
+    assert!(result.is_err());
+    assert_eq!(ids(&stack), [0, 2, 3, 4]);
+    assert_eq!(dropped(), [1]);
+    stack.assert_invariants();
+
+    // A panicking predicate keeps the item it panicked on, and the ones after it.
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        stack.retain(|item| match item.id() {
+            0 => false,
+            3 => panic!("predicate"),
+            _ => true,
+        })
+    }));
+    assert!(result.is_err());
+    assert_eq!(ids(&stack), [2, 3, 4]);
+    assert_eq!(dropped(), [0]);
+    stack.assert_invariants();
+
+    // The same goes for `retain_mut`, which shares the implementation.
+    dyn_push!(
+        stack,
+        Item {
+            id: 5,
+            panics: true
+        }
+    );
+    dyn_push!(
+        stack,
+        Item {
+            id: 6,
+            panics: false
+        }
+    );
+    let result = catch_unwind(AssertUnwindSafe(|| {
+        stack.retain_mut(|item| item.id() != 3 && item.id() != 5)
+    }));
+    assert!(result.is_err());
+    assert_eq!(ids(&stack), [2, 4, 6]);
+    assert_eq!(dropped(), [3, 5]);
+    stack.assert_invariants();
+
+    drop(stack);
+    assert_eq!(dropped(), [6, 4, 2]);
+}
+
+#[test]
+fn test_retain_mut() {
+    trait Ttl {
+        fn ttl(&self) -> u32;
+        fn tick(&mut self);
+    }
+    struct Short(u8);
+    struct Long(u64, #[allow(dead_code)] [u64; 3]);
+    impl Ttl for Short {
+        fn ttl(&self) -> u32 {
+            self.0 as u32
+        }
+        fn tick(&mut self) {
+            self.0 -= 1;
+        }
+    }
+    impl Ttl for Long {
+        fn ttl(&self) -> u32 {
+            self.0 as u32
+        }
+        fn tick(&mut self) {
+            self.0 -= 1;
+        }
+    }
+
+    let mut stack = DynStack::<dyn Ttl>::new();
+    dyn_push!(stack, Short(1));
+    dyn_push!(stack, Long(3, [0; 3]));
+    dyn_push!(stack, Short(2));
+    dyn_push!(stack, Long(1, [0; 3]));
+    dyn_push!(stack, Short(5));
+
+    let mut visited = 0;
+    stack.retain_mut(|item| {
+        visited += 1;
+        item.tick();
+        item.ttl() > 0
+    });
+    assert_eq!(visited, 5);
+    let ttls: Vec<u32> = stack.iter().map(|item| item.ttl()).collect();
+    assert_eq!(ttls, [2, 1, 4]);
+    // The remaining items are packed down: 32 bytes, then 1 and 1 byte.
+    assert_eq!(stack.byte_len(), 34);
+    stack.assert_invariants();
+
+    stack.retain_mut(|item| {
+        item.tick();
+        item.ttl() > 0
+    });
+    let ttls: Vec<u32> = stack.iter().map(|item| item.ttl()).collect();
+    assert_eq!(ttls, [1, 3]);
+    stack.assert_invariants();
+}
+
+#[test]
+fn test_retain_unsorted() {
+    use std::fmt::Debug;
+    let mut stack = DynStack::<dyn Debug>::new();
+    dyn_push!(stack, 0u8);
+    dyn_push!(stack, [1u64; 4]);
+    dyn_push!(stack, 2u16);
+    dyn_push!(stack, [3u64; 2]);
+
+    // The last array doesn't fit where the u8 was, so the table becomes unsorted.
+    assert!(stack.swap_remove(0));
+    assert!(!stack.offs_sorted);
+
+    stack.retain(|item| format!("{:?}", item) != "2");
+    assert_eq!(format!("{:?}", stack), "[[3, 3], [1, 1, 1, 1]]");
+    assert_eq!(stack.byte_len(), 48);
+}
+
+#[test]
+fn test_compact() {
+    use std::fmt::Debug;
+
+    #[repr(align(32))]
+    #[derive(Debug)]
+    struct Aligned32(#[allow(dead_code)] u8);
+
+    let mut stack = DynStack::<dyn Debug>::new_zeroed();
+    for i in 0..40u8 {
+        match i % 4 {
+            0 => dyn_push!(stack, i),
+            1 => dyn_push!(stack, [i as u64; 3]),
+            2 => dyn_push!(stack, Aligned32(i)),
+            _ => dyn_push!(stack, i as u16),
+        }
+    }
+    for i in 0..20 {
+        stack.swap_remove(i);
+    }
+    assert!(!stack.offs_sorted);
+    let before: Vec<String> = stack.iter().map(|item| format!("{:?}", item)).collect();
+    let byte_len = stack.byte_len();
+
+    stack.compact();
+    stack.assert_invariants();
+    assert!(stack.offs_sorted);
+    assert!(stack.byte_len() < byte_len);
+    let after: Vec<String> = stack.iter().map(|item| format!("{:?}", item)).collect();
+    assert_eq!(after, before);
+    // The items are in stack order in the buffer.
+    assert!(stack.offsets().windows(2).all(|pair| pair[0].0 < pair[1].0));
+
+    // Compacting a packed stack changes nothing.
+    let offsets = stack.offsets().to_vec();
+    stack.compact();
+    assert_eq!(stack.offsets(), &offsets[..]);
+    while stack.remove_last() {
+        stack.assert_invariants();
+    }
+    assert_eq!(stack.byte_len(), 0);
+}
+
+#[test]
+fn test_split_off() {
+    let mut stack = DynStack::<dyn Item>::new();
+    for i in 0..10u16 {
+        dyn_push!(stack, i);
+    }
+    for i in 10..14 {
+        dyn_push!(stack, Aligned64(i));
+    }
+
+    let mut tail = stack.split_off(8);
+    assert_eq!(stack.len(), 8);
+    // The head no longer holds any of the over-aligned items' bytes.
+    assert_eq!(stack.byte_len(), stack.offs_table[7].0 + 2);
+    // Its buffer grew to 64-byte alignment for them though, and stays that way.
+    assert_eq!(stack.buf_align, 64);
+    assert_eq!(stack.max_align, 64);
+    stack.assert_invariants();
+    assert_eq!(tail.len(), 6);
+    assert_eq!(tail.max_align, 64);
+    tail.assert_invariants();
+    let head_values: Vec<u32> = stack.iter().map(|item| item.value()).collect();
+    let tail_values: Vec<u32> = tail.iter().map(|item| item.value()).collect();
+    assert_eq!(head_values, (0..8).collect::<Vec<_>>());
+    assert_eq!(tail_values, (8..14).collect::<Vec<_>>());
+    for item in tail.iter().skip(2) {
+        assert_eq!(item as *const dyn Item as *const () as usize % 64, 0);
+    }
+
+    // Both halves stay usable.
+    dyn_push!(stack, 100u16);
+    dyn_push!(tail, Aligned64(200));
+    assert_eq!(stack[8].value(), 100);
+    assert_eq!(tail[6].value(), 200);
+
+    let empty = tail.split_off(7);
+    assert!(empty.is_empty());
+    let all = tail.split_off(0);
+    assert!(tail.is_empty());
+    assert_eq!(tail.byte_len(), 0);
+    assert_eq!(all.len(), 7);
+    tail.assert_invariants();
+
+    // Over-alignment requested when pushing is kept too.
+    let mut stack = DynStack::<dyn Item>::new();
+    unsafe { stack.push_with_align(&mut 1u16, 128) };
+    dyn_push!(stack, 2u16);
+    drop(stack.split_off(1));
+    assert_eq!(stack.max_align, 128);
+    stack.assert_invariants();
+    dyn_push!(stack, 3u16);
+    stack.reserve(1000);
+    assert_eq!(stack.as_ptr() as usize % 128, 0);
+    stack.assert_invariants();
+}
+
+#[test]
+#[should_panic]
+fn test_split_off_out_of_bounds() {
+    let mut stack = DynStack::<dyn core::fmt::Debug>::new();
+    dyn_push!(stack, 1u8);
+    stack.split_off(2);
+}
+
+#[test]
+#[cfg(feature = "rayon")]
+fn test_par_iter() {
+    use rayon::prelude::*;
+
+    trait AsUsize: Send + Sync {
+        fn make(&self) -> usize;
+        fn bump(&mut self);
+    }
+    impl AsUsize for usize {
+        fn make(&self) -> usize {
+            *self
+        }
+        fn bump(&mut self) {
+            *self += 1;
+        }
+    }
+    #[repr(align(32))]
+    struct Aligned32(u8);
+    impl AsUsize for Aligned32 {
+        fn make(&self) -> usize {
+            self.0 as usize
+        }
+        fn bump(&mut self) {
+            self.0 += 1;
+        }
     }
 
-    /// Retrieve the mutable trait object reference at the top of the stack.
-    pub fn peek_mut<'a>(&'a mut self) -> Option<&'a mut T> {
-        let index = self.len().wrapping_sub(1);
-        self.get_mut(index)
+    let mut stack = DynStack::<dyn AsUsize>::new();
+    for i in 0..1000 {
+        if i % 3 == 0 {
+            dyn_push!(stack, Aligned32((i % 100) as u8));
+        } else {
+            dyn_push!(stack, i);
+        }
     }
 
-    /// Returns the number of trait objects stored on the stack.
-    pub fn len(&self) -> usize {
-        self.offs_table.len()
+    let serial: usize = stack.iter().map(|item| item.make()).sum();
+    let parallel: usize = stack.par_iter().map(|item| item.make()).sum();
+    assert_eq!(parallel, serial);
+    assert_eq!(stack.par_iter().len(), 1000);
+
+    stack.par_iter_mut().for_each(|item| item.bump());
+    let bumped: usize = stack.par_iter().map(|item| item.make()).sum();
+    assert_eq!(bumped, serial + 1000);
+
+    let collected: Vec<usize> = stack.par_iter().map(|item| item.make()).collect();
+    let expected: Vec<usize> = stack.iter().map(|item| item.make()).collect();
+    assert_eq!(collected, expected);
+}
+
+#[test]
+#[cfg(feature = "serde")]
+fn test_serialize() {
+    let mut stack = DynStack::<dyn erased_serde::Serialize>::new();
+    dyn_push!(stack, 1u8);
+    dyn_push!(stack, "two");
+    dyn_push!(stack, [3.5f64, 4.0]);
+    dyn_push!(stack, Some(5u64));
+    assert_eq!(
+        serde_json::to_string(&stack).unwrap(),
+        r#"[1,"two",[3.5,4.0],5]"#
+    );
+
+    let values = vec![1u32, 2, 3];
+    let mut stack = DynStack::<dyn erased_serde::Serialize>::new();
+    for &value in &values {
+        dyn_push!(stack, value);
     }
+    let json = serde_json::to_string(&stack).unwrap();
+    assert_eq!(json, serde_json::to_string(&values).unwrap());
+    assert_eq!(serde_json::from_str::<Vec<u32>>(&json).unwrap(), values);
+
+    let empty = DynStack::<dyn erased_serde::Serialize>::new();
+    assert_eq!(serde_json::to_string(&empty).unwrap(), "[]");
 }
 
-impl<'a, T: 'a + ?Sized> DynStack<T> {
-    /// Returns an iterator over trait object references
-    pub fn iter(&'a self) -> DynStackIter<'a, T> {
-        DynStackIter {
-            stack: self,
-            index: 0,
+#[test]
+#[cfg(feature = "dyn-clone")]
+fn test_clone() {
+    use core::cell::Cell;
+    use std::{fmt::Debug, rc::Rc};
+
+    trait Counter: dyn_clone::DynClone + Debug {
+        fn bump(&mut self);
+    }
+    #[derive(Clone, Debug)]
+    struct Count(u32);
+    impl Counter for Count {
+        fn bump(&mut self) {
+            self.0 += 1;
         }
     }
-
-    /// Returns an iterator over mutable trait object references
-    pub fn iter_mut(&'a mut self) -> DynStackIterMut<'a, T> {
-        DynStackIterMut {
-            stack: self,
-            index: 0,
-            _spooky: PhantomData,
+    #[derive(Clone, Debug)]
+    struct Named(String, Rc<Cell<u32>>);
+    impl Counter for Named {
+        fn bump(&mut self) {
+            self.0.push('!');
+            self.1.set(self.1.get() + 1);
         }
     }
+
+    let shared = Rc::new(Cell::new(0));
+    let mut stack = DynStack::<dyn Counter>::new_zeroed();
+    dyn_push!(stack, Count(1));
+    dyn_push!(stack, Named("two".to_string(), shared.clone()));
+    dyn_push!(stack, Count(3));
+
+    let clone = stack.clone();
+    assert!(clone.zero_padding);
+    assert_eq!(Rc::strong_count(&shared), 3);
+    for item in stack.iter_mut() {
+        item.bump();
+    }
+    assert_eq!(
+        format!("{:?}", clone),
+        r#"[Count(1), Named("two", Cell { value: 1 }), Count(3)]"#
+    );
+    assert_eq!(
+        format!("{:?}", stack),
+        r#"[Count(2), Named("two!", Cell { value: 1 }), Count(4)]"#
+    );
+
+    drop(stack);
+    assert_eq!(Rc::strong_count(&shared), 2);
+    drop(clone);
+    assert_eq!(Rc::strong_count(&shared), 1);
 }
 
-impl<T: ?Sized> Index<usize> for DynStack<T> {
-    type Output = T;
+#[test]
+#[cfg(feature = "type-ids")]
+fn test_type_ids() {
+    use core::any::TypeId;
+    use std::fmt::Debug;
 
-    fn index(&self, idx: usize) -> &T {
-        self.get(idx).unwrap()
+    let mut stack = DynStack::<dyn Debug>::new();
+    dyn_push_typed!(stack, 1u32);
+    dyn_push_typed!(stack, String::from("two"));
+    dyn_push_typed!(stack, [3u8; 3]);
+    stack.push_box(Box::new(4u64));
+    dyn_push_typed!(stack, "five");
+
+    assert_eq!(stack.type_id_at(0), Some(TypeId::of::<u32>()));
+    assert_eq!(stack.type_id_at(1), Some(TypeId::of::<String>()));
+    assert_eq!(stack.type_id_at(3), None);
+    assert_eq!(stack.type_id_at(5), None);
+    assert!(stack.is::<[u8; 3]>(2));
+    assert!(!stack.is::<[u8; 4]>(2));
+    assert!(!stack.is::<u64>(3));
+    assert!(stack.is::<&str>(4));
+
+    // The type ids follow the items around.
+    stack.swap(0, 1);
+    assert!(stack.is::<String>(0) && stack.is::<u32>(1));
+    stack.rotate_left(1);
+    assert!(stack.is::<u32>(0) && stack.is::<String>(4));
+    stack.rotate_right(1);
+    stack.sort_by_key(|item| format!("{:?}", item));
+    assert_eq!(
+        format!("{:?}", stack),
+        r#"["five", "two", 1, 4, [3, 3, 3]]"#
+    );
+    assert!(stack.is::<&str>(0) && stack.is::<String>(1) && stack.is::<u32>(2));
+    stack.swap_remove(0);
+    assert!(stack.is::<[u8; 3]>(0));
+    stack.retain(|item| format!("{:?}", item) != "4");
+    assert!(stack.is::<[u8; 3]>(0) && stack.is::<String>(1) && stack.is::<u32>(2));
+    stack.assert_invariants();
+
+    // Replacing an item forgets its type.
+    dyn_replace!(stack, 2, 8i32).unwrap();
+    assert_eq!(stack.type_id_at(2), None);
+
+    let other = stack.split_off(1);
+    assert!(other.is::<String>(0) && other.type_id_at(1).is_none());
+    other.assert_invariants();
+    stack.remove_last();
+    assert_eq!(stack.type_id_at(0), None);
+    stack.assert_invariants();
+
+    let mut small = SmallDynStack::<dyn Debug, 16>::new();
+    assert_eq!(dyn_push_typed!(small, 6u8), 0);
+    dyn_push_typed!(small, [7u64; 4]);
+    let stack = small.into_dyn_stack();
+    assert!(stack.is::<u8>(0) && stack.is::<[u64; 4]>(1));
+
+    // The other macros still accept borrowed items.
+    let local = String::from("borrowed");
+    let mut stack = DynStack::<dyn Debug + '_>::new();
+    dyn_push!(stack, &local);
+    dyn_push_indexed!(stack, &local);
+    dyn_push_ref!(stack, &local);
+    dyn_replace!(stack, 0, &local).unwrap();
+    dyn_extend!(stack, core::iter::once(&local));
+    assert_eq!(stack.len(), 4);
+    assert_eq!(stack.type_id_at(0), None);
+}
+
+#[test]
+#[cfg(feature = "type-ids")]
+fn test_get_as() {
+    use std::fmt::Debug;
+
+    let mut stack = DynStack::<dyn Debug>::new();
+    dyn_push_typed!(stack, 1u32);
+    dyn_push_typed!(stack, String::from("two"));
+    stack.push_box(Box::new(3u32));
+
+    assert_eq!(stack.get_as::<u32>(0), Some(&1));
+    assert_eq!(stack.get_as::<String>(1).map(String::as_str), Some("two"));
+    assert_eq!(stack.get_as::<String>(0), None);
+    assert_eq!(stack.get_as::<u32>(1), None);
+    assert_eq!(stack.get_as::<&str>(1), None);
+    // The type isn't recorded, so it can't be checked.
+    assert_eq!(stack.get_as::<u32>(2), None);
+    assert_eq!(stack.get_as::<u32>(3), None);
+
+    *stack.get_as_mut::<u32>(0).unwrap() += 10;
+    stack.get_as_mut::<String>(1).unwrap().push_str(" more");
+    assert!(stack.get_as_mut::<u64>(0).is_none());
+    assert_eq!(format!("{:?}", stack), r#"[11, "two more", 3]"#);
+}
+
+#[test]
+fn test_push_with_align() {
+    use std::fmt::Debug;
+
+    fn addr(item: &dyn Debug) -> usize {
+        item as *const dyn Debug as *const u8 as usize
+    }
+
+    let mut stack = DynStack::<dyn Debug>::new();
+    dyn_push!(stack, 1u8);
+    let mut item = 2u32;
+    let index = unsafe { stack.push_with_align(&mut item, 64) };
+    assert_eq!(index, 1);
+    assert_eq!(addr(&stack[1]) % 64, 0);
+    assert_eq!(stack.max_alignment(), 64);
+
+    // A smaller override than the natural alignment has no effect.
+    let mut item = 3u64;
+    unsafe { stack.push_with_align(&mut item, 1) };
+    assert_eq!(stack.offsets()[2].0, stack.offsets()[1].0 + 8);
+
+    // Over-aligned items stay aligned when the buffer grows.
+    for i in 0..100u32 {
+        dyn_push!(stack, i);
     }
+    assert_eq!(addr(&stack[1]) % 64, 0);
+    assert_eq!(format!("{:?}", &stack[1]), "2");
+    stack.assert_invariants();
 }
 
-impl<T: ?Sized> IndexMut<usize> for DynStack<T> {
-    fn index_mut(&mut self, idx: usize) -> &mut T {
-        self.get_mut(idx).unwrap()
+#[test]
+#[should_panic(expected = "alignment must be a power of two")]
+fn test_push_with_align_invalid() {
+    let mut stack = DynStack::<dyn core::fmt::Debug>::new();
+    let mut item = 1u32;
+    unsafe { stack.push_with_align(&mut item, 48) };
+}
+
+#[test]
+fn test_relocate_hook() {
+    use std::{
+        fmt::Debug,
+        sync::{Arc, Mutex},
+    };
+
+    /// The buffer address and offset table the hook was called with.
+    type Calls = Vec<(usize, Vec<(usize, usize)>)>;
+
+    let calls: Arc<Mutex<Calls>> = Arc::default();
+    let mut stack = DynStack::<dyn Debug>::new();
+    let hook_calls = calls.clone();
+    stack.set_relocate_hook(move |data, offsets| {
+        hook_calls
+            .lock()
+            .unwrap()
+            .push((data as usize, offsets.to_vec()));
+    });
+    let take_calls = || calls.lock().unwrap().split_off(0);
+
+    // Allocating the first buffer doesn't move anything.
+    dyn_push!(stack, 1u64);
+    dyn_push!(stack, 2u64);
+    assert_eq!(take_calls(), []);
+
+    // Each grow notifies the hook once, with the new buffer, before the item is pushed.
+    dyn_push!(stack, 3u64);
+    assert_eq!(stack.capacity(), 32);
+    let grown = take_calls();
+    assert_eq!(
+        grown,
+        [(stack.as_ptr() as usize, stack.offsets()[..2].to_vec())]
+    );
+    for i in 0..4u64 {
+        dyn_push!(stack, [i; 2]);
     }
+    assert_eq!(stack.capacity(), 128);
+    assert_eq!(take_calls().len(), 2);
+
+    // The last item fits into the removed one's bytes.
+    stack.swap_remove(3);
+    assert_eq!(take_calls().len(), 1);
+    stack.swap(0, 1);
+    stack.compact();
+    assert_eq!(
+        take_calls(),
+        [(stack.as_ptr() as usize, stack.offsets().to_vec())]
+    );
+    assert_eq!(format!("{:?}", stack), "[2, 1, 3, [3, 3], [1, 1], [2, 2]]");
+    stack.retain(|item| format!("{:?}", item) != "1");
+    assert_eq!(take_calls().len(), 1);
+    // Nothing moves if the last item is removed.
+    stack.retain(|item| format!("{:?}", item) != "[2, 2]");
+    assert_eq!(take_calls(), []);
+
+    stack.remove_relocate_hook();
+    stack.shrink_to_fit();
+    assert_eq!(take_calls(), []);
 }
 
-impl<'a, T: 'a + ?Sized> IntoIterator for &'a DynStack<T> {
-    type Item = &'a T;
-    type IntoIter = DynStackIter<'a, T>;
+#[test]
+fn test_push_zst() {
+    use std::fmt::Debug;
 
-    fn into_iter(self) -> Self::IntoIter {
-        self.iter()
+    #[derive(Debug)]
+    struct Zst;
+
+    let mut stack = DynStack::<dyn Debug>::new();
+    for _ in 0..10000 {
+        dyn_push!(stack, Zst);
     }
+    assert_eq!(stack.len(), 10000);
+    assert_eq!(stack.capacity(), 16);
+    assert_eq!(stack.byte_len(), 0);
+
+    // A full buffer still has room for zero-sized items.
+    dyn_push!(stack, [0u8; 16]);
+    dyn_push!(stack, Zst);
+    assert_eq!(stack.capacity(), 16);
+    assert_eq!(stack.byte_len(), 16);
+    assert_eq!(format!("{:?}", stack.peek().unwrap()), "Zst");
 }
 
-impl<'a, T: 'a + ?Sized> IntoIterator for &'a mut DynStack<T> {
-    type Item = &'a mut T;
-    type IntoIter = DynStackIterMut<'a, T>;
+#[test]
+fn test_resize_realigns_moved_buffer() {
+    use std::fmt::Debug;
 
-    fn into_iter(self) -> Self::IntoIter {
-        self.iter_mut()
+    /// Places 16-byte aligned buffers 16 bytes past a 64-byte boundary.
+    struct Misaligned;
+    unsafe impl Allocator for Misaligned {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+            let padded = Layout::from_size_align(layout.size() + 64, 64).unwrap();
+            let ptr = Global.allocate(padded)?.as_ptr();
+            let offs = if layout.align() < 64 { 16 } else { 0 };
+            Ok(unsafe { NonNull::new_unchecked(ptr.add(offs)) })
+        }
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            let padded = Layout::from_size_align(layout.size() + 64, 64).unwrap();
+            let offs = if layout.align() < 64 { 16 } else { 0 };
+            Global.deallocate(NonNull::new_unchecked(ptr.as_ptr().sub(offs)), padded)
+        }
     }
+
+    let mut stack = DynStack::<dyn Debug, _>::new_in(Misaligned);
+    dyn_push!(stack, 1u8);
+    dyn_push!(stack, Aligned64(2));
+    let (data, offs) = (stack.as_ptr(), stack.offsets()[1].0);
+    assert_eq!(data as usize % 64, 16);
+    assert_eq!(offs, 48);
+
+    // Test builds always move the buffer when resizing it, so the contents must be realigned.
+    stack.reserve(stack.capacity() + 1);
+    assert_ne!(stack.as_ptr(), data);
+    assert_eq!(stack.as_ptr() as usize % 64, 0);
+    assert_eq!(stack.offsets()[1].0 % 64, 0);
+    assert_eq!(format!("{:?}", stack), "[1, Aligned64(2)]");
+    stack.assert_invariants();
 }
 
-impl<T: ?Sized> Drop for DynStack<T> {
-    fn drop(&mut self) {
-        while self.remove_last() {}
-        unsafe { dealloc(self.dyn_data, self.layout()) }
+#[test]
+fn test_iter_order_across_grow() {
+    use std::fmt::Debug;
+
+    #[repr(align(32))]
+    #[derive(Debug)]
+    struct Aligned32(#[allow(dead_code)] u16);
+
+    /// Places buffers 48 bytes past a 64-byte boundary, when their alignment allows it.
+    struct Misaligned;
+    impl Misaligned {
+        fn padded(layout: Layout) -> (Layout, usize) {
+            let padded = Layout::from_size_align(layout.size() + 64, 64).unwrap();
+            (padded, 64 - layout.align().min(64))
+        }
+    }
+    unsafe impl Allocator for Misaligned {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+            let (padded, offs) = Self::padded(layout);
+            let ptr = Global.allocate(padded)?;
+            Ok(unsafe { NonNull::new_unchecked(ptr.as_ptr().add(offs)) })
+        }
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            let (padded, offs) = Self::padded(layout);
+            Global.deallocate(NonNull::new_unchecked(ptr.as_ptr().sub(offs)), padded)
+        }
+    }
+
+    let mut stack = DynStack::<dyn Debug, _>::new_in(Misaligned);
+    let mut expected = Vec::new();
+    let mut grows = 0;
+    let mut realigned = false;
+    for i in 0..40u32 {
+        let (cap, offsets) = (stack.capacity(), stack.offsets().to_vec());
+        match i % 6 {
+            0 => dyn_push!(stack, i as u8),
+            1 => dyn_push!(stack, [i as u16; 3]),
+            2 => dyn_push!(stack, Aligned32(i as u16)),
+            3 => dyn_push!(stack, format!("item {}", i)),
+            4 => dyn_push!(stack, Aligned64(i)),
+            _ => dyn_push!(stack, [i as u64; 5]),
+        }
+        expected.push(format!("{:?}", stack.peek().unwrap()));
+        if stack.capacity() != cap {
+            grows += 1;
+            // The first buffers are misaligned for the items that need 32 or 64 bytes, so
+            // reallocating them has to move the contents.
+            realigned |= offsets[..] != stack.offsets()[..offsets.len()];
+        }
+        stack.assert_invariants();
     }
+    assert!(grows >= 4);
+    assert!(realigned);
+
+    let items: Vec<String> = stack.iter().map(|item| format!("{:?}", item)).collect();
+    assert_eq!(items, expected);
+    assert_eq!(items[2], "Aligned32(2)");
+    assert_eq!(items[39], "\"item 39\"");
 }
 
-/// Push an item onto the back of the specified stack
-#[macro_export]
-macro_rules! dyn_push {
-    { $stack:expr, $item:expr } => {{
-        let mut t = $item;
+#[test]
+fn test_push_aligned_zst() {
+    use std::fmt::Debug;
 
-        unsafe { $stack.push(&mut t) };
-        core::mem::forget(t);
-    }}
+    #[repr(align(64))]
+    #[derive(Debug)]
+    struct AlignedZst;
+
+    /// Places buffers 48 bytes past a 64-byte boundary, when their alignment allows it.
+    struct Misaligned;
+    impl Misaligned {
+        fn padded(layout: Layout) -> (Layout, usize) {
+            let padded = Layout::from_size_align(layout.size() + 64, 64).unwrap();
+            (padded, 64 - layout.align().min(64))
+        }
+    }
+    unsafe impl Allocator for Misaligned {
+        fn allocate(&self, layout: Layout) -> Result<NonNull<u8>, AllocError> {
+            let (padded, offs) = Self::padded(layout);
+            let ptr = Global.allocate(padded)?;
+            Ok(unsafe { NonNull::new_unchecked(ptr.as_ptr().add(offs)) })
+        }
+        unsafe fn deallocate(&self, ptr: NonNull<u8>, layout: Layout) {
+            let (padded, offs) = Self::padded(layout);
+            Global.deallocate(NonNull::new_unchecked(ptr.as_ptr().sub(offs)), padded)
+        }
+    }
+
+    for prefix in 0..4 {
+        let mut stack = DynStack::<dyn Debug, _>::new_in(Misaligned);
+        for i in 0..prefix {
+            dyn_push!(stack, i as u8);
+        }
+        for _ in 0..100 {
+            dyn_push!(stack, AlignedZst);
+            stack.assert_invariants();
+        }
+        dyn_push!(stack, Aligned64(1));
+        dyn_push!(stack, 2u64);
+        stack.assert_invariants();
+
+        assert_eq!(stack.len(), prefix + 102);
+        // Only the first zero-sized item needed padding, the rest share its address.
+        let zsts = &stack.offsets()[prefix..prefix + 100];
+        assert!(zsts.iter().all(|&(offs, _)| offs == zsts[0].0));
+        for item in stack.iter() {
+            let addr = item as *const dyn Debug as *const u8 as usize;
+            assert_eq!(addr % mem::align_of_val(item), 0);
+        }
+        assert_eq!(format!("{:?}", &stack[prefix]), "AlignedZst");
+        assert_eq!(format!("{:?}", &stack[prefix + 100]), "Aligned64(1)");
+        assert_eq!(format!("{:?}", &stack[prefix + 101]), "2");
+    }
 }
 
 #[test]
-fn test_push_pop() {
+fn test_thin_pointers() {
+    let mut stack = DynStack::<u64>::new();
+    for i in 0..10u64 {
+        dyn_push!(stack, i * 3);
+    }
+    assert_eq!(stack.offsets()[4], (32, 0));
+    assert_eq!(stack[4], 12);
+    assert_eq!(stack.pop_boxed(), Some(Box::new(27)));
+    let sum: u64 = stack.iter().sum();
+    assert_eq!(sum, 108);
+}
+
+#[test]
+fn test_str() {
+    let mut stack = DynStack::<str>::new();
+    for &word in &["hello", "", "dynamic", "world"] {
+        stack.push_box(word.into());
+    }
+    // `str` has no destructor, so pushing a literal's bytes is fine.
+    unsafe { stack.push("" as *const str as *mut str) };
+    let words: Vec<&str> = stack.iter().collect();
+    assert_eq!(words, ["hello", "", "dynamic", "world", ""]);
+    assert_eq!(stack.element_payload_size(2), Some(7));
+    assert_eq!(stack.byte_len(), 17);
+}
+
+#[test]
+fn test_slices() {
+    let mut stack = DynStack::<[u8]>::new();
+    dyn_push!(stack, [1u8, 2, 3]);
+    dyn_push!(stack, [4u8, 5]);
+    dyn_push!(stack, [0u8; 0]);
+    assert_eq!(stack.get(0).unwrap().len(), 3);
+    assert_eq!(stack.get(1).unwrap().len(), 2);
+    assert_eq!(stack.get(0), Some(&[1, 2, 3][..]));
+    assert_eq!(stack.get(1), Some(&[4, 5][..]));
+    assert_eq!(stack.get(2), Some(&[][..]));
+    assert_eq!(stack.byte_len(), 5);
+    assert_eq!(stack.element_payload_size(0), Some(3));
+    assert_eq!(stack.element_payload_size(1), Some(2));
+
+    stack.get_mut(1).unwrap()[0] = 6;
+    assert_eq!(stack.pop_boxed().unwrap().len(), 0);
+    assert_eq!(stack.pop_boxed(), Some(vec![6, 5].into_boxed_slice()));
+
+    // The length of a slice of aligned elements is a count, not a byte size.
+    let mut stack = DynStack::<[u32]>::new();
+    dyn_push!(stack, [1u32]);
+    dyn_push!(stack, [2u32, 3, 4]);
+    stack.assert_invariants();
+    assert_eq!(stack.get(1), Some(&[2, 3, 4][..]));
+    assert_eq!(stack.element_payload_size(1), Some(12));
+    assert_eq!(stack.offsets()[1], (4, 3));
+}
+
+#[test]
+fn test_push_reporting() {
     use std::fmt::Debug;
+
+    let mut stack = DynStack::<dyn Debug>::new();
+    assert_eq!(stack.capacity_remaining(), 0);
+    let mut item = core::mem::ManuallyDrop::new(1u32);
+    assert!(unsafe { stack.push_reporting(&mut *item) });
+    assert_eq!(stack.capacity_remaining(), 12);
+
+    for i in 0..3u32 {
+        let mut item = core::mem::ManuallyDrop::new(i);
+        assert!(!unsafe { stack.push_reporting(&mut *item) });
+    }
+    assert_eq!(stack.capacity_remaining(), 0);
+
+    let mut item = core::mem::ManuallyDrop::new(4u8);
+    assert!(unsafe { stack.push_reporting(&mut *item) });
+    assert_eq!(stack.capacity(), 32);
+    assert_eq!(stack.capacity_remaining(), 15);
+    assert_eq!(format!("{:?}", stack), "[1, 0, 1, 2, 4]");
+}
+
+#[test]
+#[should_panic(expected = "capacity overflow")]
+fn test_push_isize_overflow() {
+    use std::fmt::Debug;
+
     let mut stack = DynStack::<dyn Debug>::new();
-    let bunch = vec![1u32, 2, 3, 4, 5, 6, 7, 8, 9];
     dyn_push!(stack, 1u8);
-    dyn_push!(stack, 1u32);
-    dyn_push!(stack, 1u16);
-    dyn_push!(stack, 1u64);
-    dyn_push!(stack, 1u128);
-    dyn_push!(stack, bunch);
-    dyn_push!(stack, {
-        #[derive(Debug)]
-        struct ZST;
-        ZST
-    });
+    // Pretend the buffer is almost full, rather than allocating that much.
+    stack.dyn_size = isize::MAX as usize - 8;
+    dyn_push!(stack, 2u64);
+}
 
-    if let Some(item) = stack.peek() {
-        println!("{:?}", item);
-        assert!(format!("{:?}", item) == "ZST");
-    } else {
-        unreachable!();
+#[test]
+#[should_panic(expected = "capacity overflow")]
+fn test_reserve_isize_overflow() {
+    let mut stack = DynStack::<dyn core::fmt::Debug>::new();
+    dyn_push!(stack, 1u8);
+    stack.reserve(isize::MAX as usize);
+}
+
+#[test]
+fn test_invariants() {
+    use std::fmt::Debug;
+
+    #[repr(align(32))]
+    #[derive(Debug)]
+    struct Aligned32(#[allow(dead_code)] u8);
+
+    // A fixed pseudo-random sequence of operations, checking the invariants after each one.
+    let mut state = 0x2545_f491u32;
+    let mut next = move |bound: u32| {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        state % bound
+    };
+
+    let mut stack = DynStack::<dyn Debug>::new();
+    for _ in 0..2000 {
+        match next(10) {
+            0..=2 => dyn_push!(stack, next(256) as u8),
+            3 => dyn_push!(stack, [next(100) as u64; 3]),
+            4 => dyn_push!(stack, Aligned32(next(256) as u8)),
+            5 => {
+                stack.remove_last();
+            }
+            6 => {
+                let len = stack.len() as u32;
+                stack.swap_remove(next(len.max(1)) as usize);
+            }
+            7 => {
+                let keep = next(2);
+                let mut index = 0;
+                stack.retain(|_| {
+                    index += 1;
+                    index % 2 == keep
+                });
+            }
+            8 => stack.reserve(next(512) as usize),
+            _ => stack.shrink_to_fit(),
+        }
+        stack.assert_invariants();
     }
-    assert!(stack.remove_last());
+}
 
-    if let Some(item) = stack.peek() {
-        println!("{:?}", item);
-        assert!(format!("{:?}", item) == "[1, 2, 3, 4, 5, 6, 7, 8, 9]");
+#[test]
+fn test_fn() {
+    let mut stack = DynStack::<dyn Fn() -> usize>::new();
+    for i in 0..100 {
+        dyn_push!(stack, move || i);
     }
-    assert!(stack.remove_last());
 
-    for _i in 0..5 {
-        if let Some(item) = stack.peek() {
-            println!("{:?}", item);
-            assert!(format!("{:?}", item) == "1");
-        } else {
-            unreachable!();
+    let mut item2 = 0;
+    for func in stack.iter() {
+        item2 += func();
+    }
+    assert_eq!(item2, 4950);
+}
+
+#[test]
+fn test_drop() {
+    use core::any::Any;
+    use std::collections::HashSet;
+
+    static mut DROP_NUM: Option<HashSet<usize>> = None;
+    unsafe { DROP_NUM = Some(HashSet::new()) };
+    fn drop_num() -> &'static HashSet<usize> {
+        unsafe { (*ptr::addr_of!(DROP_NUM)).as_ref().unwrap() }
+    }
+    fn drop_num_mut() -> &'static mut HashSet<usize> {
+        unsafe { (*ptr::addr_of_mut!(DROP_NUM)).as_mut().unwrap() }
+    }
+
+    struct Droppable {
+        counter: usize,
+    }
+    impl Drop for Droppable {
+        fn drop(&mut self) {
+            drop_num_mut().insert(self.counter);
+        }
+    }
+
+    {
+        let mut stack = DynStack::<dyn Any>::new();
+        dyn_push!(stack, Droppable { counter: 1 });
+        dyn_push!(stack, Droppable { counter: 2 });
+        dyn_push!(stack, Droppable { counter: 3 });
+        dyn_push!(stack, Droppable { counter: 4 });
+        dyn_push!(stack, Droppable { counter: 5 });
+        dyn_push!(stack, Droppable { counter: 6 });
+        assert!(drop_num().is_empty());
+    }
+
+    let expected: HashSet<usize> = [1, 2, 3, 4, 5, 6].iter().cloned().collect();
+    assert_eq!(drop_num(), &expected);
+}
+
+#[test]
+fn test_into_iter() {
+    use core::any::Any;
+    use std::cell::RefCell;
+
+    thread_local!(static DROPPED: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) });
+    let dropped = || DROPPED.with(|dropped| dropped.borrow_mut().split_off(0));
+
+    #[derive(Debug)]
+    struct Droppable {
+        counter: usize,
+    }
+    impl Drop for Droppable {
+        fn drop(&mut self) {
+            DROPPED.with(|dropped| dropped.borrow_mut().push(self.counter));
         }
-        assert!(stack.remove_last());
     }
 
-    assert!(stack.len() == 0);
-    assert!(stack.dyn_size == 0);
-}
+    let build = || {
+        let mut stack = DynStack::<dyn Any>::new();
+        for counter in 0..6 {
+            dyn_push!(stack, Droppable { counter });
+        }
+        dyn_push!(stack, [7u64; 3]);
+        stack
+    };
 
-#[test]
-fn test_fn() {
-    let mut stack = DynStack::<dyn Fn() -> usize>::new();
-    for i in 0..100 {
-        dyn_push!(stack, move || i);
+    let mut counters = Vec::new();
+    let mut iter = build().into_iter();
+    assert_eq!(iter.len(), 7);
+    for boxed in &mut iter {
+        match boxed.downcast::<Droppable>() {
+            Ok(item) => counters.push(item.counter),
+            Err(other) => assert_eq!(other.downcast_ref::<[u64; 3]>(), Some(&[7; 3])),
+        }
     }
+    assert_eq!(iter.len(), 0);
+    drop(iter);
+    assert_eq!(counters, [0, 1, 2, 3, 4, 5]);
+    assert_eq!(dropped(), [0, 1, 2, 3, 4, 5]);
 
-    let mut item2 = 0;
-    for func in stack.iter() {
-        item2 += func();
+    // Breaking early drops the rest of the items exactly once, when the iterator is dropped.
+    for boxed in build() {
+        if boxed.downcast_ref::<Droppable>().unwrap().counter == 2 {
+            break;
+        }
     }
-    assert_eq!(item2, 4950);
+    let mut dropped = dropped();
+    assert_eq!(dropped[..3], [0, 1, 2]);
+    dropped.sort_unstable();
+    assert_eq!(dropped, [0, 1, 2, 3, 4, 5]);
 }
 
 #[test]
-fn test_drop() {
+fn test_clear() {
     use core::any::Any;
     use std::collections::HashSet;
 
     static mut DROP_NUM: Option<HashSet<usize>> = None;
     unsafe { DROP_NUM = Some(HashSet::new()) };
     fn drop_num() -> &'static HashSet<usize> {
-        unsafe { DROP_NUM.as_ref().unwrap() }
+        unsafe { (*ptr::addr_of!(DROP_NUM)).as_ref().unwrap() }
     }
     fn drop_num_mut() -> &'static mut HashSet<usize> {
-        unsafe { DROP_NUM.as_mut().unwrap() }
+        unsafe { (*ptr::addr_of_mut!(DROP_NUM)).as_mut().unwrap() }
     }
 
     struct Droppable {
         counter: usize,
-    };
+    }
     impl Drop for Droppable {
         fn drop(&mut self) {
             drop_num_mut().insert(self.counter);
         }
     }
 
-    {
-        let mut stack = DynStack::<dyn Any>::new();
-        dyn_push!(stack, Droppable { counter: 1 });
-        dyn_push!(stack, Droppable { counter: 2 });
-        dyn_push!(stack, Droppable { counter: 3 });
-        dyn_push!(stack, Droppable { counter: 4 });
-        dyn_push!(stack, Droppable { counter: 5 });
-        dyn_push!(stack, Droppable { counter: 6 });
-        assert!(drop_num().is_empty());
+    let mut stack = DynStack::<dyn Any>::new();
+    for i in 0..4 {
+        dyn_push!(stack, Droppable { counter: i });
     }
+    let data = stack.dyn_data;
+    let cap = stack.dyn_cap;
 
-    let expected: HashSet<usize> = [1, 2, 3, 4, 5, 6].iter().cloned().collect();
+    stack.clear();
+    assert!(stack.is_empty());
+    assert_eq!(stack.dyn_size, 0);
+    let expected: HashSet<usize> = [0, 1, 2, 3].iter().cloned().collect();
     assert_eq!(drop_num(), &expected);
+
+    dyn_push!(stack, 5u64);
+    assert_eq!(stack.dyn_data, data);
+    assert_eq!(stack.dyn_cap, cap);
+    assert_eq!(stack[0].downcast_ref::<u64>(), Some(&5));
+}
+
+#[test]
+fn test_needs_drop() {
+    use core::cell::Cell;
+    use std::fmt::Debug;
+
+    thread_local!(static DROPS: Cell<usize> = const { Cell::new(0) });
+
+    #[derive(Debug)]
+    struct Droppable;
+    impl Drop for Droppable {
+        fn drop(&mut self) {
+            DROPS.with(|drops| drops.set(drops.get() + 1));
+        }
+    }
+
+    let mut stack = DynStack::<dyn Debug>::new();
+    dyn_push!(stack, 1u32);
+    dyn_push_indexed!(stack, [2u8; 3]);
+    stack.push_copy(3u64, |x| x);
+    dyn_push_ref!(stack, "four");
+    dyn_replace!(stack, 0, 5u32).unwrap();
+    assert!(!stack.needs_drop);
+    stack.clear();
+    stack.assert_invariants();
+    assert!(stack.is_empty());
+    assert_eq!(stack.byte_len(), 0);
+
+    // Items with destructors, or of unknown type, are dropped one by one.
+    dyn_push!(stack, 1u32);
+    dyn_push!(stack, Droppable);
+    dyn_push!(stack, 2u32);
+    assert!(stack.needs_drop);
+    stack.clear();
+    assert_eq!(DROPS.with(Cell::get), 1);
+    assert!(!stack.needs_drop);
+
+    stack.push_box(Box::new(1u32));
+    assert!(stack.needs_drop);
+    stack.clear();
+
+    dyn_push!(stack, 1u32);
+    dyn_replace!(stack, 0, Droppable).unwrap_err();
+    assert!(!stack.needs_drop);
+    dyn_push!(stack, 2u32);
+    let mut other = stack.split_off(1);
+    assert!(!other.needs_drop);
+    dyn_push!(other, Droppable);
+    let other = other.split_off(0);
+    assert!(other.needs_drop);
+    drop(other);
+    assert_eq!(DROPS.with(Cell::get), 3);
+
+    let mut small = SmallDynStack::<dyn Debug, 32>::new();
+    dyn_push!(small, 1u32);
+    assert!(!small.spilled());
+    assert!(!small.into_dyn_stack().needs_drop);
+    let mut small = SmallDynStack::<dyn Debug, 32>::new();
+    unsafe { small.push(&mut 1u32) };
+    assert!(small.into_dyn_stack().needs_drop);
 }
 
 #[test]
@@ -552,15 +6243,15 @@ fn test_align() {
 
     fn new32() -> Aligned32 {
         let mut dat = [0u8; 32];
-        for i in 0..32 {
-            dat[i] = i as u8;
+        for (i, b) in dat.iter_mut().enumerate() {
+            *b = i as u8;
         }
         Aligned32 { _dat: dat }
     }
     fn new64() -> Aligned64 {
         let mut dat = [0u8; 64];
-        for i in 0..64 {
-            dat[i] = i as u8;
+        for (i, b) in dat.iter_mut().enumerate() {
+            *b = i as u8;
         }
         Aligned64 { _dat: dat }
     }
@@ -595,9 +6286,42 @@ fn test_align() {
 }
 
 #[test]
-#[should_panic]
-fn test_non_dyn() {
-    let _stack: DynStack<u8> = DynStack::new();
+fn test_iter_indexed() {
+    trait Counter {
+        fn add(&mut self, amount: usize);
+        fn get(&self) -> usize;
+    }
+    impl Counter for usize {
+        fn add(&mut self, amount: usize) {
+            *self += amount;
+        }
+        fn get(&self) -> usize {
+            *self
+        }
+    }
+    impl Counter for u8 {
+        fn add(&mut self, amount: usize) {
+            *self += amount as u8;
+        }
+        fn get(&self) -> usize {
+            *self as usize
+        }
+    }
+
+    let mut stack = DynStack::<dyn Counter>::new();
+    dyn_push!(stack, 0usize);
+    dyn_push!(stack, 0u8);
+    dyn_push!(stack, 0usize);
+
+    for (index, item) in stack.iter_indexed_mut() {
+        item.add(index * 10);
+    }
+    let pairs: Vec<(usize, usize)> = stack
+        .iter_indexed()
+        .map(|(index, item)| (index, item.get()))
+        .collect();
+    assert_eq!(pairs, [(0, 0), (1, 10), (2, 20)]);
+    assert_eq!(stack.iter_indexed().len(), 3);
 }
 
 #[test]
@@ -618,6 +6342,21 @@ fn test_send() {
     assert_eq!(stack[1].to_string(), "2");
 }
 
+#[test]
+fn test_send_closures() {
+    use std::thread;
+
+    let mut stack = DynStack::<dyn Fn() -> usize + Send>::new();
+    let offset = 10;
+    dyn_push!(stack, move || offset + 1);
+    dyn_push!(stack, || 2);
+
+    let sum = thread::spawn(move || stack.iter().map(|f| f()).sum::<usize>())
+        .join()
+        .unwrap();
+    assert_eq!(sum, 13);
+}
+
 #[test]
 fn test_sync() {
     use std::sync::{
@@ -684,6 +6423,578 @@ fn test_sync() {
     }
 }
 
+#[test]
+fn test_iter_rev() {
+    use std::fmt::Debug;
+
+    let mut stack = DynStack::<dyn Debug>::new();
+    assert_eq!(stack.iter_rev().next().map(|_| ()), None);
+    dyn_push!(stack, 1u8);
+    dyn_push!(stack, "two");
+    dyn_push!(stack, [3u64; 3]);
+    dyn_push!(stack, 4i32);
+
+    let mut forward: Vec<_> = stack.iter().map(|item| format!("{:?}", item)).collect();
+    forward.reverse();
+    let reversed: Vec<_> = stack.iter_rev().map(|item| format!("{:?}", item)).collect();
+    assert_eq!(reversed, forward);
+    assert_eq!(stack.iter_rev().len(), 4);
+
+    trait Bump {
+        fn bump(&mut self);
+        fn get(&self) -> u32;
+    }
+    impl Bump for u32 {
+        fn bump(&mut self) {
+            *self += 1;
+        }
+        fn get(&self) -> u32 {
+            *self
+        }
+    }
+
+    let mut stack = DynStack::<dyn Bump>::new();
+    for i in 0..5u32 {
+        dyn_push!(stack, i);
+    }
+    let mut rev = stack.iter_rev_mut();
+    rev.next().unwrap().bump();
+    assert_eq!(rev.len(), 4);
+    for item in rev {
+        item.bump();
+        item.bump();
+    }
+    let values: Vec<_> = stack.iter().map(|item| item.get()).collect();
+    assert_eq!(values, [2, 3, 4, 5, 5]);
+}
+
+#[test]
+fn test_for_each_mut() {
+    trait Visit {
+        fn visit(&mut self, index: usize);
+        fn visits(&self) -> &[usize];
+    }
+    struct Small(Vec<usize>);
+    struct Large([u64; 4], Vec<usize>);
+    impl Visit for Small {
+        fn visit(&mut self, index: usize) {
+            self.0.push(index);
+        }
+        fn visits(&self) -> &[usize] {
+            &self.0
+        }
+    }
+    impl Visit for Large {
+        fn visit(&mut self, index: usize) {
+            self.0[index % 4] += 1;
+            self.1.push(index);
+        }
+        fn visits(&self) -> &[usize] {
+            &self.1
+        }
+    }
+
+    let mut stack = DynStack::<dyn Visit>::new();
+    for i in 0..6 {
+        if i % 2 == 0 {
+            dyn_push!(stack, Small(Vec::new()));
+        } else {
+            dyn_push!(stack, Large([0; 4], Vec::new()));
+        }
+    }
+    let mut order = Vec::new();
+    stack.for_each_mut(|index, item| {
+        order.push(index);
+        item.visit(index);
+    });
+    assert_eq!(order, [0, 1, 2, 3, 4, 5]);
+    for (index, item) in stack.iter().enumerate() {
+        assert_eq!(item.visits(), [index]);
+    }
+}
+
+#[test]
+fn test_range() {
+    trait Value {
+        fn get(&self) -> u32;
+        fn set(&mut self, value: u32);
+    }
+    impl Value for u32 {
+        fn get(&self) -> u32 {
+            *self
+        }
+        fn set(&mut self, value: u32) {
+            *self = value;
+        }
+    }
+
+    let mut stack = DynStack::<dyn Value>::new();
+    for i in 0..10u32 {
+        dyn_push!(stack, i);
+    }
+    let values =
+        |iter: DynStackIter<'_, dyn Value>| iter.map(|item| item.get()).collect::<Vec<_>>();
+
+    assert_eq!(values(stack.range(2, 5)), [2, 3, 4]);
+    assert_eq!(values(stack.range(0, 10)), values(stack.iter()));
+    assert!(values(stack.range(7, 7)).is_empty());
+    assert!(values(stack.range(10, 10)).is_empty());
+    assert_eq!(stack.range(1, 9).len(), 8);
+    assert_eq!(stack.range(1, 9).nth(3).map(|item| item.get()), Some(4));
+    assert!(stack.range(1, 9).nth(8).is_none());
+
+    for item in stack.range_mut(5, 8) {
+        item.set(item.get() * 10);
+    }
+    assert_eq!(values(stack.iter()), [0, 1, 2, 3, 4, 50, 60, 70, 8, 9]);
+}
+
+#[test]
+fn test_insert() {
+    use std::fmt::Debug;
+
+    #[repr(align(32))]
+    #[derive(Debug)]
+    struct Aligned32(#[allow(dead_code)] u8);
+
+    fn insert<U: Debug + 'static>(stack: &mut DynStack<dyn Debug>, index: usize, item: U) {
+        let mut item = core::mem::ManuallyDrop::new(item);
+        unsafe { stack.insert(index, &mut *item) };
+        stack.assert_invariants();
+    }
+
+    let mut stack = DynStack::<dyn Debug>::new_zeroed();
+    insert(&mut stack, 0, 1u8);
+    dyn_push!(stack, 2u16);
+    dyn_push!(stack, [3u64; 2]);
+    dyn_push!(stack, String::from("four"));
+    insert(&mut stack, 2, 5u8);
+    assert_eq!(format!("{:?}", stack), r#"[1, 2, 5, [3, 3], "four"]"#);
+    insert(&mut stack, 0, Aligned32(6));
+    insert(&mut stack, 6, 7u32);
+    insert(&mut stack, 3, String::from("eight"));
+    assert_eq!(
+        format!("{:?}", stack),
+        r#"[Aligned32(6), 1, 2, "eight", 5, [3, 3], "four", 7]"#
+    );
+    assert!(stack.offs_sorted);
+    assert_eq!(stack.max_alignment(), 32);
+
+    // The padding left by the moves is zeroed.
+    let mut end = 0;
+    for &entry in stack.offsets() {
+        let padding = unsafe { &stack.as_bytes()[end..entry.0] };
+        assert!(padding.iter().all(|&b| b == 0));
+        end = entry.0 + stack.entry_layout(entry).size();
+    }
+
+    // Items out of byte order only have their offset table entries moved.
+    stack.swap(0, 7);
+    let size = stack.byte_len();
+    insert(&mut stack, 1, 9u8);
+    assert_eq!(stack.offsets()[1].0, size);
+    assert_eq!(
+        format!("{:?}", stack),
+        r#"[7, 9, 1, 2, "eight", 5, [3, 3], "four", Aligned32(6)]"#
+    );
+}
+
+#[test]
+#[should_panic(expected = "insertion index (is 2) should be <= len (is 1)")]
+fn test_insert_out_of_bounds() {
+    let mut stack = DynStack::<dyn core::fmt::Debug>::new();
+    dyn_push!(stack, 1u8);
+    let mut item = 2u8;
+    unsafe { stack.insert(2, &mut item) };
+}
+
+#[test]
+fn test_remove() {
+    use std::{cell::Cell, fmt::Debug};
+
+    thread_local!(static DROPS: Cell<usize> = const { Cell::new(0) });
+
+    #[repr(align(32))]
+    #[derive(Debug)]
+    struct Aligned32(#[allow(dead_code)] u8);
+    #[derive(Debug)]
+    struct Counted(#[allow(dead_code)] u8);
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPS.with(|drops| drops.set(drops.get() + 1));
+        }
+    }
+
+    let mut stack = DynStack::<dyn Debug>::new_zeroed();
+    dyn_push!(stack, 1u8);
+    dyn_push!(stack, Counted(2));
+    dyn_push!(stack, [3u16; 3]);
+    dyn_push!(stack, Aligned32(4));
+    dyn_push!(stack, 5u64);
+    dyn_push!(stack, "six");
+    assert!(!stack.remove(6));
+
+    assert!(stack.remove(1));
+    assert_eq!(DROPS.with(|drops| drops.get()), 1);
+    stack.assert_invariants();
+    assert_eq!(stack.offsets()[1].0, 1 + 1);
+    assert_eq!(
+        format!("{:?}", stack),
+        r#"[1, [3, 3, 3], Aligned32(4), 5, "six"]"#
+    );
+
+    // Items only move as far down as their alignment allows.
+    let offsets = stack.offsets().to_vec();
+    assert!(stack.remove(0));
+    stack.assert_invariants();
+    assert_eq!(stack.offsets()[0].0, 0);
+    assert_eq!(stack.offsets()[1..], offsets[2..]);
+    assert_eq!(
+        format!("{:?}", stack),
+        r#"[[3, 3, 3], Aligned32(4), 5, "six"]"#
+    );
+    assert!(stack.remove(3));
+    assert!(stack.offs_sorted);
+
+    // Out of byte order, the items are packed in byte order.
+    stack.swap(0, 2);
+    assert!(stack.remove(1));
+    stack.assert_invariants();
+    assert_eq!(format!("{:?}", stack), "[5, [3, 3, 3]]");
+    assert!(stack.remove(0) && stack.remove(0));
+    assert_eq!(stack.byte_len(), 0);
+}
+
+#[test]
+fn test_push_ref() {
+    let mut stack = DynStack::<dyn FnMut() -> u32>::new();
+    dyn_push!(stack, || 0);
+    let mut count = 10;
+    let f = dyn_push_ref!(stack, move || {
+        count += 1;
+        count
+    });
+    assert_eq!(f(), 11);
+    assert_eq!(f(), 12);
+    assert_eq!((stack[1])(), 13);
+    assert_eq!(stack.len(), 2);
+
+    let mut item = core::mem::ManuallyDrop::new(|| 20);
+    let f = unsafe { stack.push_mut(&mut *item) };
+    assert_eq!(f(), 20);
+    stack.assert_invariants();
+}
+
+#[test]
+fn test_fold() {
+    trait Number {
+        fn value(&self) -> usize;
+    }
+    impl Number for u8 {
+        fn value(&self) -> usize {
+            *self as usize
+        }
+    }
+    impl Number for [u64; 2] {
+        fn value(&self) -> usize {
+            (self[0] + self[1]) as usize
+        }
+    }
+
+    let mut stack = DynStack::<dyn Number>::new();
+    assert_eq!(stack.sum_by(|n| n.value()), 0);
+    assert_eq!(stack.fold(7, |acc, n| acc * n.value()), 7);
+    for i in 0..10u8 {
+        dyn_push!(stack, i);
+        dyn_push!(stack, [i as u64, 100]);
+    }
+    let expected: usize = stack.iter().map(|n| n.value()).sum();
+    assert_eq!(stack.sum_by(|n| n.value()), expected);
+    assert_eq!(expected, 1090);
+
+    let order = stack.fold(vec![], |mut order, n| {
+        order.push(n.value());
+        order
+    });
+    assert_eq!(order, stack.iter().map(|n| n.value()).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_peek_empty() {
+    let mut stack = DynStack::<dyn core::fmt::Debug>::new();
+    assert!(stack.peek().is_none());
+    assert!(stack.peek_mut().is_none());
+
+    dyn_push!(stack, 1u8);
+    assert_eq!(format!("{:?}", stack.peek()), "Some(1)");
+    stack.remove_last();
+    assert!(stack.peek().is_none());
+    assert!(stack.peek_mut().is_none());
+
+    let mut small = SmallDynStack::<dyn core::fmt::Debug, 16>::new();
+    assert!(small.peek().is_none());
+    assert!(small.peek_mut().is_none());
+}
+
+#[test]
+fn test_peek_n() {
+    let mut stack = DynStack::<dyn core::fmt::Debug>::new();
+    assert_eq!(stack.peek_n(0).unwrap().len(), 0);
+    assert!(stack.peek_n(1).is_none());
+
+    for i in 0..5u32 {
+        dyn_push!(stack, i);
+    }
+    let top: Vec<String> = stack
+        .peek_n(3)
+        .unwrap()
+        .map(|item| format!("{:?}", item))
+        .collect();
+    assert_eq!(top, ["2", "3", "4"]);
+    assert_eq!(stack.len(), 5);
+    assert_eq!(stack.peek_n(5).unwrap().len(), 5);
+    assert!(stack.peek_n(6).is_none());
+    assert!(stack.peek_n_mut(6).is_none());
+
+    let mut stack = DynStack::<dyn FnMut() -> u32>::new();
+    for i in 0..5u32 {
+        let mut count = i;
+        dyn_push!(stack, move || {
+            count += 10;
+            count
+        });
+    }
+    let top: Vec<u32> = stack.peek_n_mut(2).unwrap().map(|f| f()).collect();
+    assert_eq!(top, [13, 14]);
+    assert_eq!(stack.len(), 5);
+    assert_eq!((stack[4])(), 24);
+}
+
+#[test]
+#[should_panic(expected = "range end 11 is out of bounds for length 10")]
+fn test_range_out_of_bounds() {
+    let mut stack = DynStack::<dyn core::fmt::Debug>::new();
+    dyn_extend!(stack, 0..10u8);
+    stack.range(5, 11);
+}
+
+#[test]
+#[should_panic(expected = "range start 6 is past its end 5")]
+fn test_range_reversed() {
+    let mut stack = DynStack::<dyn core::fmt::Debug>::new();
+    dyn_extend!(stack, 0..10u8);
+    stack.range_mut(6, 5);
+}
+
+/// Holds every mutable reference an iterator yields at once. Run with `cargo miri test` to check
+/// that writing through one doesn't invalidate the others.
+#[test]
+fn test_replace() {
+    use std::cell::RefCell;
+
+    thread_local!(static DROPS: RefCell<Vec<u32>> = const { RefCell::new(Vec::new()) });
+
+    trait Value {
+        fn get(&self) -> u32;
+    }
+    impl Value for u32 {
+        fn get(&self) -> u32 {
+            *self
+        }
+    }
+    impl Value for u8 {
+        fn get(&self) -> u32 {
+            *self as u32
+        }
+    }
+    struct Counted(u32);
+    impl Value for Counted {
+        fn get(&self) -> u32 {
+            self.0
+        }
+    }
+    impl Drop for Counted {
+        fn drop(&mut self) {
+            DROPS.with(|drops| drops.borrow_mut().push(self.0));
+        }
+    }
+    let drops = || DROPS.with(|drops| drops.borrow().clone());
+    let values = |stack: &DynStack<dyn Value>| stack.iter().map(|v| v.get()).collect::<Vec<_>>();
+
+    let mut stack = DynStack::<dyn Value>::new();
+    dyn_push!(stack, 1u8);
+    dyn_push!(stack, Counted(2));
+    dyn_push!(stack, 3u32);
+    let offsets =
+        |stack: &DynStack<dyn Value>| stack.offsets().iter().map(|e| e.0).collect::<Vec<_>>();
+    let before = offsets(&stack);
+
+    // Replacing with another type of the same layout runs the old item's destructor.
+    assert_eq!(dyn_replace!(stack, 1, 20u32), Ok(()));
+    assert_eq!(drops(), [2]);
+    assert_eq!(dyn_replace!(stack, 2, Counted(30)), Ok(()));
+    assert_eq!(values(&stack), [1, 20, 30]);
+    assert_eq!(offsets(&stack), before);
+
+    assert_eq!(
+        dyn_replace!(stack, 0, Counted(10)),
+        Err(ReplaceError::LayoutMismatch)
+    );
+    assert_eq!(
+        dyn_replace!(stack, 3, Counted(40)),
+        Err(ReplaceError::OutOfBounds)
+    );
+    // The rejected items were dropped by the macro, and the stack is unchanged.
+    assert_eq!(drops(), [2, 10, 40]);
+    assert_eq!(values(&stack), [1, 20, 30]);
+
+    drop(stack);
+    assert_eq!(drops(), [2, 10, 40, 30]);
+}
+
+#[test]
+fn test_freeze() {
+    use std::{fmt::Debug, sync::Arc};
+
+    fn assert_sync<S: Sync>(_: &S) {}
+
+    let mut stack = DynStack::<dyn Debug + Send + Sync>::new();
+    for i in 0..20u32 {
+        if i % 2 == 0 {
+            dyn_push!(stack, i);
+        } else {
+            dyn_push!(stack, i.to_string());
+        }
+    }
+    let expected: Vec<String> = stack.iter().map(|item| format!("{:?}", item)).collect();
+    let frozen = stack.freeze();
+    assert_sync(&frozen);
+    assert_eq!(frozen.len(), 20);
+    assert_eq!(format!("{:?}", &frozen[1]), "\"1\"");
+    assert!(frozen.get(20).is_none());
+
+    std::thread::scope(|scope| {
+        for _ in 0..4 {
+            scope.spawn(|| {
+                let items: Vec<String> = frozen.iter().map(|item| format!("{:?}", item)).collect();
+                assert_eq!(items, expected);
+            });
+        }
+    });
+
+    let frozen = Arc::new(frozen);
+    let threads: Vec<_> = (0..4)
+        .map(|i| {
+            let frozen = Arc::clone(&frozen);
+            std::thread::spawn(move || format!("{:?}", frozen.get(i).unwrap()))
+        })
+        .collect();
+    for (i, thread) in threads.into_iter().enumerate() {
+        assert_eq!(thread.join().unwrap(), expected[i]);
+    }
+
+    let mut stack = Arc::try_unwrap(frozen).unwrap().thaw();
+    dyn_push!(stack, 20u32);
+    assert_eq!(stack.len(), 21);
+    stack.assert_invariants();
+}
+
+#[test]
+fn test_iter_fused() {
+    use std::fmt::Debug;
+
+    fn assert_fused<I: FusedIterator>(mut iter: I) {
+        while iter.next().is_some() {}
+        for _ in 0..3 {
+            assert!(iter.next().is_none());
+        }
+    }
+
+    let mut stack = DynStack::<dyn Debug>::new();
+    dyn_push!(stack, 1u8);
+    dyn_push!(stack, "two");
+    assert_fused(stack.iter());
+    assert_fused(stack.iter_mut());
+    assert_fused(stack.iter_rev());
+    assert_fused(stack.iter_rev_mut());
+    assert_fused(stack.range(1, 2));
+    assert_fused(stack.into_iter());
+}
+
+#[test]
+fn test_iter_mut_aliasing() {
+    trait Value {
+        fn get(&self) -> u64;
+        fn set(&mut self, value: u64);
+    }
+    impl Value for u8 {
+        fn get(&self) -> u64 {
+            *self as u64
+        }
+        fn set(&mut self, value: u64) {
+            *self = value as u8;
+        }
+    }
+    impl Value for [u64; 3] {
+        fn get(&self) -> u64 {
+            self[2]
+        }
+        fn set(&mut self, value: u64) {
+            self[2] = value;
+        }
+    }
+
+    let mut stack = DynStack::<dyn Value>::new();
+    for i in 0..8u8 {
+        if i % 2 == 0 {
+            dyn_push!(stack, i);
+        } else {
+            dyn_push!(stack, [i as u64; 3]);
+        }
+    }
+
+    // All references are alive at once, and each is written through after the others were made.
+    let mut items: Vec<&mut dyn Value> = stack.iter_mut().collect();
+    for item in &mut items {
+        item.set(item.get() + 10);
+    }
+    let mut items: Vec<&mut dyn Value> = stack.iter_rev_mut().collect();
+    for item in &mut items {
+        item.set(item.get() * 2);
+    }
+    let mut iter = stack.iter_mut();
+    let second = iter.nth(1).unwrap();
+    let last = iter.last().unwrap();
+    second.set(0);
+    last.set(1);
+
+    let values: Vec<u64> = stack.iter().map(|item| item.get()).collect();
+    assert_eq!(values, [20, 0, 24, 26, 28, 30, 32, 1]);
+}
+
+#[test]
+fn test_iter_nth() {
+    let mut stack = DynStack::<dyn Fn() -> usize>::new();
+    for i in 0..1000 {
+        dyn_push!(stack, move || i);
+    }
+
+    let mut iter = stack.iter();
+    assert_eq!(iter.nth(500).unwrap()(), stack.get(500).unwrap()());
+    assert_eq!(iter.len(), 499);
+    assert_eq!(iter.next().unwrap()(), 501);
+    assert!(iter.nth(usize::MAX).is_none());
+    assert_eq!(iter.len(), 0);
+
+    let stepped: Vec<usize> = stack.iter().step_by(250).map(|f| f()).collect();
+    assert_eq!(stepped, [0, 250, 500, 750]);
+
+    let mut iter = stack.iter_mut();
+    assert_eq!(iter.nth(997).unwrap()(), 997);
+    assert_eq!(iter.nth(1).unwrap()(), 999);
+    assert!(iter.nth(1).is_none());
+}
+
 #[test]
 fn test_iter_size_hint() {
     let mut stack = DynStack::<dyn Fn() -> usize>::new();
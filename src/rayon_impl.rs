@@ -0,0 +1,269 @@
+//! Parallel iteration with `rayon`, enabled by the `rayon` feature.
+//!
+//! The parallel iterators split the stack on ranges of its offset table. Each item occupies its
+//! own bytes in the buffer, so handing out `&mut T`s for disjoint ranges to different threads is
+//! sound.
+
+use crate::{fatptr, Allocator, DynStack, Global};
+use core::{marker::PhantomData, slice};
+use rayon::iter::{
+    plumbing::{bridge, Consumer, Producer, ProducerCallback, UnindexedConsumer},
+    IndexedParallelIterator, IntoParallelIterator, ParallelIterator,
+};
+
+/// Recomposes the trait object pointers for a range of offset table entries.
+struct RawItems<'a, T: ?Sized> {
+    data: *mut u8,
+    entries: slice::Iter<'a, (usize, usize)>,
+    _spooky: PhantomData<*mut T>,
+}
+
+impl<'a, T: ?Sized> RawItems<'a, T> {
+    fn new(data: *mut u8, entries: &'a [(usize, usize)]) -> Self {
+        RawItems {
+            data,
+            entries: entries.iter(),
+            _spooky: PhantomData,
+        }
+    }
+
+    fn recomp(&self, &(offs, vtable): &(usize, usize)) -> *mut T {
+        unsafe { fatptr::recomp(self.data.add(offs), vtable) }
+    }
+
+    fn next(&mut self) -> Option<*mut T> {
+        let entry = self.entries.next()?;
+        Some(self.recomp(entry))
+    }
+
+    fn next_back(&mut self) -> Option<*mut T> {
+        let entry = self.entries.next_back()?;
+        Some(self.recomp(entry))
+    }
+}
+
+/// A sequential iterator over the part of a stack handed to one [`DynStackParIter`] job.
+struct SplitIter<'a, T: ?Sized> {
+    raw: RawItems<'a, T>,
+    _spooky: PhantomData<&'a T>,
+}
+
+impl<'a, T: ?Sized> Iterator for SplitIter<'a, T> {
+    type Item = &'a T;
+
+    fn next(&mut self) -> Option<&'a T> {
+        self.raw.next().map(|item| unsafe { &*item })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.raw.entries.size_hint()
+    }
+}
+
+impl<T: ?Sized> DoubleEndedIterator for SplitIter<'_, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.raw.next_back().map(|item| unsafe { &*item })
+    }
+}
+
+impl<T: ?Sized> ExactSizeIterator for SplitIter<'_, T> {}
+
+/// A sequential iterator over the part of a stack handed to one [`DynStackParIterMut`] job.
+struct SplitIterMut<'a, T: ?Sized> {
+    raw: RawItems<'a, T>,
+    _spooky: PhantomData<&'a mut T>,
+}
+
+impl<'a, T: ?Sized> Iterator for SplitIterMut<'a, T> {
+    type Item = &'a mut T;
+
+    fn next(&mut self) -> Option<&'a mut T> {
+        self.raw.next().map(|item| unsafe { &mut *item })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        self.raw.entries.size_hint()
+    }
+}
+
+impl<T: ?Sized> DoubleEndedIterator for SplitIterMut<'_, T> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        self.raw.next_back().map(|item| unsafe { &mut *item })
+    }
+}
+
+impl<T: ?Sized> ExactSizeIterator for SplitIterMut<'_, T> {}
+
+/// A parallel iterator over trait object references, created by
+/// [`par_iter`](rayon::iter::IntoParallelRefIterator::par_iter).
+pub struct DynStackParIter<'a, T: ?Sized, A: Allocator = Global> {
+    stack: &'a DynStack<T, A>,
+}
+
+/// A parallel iterator over mutable trait object references, created by
+/// [`par_iter_mut`](rayon::iter::IntoParallelRefMutIterator::par_iter_mut).
+pub struct DynStackParIterMut<'a, T: ?Sized, A: Allocator = Global> {
+    stack: &'a mut DynStack<T, A>,
+}
+
+struct IterProducer<'a, T: ?Sized> {
+    data: *mut u8,
+    entries: &'a [(usize, usize)],
+    _spooky: PhantomData<&'a T>,
+}
+
+// Safety: the producer only hands out `&T`s.
+unsafe impl<T: ?Sized + Sync> Send for IterProducer<'_, T> {}
+
+struct IterMutProducer<'a, T: ?Sized> {
+    data: *mut u8,
+    entries: &'a [(usize, usize)],
+    _spooky: PhantomData<&'a mut T>,
+}
+
+// Safety: the producer hands out `&mut T`s to items that no other producer can reach.
+unsafe impl<T: ?Sized + Send> Send for IterMutProducer<'_, T> {}
+
+impl<'a, T: ?Sized + Sync + 'a> Producer for IterProducer<'a, T> {
+    type Item = &'a T;
+    type IntoIter = SplitIter<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        SplitIter {
+            raw: RawItems::new(self.data, self.entries),
+            _spooky: PhantomData,
+        }
+    }
+
+    fn split_at(self, index: usize) -> (Self, Self) {
+        let (left, right) = self.entries.split_at(index);
+        (
+            IterProducer {
+                data: self.data,
+                entries: left,
+                _spooky: PhantomData,
+            },
+            IterProducer {
+                data: self.data,
+                entries: right,
+                _spooky: PhantomData,
+            },
+        )
+    }
+}
+
+impl<'a, T: ?Sized + Send + 'a> Producer for IterMutProducer<'a, T> {
+    type Item = &'a mut T;
+    type IntoIter = SplitIterMut<'a, T>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        SplitIterMut {
+            raw: RawItems::new(self.data, self.entries),
+            _spooky: PhantomData,
+        }
+    }
+
+    fn split_at(self, index: usize) -> (Self, Self) {
+        let (left, right) = self.entries.split_at(index);
+        (
+            IterMutProducer {
+                data: self.data,
+                entries: left,
+                _spooky: PhantomData,
+            },
+            IterMutProducer {
+                data: self.data,
+                entries: right,
+                _spooky: PhantomData,
+            },
+        )
+    }
+}
+
+impl<'a, T: ?Sized + Sync + 'a, A: Allocator + Sync> ParallelIterator
+    for DynStackParIter<'a, T, A>
+{
+    type Item = &'a T;
+
+    fn drive_unindexed<C: UnindexedConsumer<Self::Item>>(self, consumer: C) -> C::Result {
+        bridge(self, consumer)
+    }
+
+    fn opt_len(&self) -> Option<usize> {
+        Some(self.stack.len())
+    }
+}
+
+impl<'a, T: ?Sized + Sync + 'a, A: Allocator + Sync> IndexedParallelIterator
+    for DynStackParIter<'a, T, A>
+{
+    fn len(&self) -> usize {
+        self.stack.len()
+    }
+
+    fn drive<C: Consumer<Self::Item>>(self, consumer: C) -> C::Result {
+        bridge(self, consumer)
+    }
+
+    fn with_producer<CB: ProducerCallback<Self::Item>>(self, callback: CB) -> CB::Output {
+        callback.callback(IterProducer {
+            data: self.stack.dyn_data,
+            entries: &self.stack.offs_table,
+            _spooky: PhantomData,
+        })
+    }
+}
+
+impl<'a, T: ?Sized + Send + 'a, A: Allocator + Send> ParallelIterator
+    for DynStackParIterMut<'a, T, A>
+{
+    type Item = &'a mut T;
+
+    fn drive_unindexed<C: UnindexedConsumer<Self::Item>>(self, consumer: C) -> C::Result {
+        bridge(self, consumer)
+    }
+
+    fn opt_len(&self) -> Option<usize> {
+        Some(self.stack.len())
+    }
+}
+
+impl<'a, T: ?Sized + Send + 'a, A: Allocator + Send> IndexedParallelIterator
+    for DynStackParIterMut<'a, T, A>
+{
+    fn len(&self) -> usize {
+        self.stack.len()
+    }
+
+    fn drive<C: Consumer<Self::Item>>(self, consumer: C) -> C::Result {
+        bridge(self, consumer)
+    }
+
+    fn with_producer<CB: ProducerCallback<Self::Item>>(self, callback: CB) -> CB::Output {
+        callback.callback(IterMutProducer {
+            data: self.stack.dyn_data,
+            entries: &self.stack.offs_table,
+            _spooky: PhantomData,
+        })
+    }
+}
+
+impl<'a, T: ?Sized + Sync + 'a, A: Allocator + Sync> IntoParallelIterator for &'a DynStack<T, A> {
+    type Iter = DynStackParIter<'a, T, A>;
+    type Item = &'a T;
+
+    fn into_par_iter(self) -> Self::Iter {
+        DynStackParIter { stack: self }
+    }
+}
+
+impl<'a, T: ?Sized + Send + 'a, A: Allocator + Send> IntoParallelIterator
+    for &'a mut DynStack<T, A>
+{
+    type Iter = DynStackParIterMut<'a, T, A>;
+    type Item = &'a mut T;
+
+    fn into_par_iter(self) -> Self::Iter {
+        DynStackParIterMut { stack: self }
+    }
+}
@@ -0,0 +1,23 @@
+//! `serde` support, enabled by the `serde` feature.
+
+use crate::{Allocator, DynStack};
+use serde::ser::{Serialize, Serializer};
+
+/// Serializes an item through `erased_serde`, since `T` may be unsized.
+struct Erased<'a, T: ?Sized>(&'a T);
+
+impl<T: ?Sized + erased_serde::Serialize> Serialize for Erased<'_, T> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        erased_serde::serialize(self.0, serializer)
+    }
+}
+
+/// Serializes the stack as a sequence of its items, from the bottom up.
+///
+/// Only serialization is supported: deserializing a stack of trait objects would require a
+/// registry mapping type tags to concrete types.
+impl<T: ?Sized + erased_serde::Serialize, A: Allocator> Serialize for DynStack<T, A> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_seq(self.iter().map(Erased))
+    }
+}
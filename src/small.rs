@@ -0,0 +1,264 @@
+//! A stack that keeps its first items inline, before spilling them to a [`DynStack`].
+
+use crate::{align_up, fatptr, unwrap_alloc, DynStack};
+use core::{
+    fmt,
+    mem::{self, MaybeUninit},
+    ops::{Index, IndexMut},
+    ptr,
+};
+
+/// The alignment of the inline buffer. Items aligned more strictly than this are always stored on
+/// the heap.
+const INLINE_ALIGN: usize = 16;
+
+#[repr(C, align(16))]
+struct InlineBuf<const N: usize>([MaybeUninit<u8>; N]);
+
+/// A stack for trait objects that stores up to `INLINE` bytes of items inline, and only allocates
+/// a buffer once they don't fit anymore.
+///
+/// This avoids allocating the item buffer for stacks that usually stay small, like short-lived
+/// stacks that are created and dropped in a hot loop. The offset table is still kept in a `Vec`.
+///
+/// Once the items spill to the heap, they stay there, and the stack behaves like a
+/// [`DynStack`], which [`into_dyn_stack`](SmallDynStack::into_dyn_stack) converts it to. Items
+/// aligned to more than 16 bytes are never stored inline.
+///
+/// ```
+/// # use dynstack::{SmallDynStack, dyn_push};
+/// # use std::fmt::Debug;
+/// let mut stack = SmallDynStack::<dyn Debug, 32>::new();
+/// dyn_push!(stack, 1u32);
+/// dyn_push!(stack, "two");
+/// assert!(!stack.spilled());
+///
+/// dyn_push!(stack, [3u64; 4]);
+/// assert!(stack.spilled());
+/// assert_eq!(format!("{:?}", stack), r#"[1, "two", [3, 3, 3, 3]]"#);
+/// ```
+pub struct SmallDynStack<T: ?Sized, const INLINE: usize> {
+    inline: InlineBuf<INLINE>,
+    /// Holds the offset table, and `dyn_size` counts the bytes used inline until the items spill
+    /// to its buffer. Its buffer pointer is null as long as the items are inline.
+    stack: DynStack<T>,
+}
+
+impl<T: ?Sized, const INLINE: usize> SmallDynStack<T, INLINE> {
+    /// Creates a new, empty, [`SmallDynStack`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if pointers to `T` are neither thin, nor a data pointer and one `usize` of metadata.
+    pub fn new() -> Self {
+        SmallDynStack {
+            inline: InlineBuf([MaybeUninit::uninit(); INLINE]),
+            stack: DynStack::new(),
+        }
+    }
+
+    /// Returns `true` if the items have moved to a heap-allocated buffer.
+    pub fn spilled(&self) -> bool {
+        !self.stack.dyn_data.is_null()
+    }
+
+    fn data(&self) -> *const u8 {
+        if self.spilled() {
+            self.stack.dyn_data
+        } else {
+            self.inline.0.as_ptr() as *const u8
+        }
+    }
+
+    fn data_mut(&mut self) -> *mut u8 {
+        if self.spilled() {
+            self.stack.dyn_data
+        } else {
+            self.inline.0.as_mut_ptr() as *mut u8
+        }
+    }
+
+    /// Move the inline items to a new buffer, twice the size of the inline one.
+    ///
+    /// Both buffers are aligned to 16 bytes, so the items keep their offsets.
+    fn spill(&mut self) {
+        let used = self.stack.dyn_size;
+        unwrap_alloc(self.stack.allocate(INLINE * 2, INLINE_ALIGN));
+        unsafe {
+            ptr::copy_nonoverlapping(
+                self.inline.0.as_ptr() as *const u8,
+                self.stack.dyn_data,
+                used,
+            )
+        };
+    }
+
+    /// Push a trait object onto the stack, returning its index.
+    ///
+    /// It is highly recommended to use the `dyn_push` macro instead of calling this directly.
+    ///
+    /// # Safety
+    ///
+    /// `item` must point to a valid `T`. Ownership of the pointee moves into the stack, so the
+    /// caller must not drop it afterwards. See [`DynStack::push_raw_owned`] for the full
+    /// contract.
+    pub unsafe fn push(&mut self, item: *mut T) -> usize {
+        if !self.spilled() {
+            let size = mem::size_of_val(&*item);
+            let align = mem::align_of_val(&*item);
+            let offs = align_up(self.stack.dyn_size, align);
+            if align <= INLINE_ALIGN && offs.checked_add(size).is_some_and(|end| end <= INLINE) {
+                self.data_mut()
+                    .add(offs)
+                    .copy_from_nonoverlapping(item as *const u8, size);
+                self.stack.offs_table.push((offs, fatptr::decomp(item)[1]));
+                self.stack.type_ids.push();
+                self.stack.needs_drop = true;
+                self.stack.dyn_size = offs + size;
+                return self.stack.offs_table.len() - 1;
+            }
+            // Without items to move, the inner stack allocates a buffer that fits this one.
+            if !self.is_empty() {
+                self.spill();
+            }
+        }
+        self.stack.push_raw_owned(item)
+    }
+
+    /// Push a trait object onto the stack, for the pushing macros.
+    #[doc(hidden)]
+    #[inline]
+    pub unsafe fn __dyn_push(&mut self, item: *mut T, needs_drop: bool) -> usize {
+        let needs_drop = self.stack.needs_drop || needs_drop;
+        let index = self.push(item);
+        self.stack.needs_drop = needs_drop;
+        index
+    }
+
+    /// Push a trait object onto the stack and record its type, for `dyn_push_typed!`.
+    #[cfg(feature = "type-ids")]
+    #[doc(hidden)]
+    #[inline]
+    pub unsafe fn __dyn_push_typed(
+        &mut self,
+        item: *mut T,
+        type_id: core::any::TypeId,
+        needs_drop: bool,
+    ) -> usize {
+        let index = self.__dyn_push(item, needs_drop);
+        self.stack.type_ids.set(index, type_id);
+        index
+    }
+
+    /// Retrieve a trait object reference at the provided index.
+    pub fn get(&self, index: usize) -> Option<&T> {
+        let &(offs, vtable) = self.stack.offs_table.get(index)?;
+        let data = self.data() as *mut u8;
+        Some(unsafe { &*fatptr::recomp(data.add(offs), vtable) })
+    }
+
+    /// Retrieve a mutable trait object reference at the provided index.
+    pub fn get_mut(&mut self, index: usize) -> Option<&mut T> {
+        let &(offs, vtable) = self.stack.offs_table.get(index)?;
+        let data = self.data_mut();
+        Some(unsafe { &mut *fatptr::recomp(data.add(offs), vtable) })
+    }
+
+    /// Retrieve the trait object reference at the top of the stack.
+    pub fn peek(&self) -> Option<&T> {
+        let index = self.len().checked_sub(1)?;
+        self.get(index)
+    }
+
+    /// Retrieve the mutable trait object reference at the top of the stack.
+    pub fn peek_mut(&mut self) -> Option<&mut T> {
+        let index = self.len().checked_sub(1)?;
+        self.get_mut(index)
+    }
+
+    /// Iterate over the trait object references, from the bottom of the stack up.
+    pub fn iter(&self) -> impl DoubleEndedIterator<Item = &T> + ExactSizeIterator {
+        (0..self.len()).map(move |index| &self[index])
+    }
+
+    /// Remove the last trait object from the stack.
+    /// Returns true if any items were removed.
+    pub fn remove_last(&mut self) -> bool {
+        match self.peek_mut() {
+            Some(last_item) => {
+                let last_item: *mut T = last_item;
+                unsafe { self.stack.drop_last(last_item) };
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove all trait objects from the stack. The inline or allocated buffer is kept for
+    /// subsequent pushes.
+    pub fn clear(&mut self) {
+        while self.remove_last() {}
+        self.stack.dyn_size = 0;
+    }
+
+    /// Returns the number of trait objects stored on the stack.
+    pub fn len(&self) -> usize {
+        self.stack.len()
+    }
+
+    /// Returns `true` if the stack holds no trait objects.
+    pub fn is_empty(&self) -> bool {
+        self.stack.is_empty()
+    }
+
+    /// Convert the stack into a [`DynStack`], moving the items to the heap if they are inline.
+    pub fn into_dyn_stack(mut self) -> DynStack<T> {
+        if !self.spilled() && !self.is_empty() {
+            self.spill();
+        }
+        // The items now belong to the returned stack, so dropping `self` does nothing.
+        mem::replace(&mut self.stack, DynStack::new())
+    }
+}
+
+impl<T: ?Sized, const INLINE: usize> Default for SmallDynStack<T, INLINE> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T: ?Sized + fmt::Debug, const INLINE: usize> fmt::Debug for SmallDynStack<T, INLINE> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+impl<T: ?Sized, const INLINE: usize> Index<usize> for SmallDynStack<T, INLINE> {
+    type Output = T;
+
+    fn index(&self, idx: usize) -> &T {
+        self.get(idx).unwrap()
+    }
+}
+
+impl<T: ?Sized, const INLINE: usize> IndexMut<usize> for SmallDynStack<T, INLINE> {
+    fn index_mut(&mut self, idx: usize) -> &mut T {
+        self.get_mut(idx).unwrap()
+    }
+}
+
+impl<T: ?Sized, const INLINE: usize> Drop for SmallDynStack<T, INLINE> {
+    fn drop(&mut self) {
+        // The inner stack can't drop inline items itself, so they must all be gone before it is
+        // dropped, even if an item's destructor panics.
+        struct ClearOnDrop<'a, T: ?Sized, const INLINE: usize>(&'a mut SmallDynStack<T, INLINE>);
+        impl<T: ?Sized, const INLINE: usize> Drop for ClearOnDrop<'_, T, INLINE> {
+            fn drop(&mut self) {
+                self.0.clear();
+            }
+        }
+
+        let stack = ClearOnDrop(self);
+        stack.0.clear();
+    }
+}
@@ -0,0 +1,31 @@
+//! Item types shared by the tests.
+
+/// An item aligned to more than any primitive, which forces the buffer to be realigned.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(align(64))]
+pub struct Aligned64(pub u32);
+
+/// A trait for stacks of items that can be told apart by value, and whose placement can be
+/// checked against their alignment.
+pub trait Item {
+    fn value(&self) -> u32;
+
+    fn alignment(&self) -> usize {
+        core::mem::align_of_val(self)
+    }
+}
+
+macro_rules! impl_item {
+    ($ty:ty, $value:expr) => {
+        impl Item for $ty {
+            fn value(&self) -> u32 {
+                $value(self)
+            }
+        }
+    };
+}
+impl_item!(u8, |x: &u8| *x as u32);
+impl_item!(u16, |x: &u16| *x as u32);
+impl_item!(u32, |x: &u32| *x);
+impl_item!(u64, |x: &u64| *x as u32);
+impl_item!(Aligned64, |x: &Aligned64| x.0);
@@ -0,0 +1,205 @@
+//! Per-item type ids, recorded by [`dyn_push_typed!`](crate::dyn_push_typed) when the `type-ids`
+//! feature is enabled.
+//!
+//! Without the feature, [`TypeIds`] is an empty struct whose methods do nothing, so that the code
+//! keeping it in sync with the offset table doesn't need to be feature gated.
+
+#[cfg(feature = "type-ids")]
+use crate::{Allocator, DynStack};
+#[cfg(feature = "type-ids")]
+use alloc::vec::Vec;
+#[cfg(feature = "type-ids")]
+use core::any::TypeId;
+
+/// Returns the type id of the value `dyn_push_typed!` is about to push.
+#[cfg(feature = "type-ids")]
+pub fn type_id_of<U: 'static>(_: &U) -> TypeId {
+    TypeId::of::<U>()
+}
+
+/// The type id of each item, parallel to the offset table. `None` for items that weren't pushed
+/// through `dyn_push_typed!`, and whose type is therefore unknown.
+#[cfg(feature = "type-ids")]
+#[derive(Clone)]
+pub(crate) struct TypeIds(Vec<Option<TypeId>>);
+
+#[cfg(not(feature = "type-ids"))]
+#[derive(Clone)]
+pub(crate) struct TypeIds;
+
+#[cfg(feature = "type-ids")]
+impl TypeIds {
+    pub(crate) const fn new() -> Self {
+        TypeIds(Vec::new())
+    }
+
+    #[cfg(test)]
+    pub(crate) fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub(crate) fn get(&self, index: usize) -> Option<TypeId> {
+        self.0.get(index).copied().flatten()
+    }
+
+    /// Record the type of a newly pushed item, which isn't known yet.
+    pub(crate) fn push(&mut self) {
+        self.0.push(None);
+    }
+
+    pub(crate) fn set(&mut self, index: usize, type_id: TypeId) {
+        self.0[index] = Some(type_id);
+    }
+
+    /// Forget the type of an item that was replaced by one of unknown type.
+    pub(crate) fn unset(&mut self, index: usize) {
+        self.0[index] = None;
+    }
+
+    pub(crate) fn pop(&mut self) {
+        self.0.pop();
+    }
+
+    pub(crate) fn swap(&mut self, a: usize, b: usize) {
+        self.0.swap(a, b);
+    }
+
+    pub(crate) fn rotate_left(&mut self, n: usize) {
+        self.0.rotate_left(n);
+    }
+
+    /// Record the type of an item inserted at `index`, which isn't known.
+    pub(crate) fn insert(&mut self, index: usize) {
+        self.0.insert(index, None);
+    }
+
+    pub(crate) fn remove(&mut self, index: usize) {
+        self.0.remove(index);
+    }
+
+    /// Reorder the type ids like `sort_by` reorders the offset table.
+    pub(crate) fn permute(&mut self, order: &[usize]) {
+        self.0 = order.iter().map(|&index| self.0[index]).collect();
+    }
+
+    /// Move the type id at `from` down to `to`, while compacting the table after removals.
+    pub(crate) fn move_down(&mut self, from: usize, to: usize) {
+        self.0[to] = self.0[from];
+    }
+
+    pub(crate) fn truncate(&mut self, len: usize) {
+        self.0.truncate(len);
+    }
+
+    pub(crate) fn split_off(&mut self, at: usize) -> Self {
+        TypeIds(self.0.split_off(at))
+    }
+
+    pub(crate) fn remove_front(&mut self, count: usize) {
+        self.0.drain(..count);
+    }
+}
+
+#[cfg(not(feature = "type-ids"))]
+impl TypeIds {
+    pub(crate) const fn new() -> Self {
+        TypeIds
+    }
+
+    pub(crate) fn push(&mut self) {}
+
+    pub(crate) fn unset(&mut self, _: usize) {}
+
+    pub(crate) fn pop(&mut self) {}
+
+    pub(crate) fn swap(&mut self, _: usize, _: usize) {}
+
+    pub(crate) fn rotate_left(&mut self, _: usize) {}
+
+    pub(crate) fn insert(&mut self, _: usize) {}
+
+    pub(crate) fn remove(&mut self, _: usize) {}
+
+    pub(crate) fn permute(&mut self, _: &[usize]) {}
+
+    pub(crate) fn move_down(&mut self, _: usize, _: usize) {}
+
+    pub(crate) fn truncate(&mut self, _: usize) {}
+
+    pub(crate) fn split_off(&mut self, _: usize) -> Self {
+        TypeIds
+    }
+
+    pub(crate) fn remove_front(&mut self, _: usize) {}
+}
+
+#[cfg(feature = "type-ids")]
+impl<T: ?Sized, A: Allocator> DynStack<T, A> {
+    /// Returns the type id of the concrete type of the trait object at the provided index.
+    ///
+    /// Types are only recorded for items pushed with [`dyn_push_typed!`](crate::dyn_push_typed).
+    /// For other items, and if the index is out of bounds, this returns `None`.
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push_typed};
+    /// # use std::{any::TypeId, fmt::Debug};
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// dyn_push_typed!(stack, 1u8);
+    /// stack.push_box(Box::new("unknown"));
+    /// assert_eq!(stack.type_id_at(0), Some(TypeId::of::<u8>()));
+    /// assert_eq!(stack.type_id_at(1), None);
+    /// ```
+    pub fn type_id_at(&self, index: usize) -> Option<TypeId> {
+        self.type_ids.get(index)
+    }
+
+    /// Returns `true` if the trait object at the provided index is known to be a `U`.
+    /// See [`type_id_at`](DynStack::type_id_at).
+    pub fn is<U: 'static>(&self, index: usize) -> bool {
+        self.type_id_at(index) == Some(TypeId::of::<U>())
+    }
+
+    /// Retrieve a reference to the trait object at the provided index as its concrete type.
+    ///
+    /// Returns `None` unless the item is known to be a `U`, see [`is`](DynStack::is).
+    ///
+    /// ```
+    /// # use dynstack::{DynStack, dyn_push_typed};
+    /// # use std::fmt::Debug;
+    /// let mut stack = DynStack::<dyn Debug>::new();
+    /// dyn_push_typed!(stack, 5u32);
+    /// assert_eq!(stack.get_as::<u32>(0), Some(&5));
+    /// assert_eq!(stack.get_as::<i32>(0), None);
+    /// ```
+    pub fn get_as<U: 'static>(&self, index: usize) -> Option<&U> {
+        if !self.is::<U>(index) {
+            return None;
+        }
+        let item: *const T = self.get(index)?;
+        Some(unsafe { &*(item as *const U) })
+    }
+
+    /// Retrieve a mutable reference to the trait object at the provided index as its concrete
+    /// type. See [`get_as`](DynStack::get_as).
+    pub fn get_as_mut<U: 'static>(&mut self, index: usize) -> Option<&mut U> {
+        if !self.is::<U>(index) {
+            return None;
+        }
+        let item: *mut T = self.get_mut(index)?;
+        Some(unsafe { &mut *(item as *mut U) })
+    }
+
+    /// Push a trait object onto the stack and record its type, for `dyn_push_typed!`.
+    #[doc(hidden)]
+    #[inline]
+    pub unsafe fn __dyn_push_typed(
+        &mut self,
+        item: *mut T,
+        type_id: TypeId,
+        needs_drop: bool,
+    ) -> usize {
+        let index = self.__dyn_push(item, needs_drop);
+        self.type_ids.set(index, type_id);
+        index
+    }
+}